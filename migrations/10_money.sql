@@ -0,0 +1,30 @@
+-- Money and percents were floats. Casting keeps the 15 significant digits a float holds
+-- reliably, which gives back the amounts as they were entered without the float noise.
+-- NUMERIC has no scale so nothing is rounded away, rounding is up to the server.
+-- The checks are added again so they compare numerics instead of casting to floats.
+ALTER TABLE users
+    DROP CONSTRAINT users_pay,
+    DROP CONSTRAINT users_percent,
+    ALTER COLUMN pay TYPE NUMERIC USING pay::NUMERIC,
+    ALTER COLUMN percent TYPE NUMERIC USING percent::NUMERIC,
+    ADD CONSTRAINT users_pay CHECK (pay >= 0) NOT VALID,
+    ADD CONSTRAINT users_percent CHECK (percent BETWEEN 0 AND 100) NOT VALID;
+
+ALTER TABLE revenue
+    DROP CONSTRAINT revenue_amounts,
+    ALTER COLUMN with_percent TYPE NUMERIC USING with_percent::NUMERIC,
+    ALTER COLUMN without_percent TYPE NUMERIC USING without_percent::NUMERIC,
+    ADD CONSTRAINT revenue_amounts CHECK (with_percent >= 0 AND without_percent >= 0) NOT VALID;
+
+ALTER TABLE payouts
+    DROP CONSTRAINT payouts_amount,
+    ALTER COLUMN amount TYPE NUMERIC USING amount::NUMERIC,
+    ADD CONSTRAINT payouts_amount CHECK (amount >= 0) NOT VALID;
+
+ALTER TABLE pay_rates
+    DROP CONSTRAINT pay_rates_pay,
+    DROP CONSTRAINT pay_rates_percent,
+    ALTER COLUMN pay TYPE NUMERIC USING pay::NUMERIC,
+    ALTER COLUMN percent TYPE NUMERIC USING percent::NUMERIC,
+    ADD CONSTRAINT pay_rates_pay CHECK (pay >= 0) NOT VALID,
+    ADD CONSTRAINT pay_rates_percent CHECK (percent BETWEEN 0 AND 100) NOT VALID;
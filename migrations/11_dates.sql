@@ -0,0 +1,68 @@
+-- Days a month doesn't have, like 31 February, got in before requests were checked and
+-- make_date fails on them. Such rows are moved with their original columns to the
+-- *_quarantine tables to be fixed and put back by hand, with a warning saying how many there are.
+-- The date checks go away with the columns, a DATE can't hold an invalid day.
+CREATE FUNCTION pg_temp.is_date(year INTEGER, month INTEGER, day INTEGER) RETURNS BOOLEAN
+    LANGUAGE SQL IMMUTABLE
+    AS $$
+        SELECT CASE WHEN year BETWEEN 1 AND 9999 AND month BETWEEN 1 AND 12 AND day >= 1
+            THEN day <= extract(DAY FROM make_date(year, month, 1) + INTERVAL '1 month - 1 day')
+            ELSE FALSE
+        END
+    $$;
+
+CREATE TABLE schedule_quarantine AS
+    SELECT * FROM schedule WHERE NOT pg_temp.is_date(year, month, day);
+DELETE FROM schedule WHERE NOT pg_temp.is_date(year, month, day);
+CREATE TABLE revenue_quarantine AS
+    SELECT * FROM revenue WHERE NOT pg_temp.is_date(year, month, day);
+DELETE FROM revenue WHERE NOT pg_temp.is_date(year, month, day);
+CREATE TABLE payouts_quarantine AS
+    SELECT * FROM payouts WHERE NOT pg_temp.is_date(year, month, day);
+DELETE FROM payouts WHERE NOT pg_temp.is_date(year, month, day);
+
+DO $$
+DECLARE
+    moved BIGINT := (SELECT count(*) FROM schedule_quarantine)
+        + (SELECT count(*) FROM revenue_quarantine)
+        + (SELECT count(*) FROM payouts_quarantine);
+BEGIN
+    IF moved > 0 THEN
+        RAISE WARNING '% rows with invalid days moved to schedule_quarantine, revenue_quarantine and payouts_quarantine', moved;
+    END IF;
+END
+$$;
+DROP FUNCTION pg_temp.is_date;
+
+ALTER TABLE schedule ADD COLUMN date DATE;
+UPDATE schedule SET date = make_date(year, month, day);
+ALTER TABLE schedule
+    DROP CONSTRAINT schedule_pkey,
+    DROP COLUMN day,
+    DROP COLUMN month,
+    DROP COLUMN year,
+    ALTER COLUMN date SET NOT NULL;
+ALTER TABLE schedule RENAME COLUMN date TO day;
+ALTER TABLE schedule ADD PRIMARY KEY (day, user_id);
+
+ALTER TABLE revenue ADD COLUMN date DATE;
+UPDATE revenue SET date = make_date(year, month, day);
+ALTER TABLE revenue
+    DROP CONSTRAINT revenue_pkey,
+    DROP COLUMN day,
+    DROP COLUMN month,
+    DROP COLUMN year,
+    ALTER COLUMN date SET NOT NULL;
+ALTER TABLE revenue RENAME COLUMN date TO day;
+ALTER TABLE revenue ADD PRIMARY KEY (day);
+
+ALTER TABLE payouts ADD COLUMN date DATE;
+UPDATE payouts SET date = make_date(year, month, day);
+ALTER TABLE payouts
+    DROP CONSTRAINT payouts_pkey,
+    DROP COLUMN day,
+    DROP COLUMN month,
+    DROP COLUMN year,
+    ALTER COLUMN date SET NOT NULL;
+ALTER TABLE payouts RENAME COLUMN date TO day;
+ALTER TABLE payouts ADD PRIMARY KEY (day, user_id);
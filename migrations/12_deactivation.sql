@@ -0,0 +1,4 @@
+-- Users who left are deactivated instead of deleted, schedule and payouts still refer to them
+ALTER TABLE users
+    ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN deactivated_at TIMESTAMPTZ;
@@ -0,0 +1,6 @@
+CREATE TABLE setup_codes (
+                             user_id INTEGER PRIMARY KEY,
+                             code_hash VARCHAR NOT NULL,
+                             expires_at TIMESTAMPTZ NOT NULL,
+                             FOREIGN KEY(user_id) REFERENCES users(id)
+);
@@ -0,0 +1,14 @@
+CREATE TABLE lockouts (
+                          id SERIAL PRIMARY KEY,
+                          login VARCHAR,
+                          address VARCHAR,
+                          locked_at TIMESTAMPTZ NOT NULL,
+                          locked_until TIMESTAMPTZ NOT NULL,
+                          cleared_at TIMESTAMPTZ,
+                          cleared_by INTEGER,
+                          FOREIGN KEY(cleared_by) REFERENCES users(id)
+);
+
+-- Every login looks up the lockouts still in force for its login and address
+CREATE INDEX lockouts_login ON lockouts(login, locked_until) WHERE cleared_at IS NULL;
+CREATE INDEX lockouts_address ON lockouts(address, locked_until) WHERE cleared_at IS NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE audit_log (
+                           id SERIAL PRIMARY KEY,
+                           user_id INTEGER NOT NULL,
+                           created_at TIMESTAMPTZ NOT NULL,
+                           kind VARCHAR NOT NULL,
+                           value_before TEXT,
+                           value_after TEXT,
+                           FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+CREATE INDEX audit_log_created_at ON audit_log(created_at);
@@ -0,0 +1,11 @@
+CREATE TABLE pay_rates (
+                           user_id INTEGER NOT NULL,
+                           valid_from DATE NOT NULL,
+                           pay DOUBLE PRECISION NOT NULL,
+                           percent DOUBLE PRECISION NOT NULL,
+
+                           PRIMARY KEY(user_id, valid_from),
+                           FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+INSERT INTO pay_rates SELECT id, CURRENT_DATE, pay, percent FROM users;
@@ -0,0 +1,23 @@
+-- Rows written before requests were validated are left as they are,
+-- run VALIDATE CONSTRAINT for each once they're fixed
+ALTER TABLE users
+    ADD CONSTRAINT users_pay CHECK (pay >= 0) NOT VALID,
+    ADD CONSTRAINT users_percent CHECK (percent BETWEEN 0 AND 100) NOT VALID;
+
+ALTER TABLE schedule
+    ADD CONSTRAINT schedule_date CHECK (year > 0 AND month BETWEEN 1 AND 12 AND day BETWEEN 1 AND 31) NOT VALID;
+
+ALTER TABLE revenue
+    ADD CONSTRAINT revenue_date CHECK (year > 0 AND month BETWEEN 1 AND 12 AND day BETWEEN 1 AND 31) NOT VALID,
+    ADD CONSTRAINT revenue_amounts CHECK (with_percent >= 0 AND without_percent >= 0) NOT VALID;
+
+ALTER TABLE payouts
+    ADD CONSTRAINT payouts_date CHECK (year > 0 AND month BETWEEN 1 AND 12 AND day BETWEEN 1 AND 31) NOT VALID,
+    ADD CONSTRAINT payouts_amount CHECK (amount >= 0) NOT VALID;
+
+ALTER TABLE pay_rates
+    ADD CONSTRAINT pay_rates_pay CHECK (pay >= 0) NOT VALID,
+    ADD CONSTRAINT pay_rates_percent CHECK (percent BETWEEN 0 AND 100) NOT VALID;
+
+ALTER TABLE closed_months
+    ADD CONSTRAINT closed_months_month CHECK (year > 0 AND month BETWEEN 1 AND 12) NOT VALID;
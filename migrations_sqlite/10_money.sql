@@ -0,0 +1,136 @@
+-- SQLite has no decimal type, money and percents are kept as decimal text.
+-- Casting keeps the 15 significant digits a float holds reliably, same as Postgres.
+-- Columns can't change their type, so each one is replaced by a TEXT column,
+-- which means the triggers using them have to go first. The date triggers go too, they would
+-- stop the copying on invalid days saved before them, those are dealt with when days become dates.
+
+DROP TRIGGER users_pay_insert;
+DROP TRIGGER users_pay_update;
+DROP TRIGGER users_percent_insert;
+DROP TRIGGER users_percent_update;
+DROP TRIGGER revenue_amounts_insert;
+DROP TRIGGER revenue_amounts_update;
+DROP TRIGGER payouts_amount_insert;
+DROP TRIGGER payouts_amount_update;
+DROP TRIGGER revenue_date_update;
+DROP TRIGGER payouts_date_update;
+DROP TRIGGER pay_rates_pay_insert;
+DROP TRIGGER pay_rates_pay_update;
+DROP TRIGGER pay_rates_percent_insert;
+DROP TRIGGER pay_rates_percent_update;
+
+ALTER TABLE users ADD COLUMN pay_text TEXT NOT NULL DEFAULT '0';
+UPDATE users SET pay_text = CAST(pay AS TEXT);
+ALTER TABLE users DROP COLUMN pay;
+ALTER TABLE users RENAME COLUMN pay_text TO pay;
+ALTER TABLE users ADD COLUMN percent_text TEXT NOT NULL DEFAULT '0';
+UPDATE users SET percent_text = CAST(percent AS TEXT);
+ALTER TABLE users DROP COLUMN percent;
+ALTER TABLE users RENAME COLUMN percent_text TO percent;
+
+ALTER TABLE revenue ADD COLUMN with_percent_text TEXT NOT NULL DEFAULT '0';
+UPDATE revenue SET with_percent_text = CAST(with_percent AS TEXT);
+ALTER TABLE revenue DROP COLUMN with_percent;
+ALTER TABLE revenue RENAME COLUMN with_percent_text TO with_percent;
+ALTER TABLE revenue ADD COLUMN without_percent_text TEXT NOT NULL DEFAULT '0';
+UPDATE revenue SET without_percent_text = CAST(without_percent AS TEXT);
+ALTER TABLE revenue DROP COLUMN without_percent;
+ALTER TABLE revenue RENAME COLUMN without_percent_text TO without_percent;
+
+ALTER TABLE payouts ADD COLUMN amount_text TEXT NOT NULL DEFAULT '0';
+UPDATE payouts SET amount_text = CAST(amount AS TEXT);
+ALTER TABLE payouts DROP COLUMN amount;
+ALTER TABLE payouts RENAME COLUMN amount_text TO amount;
+
+ALTER TABLE pay_rates ADD COLUMN pay_text TEXT NOT NULL DEFAULT '0';
+UPDATE pay_rates SET pay_text = CAST(pay AS TEXT);
+ALTER TABLE pay_rates DROP COLUMN pay;
+ALTER TABLE pay_rates RENAME COLUMN pay_text TO pay;
+ALTER TABLE pay_rates ADD COLUMN percent_text TEXT NOT NULL DEFAULT '0';
+UPDATE pay_rates SET percent_text = CAST(percent AS TEXT);
+ALTER TABLE pay_rates DROP COLUMN percent;
+ALTER TABLE pay_rates RENAME COLUMN percent_text TO percent;
+
+CREATE TRIGGER users_pay_insert BEFORE INSERT ON users
+WHEN CAST(NEW.pay AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_pay');
+END;
+
+CREATE TRIGGER users_pay_update BEFORE UPDATE ON users
+WHEN CAST(NEW.pay AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_pay');
+END;
+
+CREATE TRIGGER users_percent_insert BEFORE INSERT ON users
+WHEN CAST(NEW.percent AS REAL) NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_percent');
+END;
+
+CREATE TRIGGER users_percent_update BEFORE UPDATE ON users
+WHEN CAST(NEW.percent AS REAL) NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_percent');
+END;
+
+CREATE TRIGGER revenue_amounts_insert BEFORE INSERT ON revenue
+WHEN CAST(NEW.with_percent AS REAL) < 0 OR CAST(NEW.without_percent AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_amounts');
+END;
+
+CREATE TRIGGER revenue_amounts_update BEFORE UPDATE ON revenue
+WHEN CAST(NEW.with_percent AS REAL) < 0 OR CAST(NEW.without_percent AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_amounts');
+END;
+
+CREATE TRIGGER revenue_date_update BEFORE UPDATE ON revenue
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_date');
+END;
+
+CREATE TRIGGER payouts_date_update BEFORE UPDATE ON payouts
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_date');
+END;
+
+CREATE TRIGGER payouts_amount_insert BEFORE INSERT ON payouts
+WHEN CAST(NEW.amount AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_amount');
+END;
+
+CREATE TRIGGER payouts_amount_update BEFORE UPDATE ON payouts
+WHEN CAST(NEW.amount AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_amount');
+END;
+
+CREATE TRIGGER pay_rates_pay_insert BEFORE INSERT ON pay_rates
+WHEN CAST(NEW.pay AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_pay');
+END;
+
+CREATE TRIGGER pay_rates_pay_update BEFORE UPDATE ON pay_rates
+WHEN CAST(NEW.pay AS REAL) < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_pay');
+END;
+
+CREATE TRIGGER pay_rates_percent_insert BEFORE INSERT ON pay_rates
+WHEN CAST(NEW.percent AS REAL) NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_percent');
+END;
+
+CREATE TRIGGER pay_rates_percent_update BEFORE UPDATE ON pay_rates
+WHEN CAST(NEW.percent AS REAL) NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_percent');
+END;
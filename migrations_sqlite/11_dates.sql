@@ -0,0 +1,68 @@
+-- The tables are rebuilt with a DATE in place of day, month and year, nothing references them.
+-- date() with a modifier doesn't give back days a month doesn't have, like 31 February,
+-- without one older SQLite passes them through as they are. Such days and negative amounts
+-- could be saved before the triggers. Rows the new tables would refuse are moved with their
+-- original columns to the *_quarantine tables to be fixed and put back by hand.
+-- The triggers checking these tables go with them, the new tables check amounts themselves.
+CREATE TABLE schedule_quarantine AS
+SELECT * FROM schedule
+WHERE date(printf('%04d-%02d-%02d', year, month, day), '+0 days') IS NOT printf('%04d-%02d-%02d', year, month, day);
+
+CREATE TABLE revenue_quarantine AS
+SELECT * FROM revenue
+WHERE date(printf('%04d-%02d-%02d', year, month, day), '+0 days') IS NOT printf('%04d-%02d-%02d', year, month, day)
+   OR CAST(with_percent AS REAL) < 0 OR CAST(without_percent AS REAL) < 0;
+
+CREATE TABLE payouts_quarantine AS
+SELECT * FROM payouts
+WHERE date(printf('%04d-%02d-%02d', year, month, day), '+0 days') IS NOT printf('%04d-%02d-%02d', year, month, day)
+   OR CAST(amount AS REAL) < 0;
+
+CREATE TABLE schedule_dates (
+                                day DATE NOT NULL,
+                                user_id INTEGER NOT NULL,
+
+                                PRIMARY KEY(day, user_id),
+                                FOREIGN KEY(user_id) REFERENCES users(id),
+                                CONSTRAINT schedule_day CHECK (date(day, '+0 days') IS day)
+);
+
+INSERT INTO schedule_dates
+SELECT printf('%04d-%02d-%02d', year, month, day), user_id FROM schedule
+EXCEPT SELECT printf('%04d-%02d-%02d', year, month, day), user_id FROM schedule_quarantine;
+DROP TABLE schedule;
+ALTER TABLE schedule_dates RENAME TO schedule;
+
+CREATE TABLE revenue_dates (
+                               day DATE PRIMARY KEY,
+                               with_percent TEXT NOT NULL,
+                               without_percent TEXT NOT NULL,
+
+                               CONSTRAINT revenue_day CHECK (date(day, '+0 days') IS day),
+                               CONSTRAINT revenue_amounts CHECK (
+                                   CAST(with_percent AS REAL) >= 0 AND CAST(without_percent AS REAL) >= 0
+                               )
+);
+
+INSERT INTO revenue_dates
+SELECT printf('%04d-%02d-%02d', year, month, day), with_percent, without_percent FROM revenue
+EXCEPT SELECT printf('%04d-%02d-%02d', year, month, day), with_percent, without_percent FROM revenue_quarantine;
+DROP TABLE revenue;
+ALTER TABLE revenue_dates RENAME TO revenue;
+
+CREATE TABLE payouts_dates (
+                               day DATE NOT NULL,
+                               user_id INTEGER NOT NULL,
+                               amount TEXT NOT NULL,
+
+                               PRIMARY KEY(day, user_id),
+                               FOREIGN KEY(user_id) REFERENCES users(id),
+                               CONSTRAINT payouts_day CHECK (date(day, '+0 days') IS day),
+                               CONSTRAINT payouts_amount CHECK (CAST(amount AS REAL) >= 0)
+);
+
+INSERT INTO payouts_dates
+SELECT printf('%04d-%02d-%02d', year, month, day), user_id, amount FROM payouts
+EXCEPT SELECT printf('%04d-%02d-%02d', year, month, day), user_id, amount FROM payouts_quarantine;
+DROP TABLE payouts;
+ALTER TABLE payouts_dates RENAME TO payouts;
@@ -0,0 +1,3 @@
+-- Users who left are deactivated instead of deleted, schedule and payouts still refer to them
+ALTER TABLE users ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE users ADD COLUMN deactivated_at DATETIME;
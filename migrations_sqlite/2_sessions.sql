@@ -0,0 +1,13 @@
+CREATE TABLE sessions (
+                          token_hash VARCHAR PRIMARY KEY,
+                          user_id INTEGER NOT NULL,
+                          created_at DATETIME NOT NULL,
+                          last_seen_at DATETIME NOT NULL,
+                          expires_at DATETIME NOT NULL,
+                          user_agent VARCHAR NOT NULL,
+                          FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+CREATE INDEX sessions_user_id ON sessions(user_id);
+
+ALTER TABLE users DROP COLUMN token;
@@ -0,0 +1,14 @@
+CREATE TABLE lockouts (
+                          id INTEGER PRIMARY KEY AUTOINCREMENT,
+                          login VARCHAR,
+                          address VARCHAR,
+                          locked_at DATETIME NOT NULL,
+                          locked_until DATETIME NOT NULL,
+                          cleared_at DATETIME,
+                          cleared_by INTEGER,
+                          FOREIGN KEY(cleared_by) REFERENCES users(id)
+);
+
+-- Every login looks up the lockouts still in force for its login and address
+CREATE INDEX lockouts_login ON lockouts(login, locked_until) WHERE cleared_at IS NULL;
+CREATE INDEX lockouts_address ON lockouts(address, locked_until) WHERE cleared_at IS NULL;
@@ -0,0 +1,12 @@
+CREATE TABLE commission_rules (
+                                  user_id INTEGER NOT NULL,
+                                  valid_from DATE NOT NULL,
+                                  rule TEXT NOT NULL,
+                                  PRIMARY KEY(user_id, valid_from),
+                                  FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+CREATE TABLE holidays (
+                          day DATE PRIMARY KEY,
+                          name VARCHAR NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE closed_months (
+                               year INTEGER NOT NULL,
+                               month INTEGER NOT NULL,
+                               closed_at DATETIME NOT NULL,
+                               closed_by INTEGER NOT NULL,
+                               snapshot TEXT NOT NULL,
+
+                               PRIMARY KEY(year, month),
+                               FOREIGN KEY(closed_by) REFERENCES users(id)
+);
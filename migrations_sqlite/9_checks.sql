@@ -0,0 +1,120 @@
+-- SQLite can't add CHECK constraints to existing tables, triggers do the same
+CREATE TRIGGER users_pay_insert BEFORE INSERT ON users
+WHEN NEW.pay < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_pay');
+END;
+
+CREATE TRIGGER users_pay_update BEFORE UPDATE ON users
+WHEN NEW.pay < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_pay');
+END;
+
+CREATE TRIGGER users_percent_insert BEFORE INSERT ON users
+WHEN NEW.percent NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_percent');
+END;
+
+CREATE TRIGGER users_percent_update BEFORE UPDATE ON users
+WHEN NEW.percent NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: users_percent');
+END;
+
+CREATE TRIGGER schedule_date_insert BEFORE INSERT ON schedule
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: schedule_date');
+END;
+
+CREATE TRIGGER schedule_date_update BEFORE UPDATE ON schedule
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: schedule_date');
+END;
+
+CREATE TRIGGER revenue_date_insert BEFORE INSERT ON revenue
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_date');
+END;
+
+CREATE TRIGGER revenue_date_update BEFORE UPDATE ON revenue
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_date');
+END;
+
+CREATE TRIGGER revenue_amounts_insert BEFORE INSERT ON revenue
+WHEN NEW.with_percent < 0 OR NEW.without_percent < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_amounts');
+END;
+
+CREATE TRIGGER revenue_amounts_update BEFORE UPDATE ON revenue
+WHEN NEW.with_percent < 0 OR NEW.without_percent < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: revenue_amounts');
+END;
+
+CREATE TRIGGER payouts_date_insert BEFORE INSERT ON payouts
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_date');
+END;
+
+CREATE TRIGGER payouts_date_update BEFORE UPDATE ON payouts
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12 OR NEW.day NOT BETWEEN 1 AND 31
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_date');
+END;
+
+CREATE TRIGGER payouts_amount_insert BEFORE INSERT ON payouts
+WHEN NEW.amount < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_amount');
+END;
+
+CREATE TRIGGER payouts_amount_update BEFORE UPDATE ON payouts
+WHEN NEW.amount < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: payouts_amount');
+END;
+
+CREATE TRIGGER pay_rates_pay_insert BEFORE INSERT ON pay_rates
+WHEN NEW.pay < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_pay');
+END;
+
+CREATE TRIGGER pay_rates_pay_update BEFORE UPDATE ON pay_rates
+WHEN NEW.pay < 0
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_pay');
+END;
+
+CREATE TRIGGER pay_rates_percent_insert BEFORE INSERT ON pay_rates
+WHEN NEW.percent NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_percent');
+END;
+
+CREATE TRIGGER pay_rates_percent_update BEFORE UPDATE ON pay_rates
+WHEN NEW.percent NOT BETWEEN 0 AND 100
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: pay_rates_percent');
+END;
+
+CREATE TRIGGER closed_months_month_insert BEFORE INSERT ON closed_months
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: closed_months_month');
+END;
+
+CREATE TRIGGER closed_months_month_update BEFORE UPDATE ON closed_months
+WHEN NEW.year <= 0 OR NEW.month NOT BETWEEN 1 AND 12
+BEGIN
+    SELECT RAISE(ABORT, 'CHECK constraint failed: closed_months_month');
+END;
@@ -0,0 +1,519 @@
+use crate::login_throttle::ThrottleConfig;
+use crate::schedule_policy::SchedulePolicy;
+use anyhow::{anyhow, bail, ensure, Context};
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Level;
+
+/// Used when `PRAVDA_CONFIG` isn't set and the file exists
+const DEFAULT_CONFIG_PATH: &str = "pravda.toml";
+
+/// Server settings, read from the optional TOML file and then overridden
+/// by environment variables
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database_url: String,
+    pub listen: SocketAddr,
+    pub assets_dir: PathBuf,
+    /// How long requests in flight may take to finish on shutdown
+    pub shutdown_timeout_secs: u64,
+    /// Proxies whose `X-Forwarded-For` gives the client address, without them
+    /// every client behind a proxy shares the proxy's address
+    pub trusted_proxies: Vec<IpAddr>,
+    pub pool: PoolConfig,
+    pub log: LogConfig,
+    pub tls: Option<TlsConfig>,
+    pub login: LoginConfig,
+    pub schedule: ScheduleConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+    /// Connections idle for longer are closed, never if unset
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub level: Level,
+    #[serde(deserialize_with = "from_str")]
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM files
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    pub backoff_after: u32,
+    pub backoff_base_secs: i64,
+    pub backoff_max_secs: i64,
+    pub lockout_after: u32,
+    pub lockout_minutes: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub edit_days_back: u32,
+    /// "HH:MM" local time
+    #[serde(deserialize_with = "time_of_day")]
+    pub cutoff: Option<NaiveTime>,
+    pub allow_non_workers: bool,
+    pub max_workers_per_day: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database_url: String::new(),
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            assets_dir: PathBuf::from("assets"),
+            shutdown_timeout_secs: 30,
+            trusted_proxies: Vec::new(),
+            pool: Default::default(),
+            log: Default::default(),
+            tls: None,
+            login: Default::default(),
+            schedule: Default::default(),
+        }
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 3,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: Some(600),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        let throttle = ThrottleConfig::default();
+        Self {
+            backoff_after: throttle.backoff_after,
+            backoff_base_secs: throttle.backoff_base.num_seconds(),
+            backoff_max_secs: throttle.backoff_max.num_seconds(),
+            lockout_after: throttle.lockout_after,
+            lockout_minutes: throttle.lockout_duration.num_minutes(),
+        }
+    }
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        let policy = SchedulePolicy::default();
+        Self {
+            edit_days_back: policy.edit_days_back,
+            cutoff: policy.cutoff,
+            allow_non_workers: policy.allow_non_workers,
+            max_workers_per_day: policy.max_workers_per_day,
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("expected \"text\" or \"json\"")),
+        }
+    }
+}
+
+impl Config {
+    /// Reads `PRAVDA_CONFIG` (or `pravda.toml`), then the environment, and checks the result
+    pub fn load() -> anyhow::Result<Self> {
+        let path = match env::var("PRAVDA_CONFIG") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        fn parse<T>(name: &str, value: String) -> anyhow::Result<T>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            value
+                .parse()
+                .map_err(|e| anyhow!("{} has an invalid value {:?}: {}", name, value, e))
+        }
+        macro_rules! set {
+            ($name:literal, $field:expr) => {
+                if let Some(value) = var($name) {
+                    $field = parse($name, value)?;
+                }
+            };
+        }
+
+        set!("DATABASE_URL", self.database_url);
+        set!("LISTEN_ADDR", self.listen);
+        set!("ASSETS_DIR", self.assets_dir);
+        set!("SHUTDOWN_TIMEOUT_SECS", self.shutdown_timeout_secs);
+        if let Some(value) = var("TRUSTED_PROXIES") {
+            // Comma separated, empty trusts none
+            self.trusted_proxies = value
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| parse("TRUSTED_PROXIES", proxy.to_string()))
+                .collect::<anyhow::Result<_>>()?;
+        }
+
+        set!("DB_MAX_CONNECTIONS", self.pool.max_connections);
+        set!("DB_MIN_CONNECTIONS", self.pool.min_connections);
+        set!("DB_ACQUIRE_TIMEOUT_SECS", self.pool.acquire_timeout_secs);
+        if let Some(value) = var("DB_IDLE_TIMEOUT_SECS") {
+            // Empty disables the timeout
+            self.pool.idle_timeout_secs = if value.is_empty() {
+                None
+            } else {
+                Some(parse("DB_IDLE_TIMEOUT_SECS", value)?)
+            };
+        }
+
+        set!("LOG_LEVEL", self.log.level);
+        set!("LOG_FORMAT", self.log.format);
+
+        match (var("TLS_CERT"), var("TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(TlsConfig {
+                    cert: cert.into(),
+                    key: key.into(),
+                })
+            }
+            (None, None) => {}
+            _ => bail!("TLS_CERT and TLS_KEY have to be set together"),
+        }
+
+        set!("LOGIN_BACKOFF_AFTER", self.login.backoff_after);
+        set!("LOGIN_BACKOFF_BASE_SECS", self.login.backoff_base_secs);
+        set!("LOGIN_BACKOFF_MAX_SECS", self.login.backoff_max_secs);
+        set!("LOGIN_LOCKOUT_AFTER", self.login.lockout_after);
+        set!("LOGIN_LOCKOUT_MINUTES", self.login.lockout_minutes);
+        set!("SCHEDULE_EDIT_DAYS_BACK", self.schedule.edit_days_back);
+        // Empty removes the cutoff or the limit
+        if let Some(value) = var("SCHEDULE_CUTOFF") {
+            self.schedule.cutoff = if value.is_empty() {
+                None
+            } else {
+                let time = parse_time(&value).map_err(|e| {
+                    anyhow!("SCHEDULE_CUTOFF has an invalid value {:?}: {}", value, e)
+                })?;
+                Some(time)
+            };
+        }
+        set!(
+            "SCHEDULE_ALLOW_NON_WORKERS",
+            self.schedule.allow_non_workers
+        );
+        if let Some(value) = var("SCHEDULE_MAX_WORKERS_PER_DAY") {
+            self.schedule.max_workers_per_day = if value.is_empty() {
+                None
+            } else {
+                Some(parse("SCHEDULE_MAX_WORKERS_PER_DAY", value)?)
+            };
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            !self.database_url.is_empty(),
+            "DATABASE_URL is not set, neither in the environment nor in the config file"
+        );
+        ensure!(
+            self.pool.max_connections > 0,
+            "pool.max_connections has to be at least 1"
+        );
+        ensure!(
+            self.pool.min_connections <= self.pool.max_connections,
+            "pool.min_connections ({}) is larger than pool.max_connections ({})",
+            self.pool.min_connections,
+            self.pool.max_connections
+        );
+        if let Some(tls) = &self.tls {
+            ensure!(
+                tls.cert.is_file(),
+                "TLS certificate {} does not exist",
+                tls.cert.display()
+            );
+            ensure!(
+                tls.key.is_file(),
+                "TLS key {} does not exist",
+                tls.key.display()
+            );
+        }
+        let login = &self.login;
+        ensure!(
+            login.backoff_base_secs >= 0
+                && login.backoff_max_secs >= 0
+                && login.lockout_minutes >= 0,
+            "login durations can't be negative"
+        );
+        ensure!(
+            login.lockout_after > 0,
+            "login.lockout_after has to be at least 1"
+        );
+        ensure!(
+            self.schedule.max_workers_per_day != Some(0),
+            "schedule.max_workers_per_day has to be at least 1"
+        );
+        Ok(())
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl PoolConfig {
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+}
+
+impl LoginConfig {
+    pub fn throttle(&self) -> ThrottleConfig {
+        ThrottleConfig {
+            backoff_after: self.backoff_after,
+            backoff_base: chrono::Duration::seconds(self.backoff_base_secs),
+            backoff_max: chrono::Duration::seconds(self.backoff_max_secs),
+            lockout_after: self.lockout_after,
+            lockout_duration: chrono::Duration::minutes(self.lockout_minutes),
+        }
+    }
+}
+
+impl ScheduleConfig {
+    pub fn policy(&self) -> SchedulePolicy {
+        SchedulePolicy {
+            edit_days_back: self.edit_days_back,
+            cutoff: self.cutoff,
+            allow_non_workers: self.allow_non_workers,
+            max_workers_per_day: self.max_workers_per_day,
+        }
+    }
+}
+
+fn parse_time(value: &str) -> chrono::ParseResult<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+}
+
+fn time_of_day<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_time(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars = vars.iter().copied().collect::<HashMap<_, _>>();
+        move |name| vars.get(name).map(|v| v.to_string())
+    }
+
+    #[test]
+    fn test_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            database_url = "postgres://localhost/pravda"
+            listen = "0.0.0.0:8080"
+            trusted_proxies = ["127.0.0.1", "::1"]
+
+            [pool]
+            max_connections = 10
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [login]
+            lockout_after = 5
+
+            [schedule]
+            cutoff = "10:30"
+            max_workers_per_day = 4
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.assets_dir, PathBuf::from("assets"));
+        assert_eq!(
+            config.trusted_proxies,
+            [
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.pool.max_connections, 10);
+        assert_eq!(config.pool.acquire_timeout_secs, 30);
+        assert_eq!(config.log.level, Level::DEBUG);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert!(config.tls.is_none());
+        assert_eq!(config.login.lockout_after, 5);
+        assert_eq!(config.login.backoff_after, 3);
+        let policy = config.schedule.policy();
+        assert_eq!(policy.cutoff, NaiveTime::from_hms_opt(10, 30, 0));
+        assert_eq!(policy.max_workers_per_day, Some(4));
+        assert_eq!(policy.edit_days_back, 3);
+        assert!(!policy.allow_non_workers);
+        config.validate().unwrap();
+
+        assert!(toml::from_str::<Config>("listen = \"localhost\"").is_err());
+        assert!(toml::from_str::<Config>("[log]\nlevel = \"loud\"").is_err());
+        assert!(toml::from_str::<Config>("port = 3000").is_err());
+        assert!(toml::from_str::<Config>("[schedule]\ncutoff = \"noon\"").is_err());
+
+        let example = Config::from_file(Path::new("pravda.example.toml")).unwrap();
+        example.validate().unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                ("DATABASE_URL", "sqlite://pravda.db"),
+                ("LISTEN_ADDR", "[::]:443"),
+                ("TRUSTED_PROXIES", "10.0.0.1, 10.0.0.2"),
+                ("DB_MAX_CONNECTIONS", "8"),
+                ("DB_IDLE_TIMEOUT_SECS", ""),
+                ("LOG_FORMAT", "JSON"),
+                ("LOGIN_LOCKOUT_MINUTES", "60"),
+                ("SCHEDULE_CUTOFF", "09:00"),
+                ("SCHEDULE_ALLOW_NON_WORKERS", "true"),
+            ]))
+            .unwrap();
+        assert_eq!(config.database_url, "sqlite://pravda.db");
+        assert_eq!(config.listen.port(), 443);
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert_eq!(config.pool.max_connections, 8);
+        assert_eq!(config.pool.idle_timeout(), None);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(
+            config.login.throttle().lockout_duration,
+            chrono::Duration::hours(1)
+        );
+
+        assert_eq!(config.schedule.cutoff, NaiveTime::from_hms_opt(9, 0, 0));
+        assert!(config.schedule.allow_non_workers);
+        config
+            .apply_env(env(&[
+                ("DB_IDLE_TIMEOUT_SECS", "60"),
+                ("SCHEDULE_CUTOFF", ""),
+                ("TRUSTED_PROXIES", ""),
+            ]))
+            .unwrap();
+        assert!(config.trusted_proxies.is_empty());
+        assert_eq!(config.schedule.cutoff, None);
+        assert_eq!(config.pool.idle_timeout(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut config = Config::default();
+        let error = config
+            .apply_env(env(&[("DB_MAX_CONNECTIONS", "many")]))
+            .unwrap_err();
+        assert!(error.to_string().contains("DB_MAX_CONNECTIONS"));
+        let error = config
+            .apply_env(env(&[("TRUSTED_PROXIES", "10.0.0.1,proxy")]))
+            .unwrap_err();
+        assert!(error.to_string().contains("TRUSTED_PROXIES"));
+        assert!(config.apply_env(env(&[("TLS_CERT", "cert.pem")])).is_err());
+        assert!(config.validate().is_err());
+
+        config.database_url = "postgres://localhost/pravda".to_string();
+        config.validate().unwrap();
+        config.pool.min_connections = 5;
+        assert!(config.validate().is_err());
+        config.pool.min_connections = 0;
+        config.schedule.max_workers_per_day = Some(0);
+        assert!(config.validate().is_err());
+        config.schedule.max_workers_per_day = None;
+        config.tls = Some(TlsConfig {
+            cert: "missing.pem".into(),
+            key: "missing.pem".into(),
+        });
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("missing.pem"));
+    }
+}
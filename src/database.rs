@@ -1,81 +1,924 @@
 use crate::utils;
+use anyhow::bail;
 use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use pravda_protocol::ProtocolError;
+use rust_decimal::Decimal;
+use sqlx::error::ErrorKind;
+use sqlx::migrate::{AppliedMigration, Migrator};
+use tracing::{error, warn};
 
 pub enum UserSearch {
     Id(i32),
     Login(String),
+    /// Hash of a session token, only matches sessions that haven't expired
     Token(String),
 }
 
+/// Money and percents are exact decimals, see `payroll::round_money` for how they're rounded.
+/// SQLite reads these from decimal text by hand instead of deriving `FromRow`.
+#[derive(Clone)]
 pub struct UserData {
     pub id: i32,
     pub login: String,
     pub name: String,
     pub is_admin: bool,
     pub is_worker: bool,
-    pub pay: f64,
-    pub percent: f64,
+    pub pay: Decimal,
+    pub percent: Decimal,
     pub pwd_hash: String,
     pub pwd_salt: String,
-    pub token: String,
+    /// Deactivated users can't log in or be scheduled, they stay for the
+    /// history they're part of
+    pub is_active: bool,
+    pub deactivated_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct SessionData {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: String,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct SetupCodeData {
+    pub user_id: i32,
+    pub code_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct LockoutData {
+    pub id: i32,
+    pub login: Option<String>,
+    pub address: Option<String>,
+    pub locked_at: DateTime<Utc>,
+    pub locked_until: DateTime<Utc>,
+    pub cleared_at: Option<DateTime<Utc>>,
+    pub cleared_by: Option<i32>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct AuditData {
+    pub id: i32,
+    /// Who made the change
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub kind: String,
+    /// JSON of the affected values before and after the change
+    pub value_before: Option<String>,
+    pub value_after: Option<String>,
+}
+
+/// Every field narrows the search, `from` is inclusive and `to` exclusive
+#[derive(Default)]
+pub struct AuditFilter {
+    pub user_id: Option<i32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub kind: Option<String>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
 pub struct ScheduleData {
-    pub day: i32,
-    pub month: i32,
-    pub year: i32,
+    pub day: NaiveDate,
     pub user_id: i32,
 }
 
+#[derive(Clone, Debug)]
 pub struct RevenueData {
-    pub day: i32,
-    pub month: i32,
-    pub year: i32,
-    pub with_percent: f64,
-    pub without_percent: f64,
+    pub day: NaiveDate,
+    pub with_percent: Decimal,
+    pub without_percent: Decimal,
 }
 
+#[derive(Clone, Debug)]
 pub struct PayoutData {
-    pub day: i32,
-    pub month: i32,
+    pub day: NaiveDate,
+    pub user_id: i32,
+    pub amount: Decimal,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct ClosedMonthData {
     pub year: i32,
+    pub month: i32,
+    pub closed_at: DateTime<Utc>,
+    pub closed_by: i32,
+    /// JSON of the salary calculation at the time of closing
+    pub snapshot: String,
+}
+
+#[derive(Clone)]
+pub struct PayRateData {
     pub user_id: i32,
-    pub amount: f64,
+    pub valid_from: NaiveDate,
+    pub pay: Decimal,
+    pub percent: Decimal,
 }
 
-pub struct SalaryData {
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct CommissionRuleData {
     pub user_id: i32,
-    pub amount_paid: f64,
-    pub amount_owed: f64,
+    pub valid_from: NaiveDate,
+    /// `payroll::CommissionRule` as JSON
+    pub rule: String,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "sqlite", derive(sqlx::FromRow))]
+pub struct HolidayData {
+    pub day: NaiveDate,
+    pub name: String,
+}
+
+/// Why a query failed. Messages are for the log, clients only learn the kind.
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error("not found")]
+    NotFound,
+    /// A unique key is taken, or a concurrent transaction got there first
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// A foreign key, check or not null constraint rejected a row
+    #[error("constraint violation: {0}")]
+    ConstraintViolation(String),
+    /// The database can't be reached or has no free connections
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => DbError::NotFound,
+            sqlx::Error::Database(db) => {
+                let message = db.message().to_string();
+                match db.code().as_deref() {
+                    // Serialization failures and deadlocks in Postgres, a busy
+                    // or locked database in SQLite
+                    Some("40001" | "40P01" | "5" | "6" | "517") => {
+                        return DbError::Conflict(message)
+                    }
+                    // SQLite checks decimal text with triggers that raise an abort
+                    Some("1811") => return DbError::ConstraintViolation(message),
+                    _ => {}
+                }
+                match db.kind() {
+                    ErrorKind::UniqueViolation => DbError::Conflict(message),
+                    ErrorKind::ForeignKeyViolation
+                    | ErrorKind::NotNullViolation
+                    | ErrorKind::CheckViolation => DbError::ConstraintViolation(message),
+                    _ => DbError::Other(e.into()),
+                }
+            }
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => DbError::Unavailable(e.to_string()),
+            _ => DbError::Other(e.into()),
+        }
+    }
+}
+
+impl From<DbError> for ProtocolError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::NotFound => ProtocolError::NotFound,
+            DbError::Conflict(_) => {
+                warn!("Database error: {}", e);
+                ProtocolError::Conflict
+            }
+            DbError::ConstraintViolation(_) => {
+                warn!("Database error: {}", e);
+                ProtocolError::ConstraintViolation
+            }
+            DbError::Unavailable(_) => {
+                error!("Database error: {}", e);
+                ProtocolError::Unavailable
+            }
+            DbError::Other(_) => {
+                error!("Database error: {:#}", e);
+                ProtocolError::Unknown("Ошибка базы данных".to_string())
+            }
+        }
+    }
 }
 
 #[async_trait]
-pub trait Database {
+pub trait Database: Queries {
+    type Transaction: Transaction;
+
+    // Health
+    /// Fails unless the database answers queries and has every migration applied
+    async fn check_ready(&self) -> anyhow::Result<()>;
+
+    // Transactions
+    /// Queries of the returned handle run in one transaction, seeing its own
+    /// writes and nobody else's half-done ones. Dropping the handle without
+    /// committing rolls it back.
+    async fn begin(&self) -> DbResult<Self::Transaction>;
+}
+
+#[async_trait]
+pub trait Transaction: Queries + Sized {
+    async fn commit(self) -> DbResult<()>;
+    async fn rollback(self) -> DbResult<()>;
+}
+
+/// Reads and writes, of a database or of one of its transactions
+#[async_trait]
+pub trait Queries: Send + Sync {
     // Users
-    async fn add_user(&self, user: &UserData) -> anyhow::Result<UserData>;
-    async fn get_user(&self, user_search: &UserSearch) -> anyhow::Result<Option<UserData>>;
-    async fn get_users(&self, ids: Option<&[i32]>) -> anyhow::Result<Vec<UserData>>;
-    async fn update_user(&self, user: &UserData) -> anyhow::Result<UserData>;
+    async fn add_user(&self, user: &UserData) -> DbResult<UserData>;
+    async fn get_user(&self, user_search: &UserSearch) -> DbResult<Option<UserData>>;
+    async fn get_users(&self, ids: Option<&[i32]>) -> DbResult<Vec<UserData>>;
+    async fn update_user(&self, user: &UserData) -> DbResult<UserData>;
+
+    // Sessions
+    async fn add_session(&self, session: &SessionData) -> DbResult<()>;
+    async fn touch_session(
+        &self,
+        token_hash: &str,
+        last_seen_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<()>;
+    async fn delete_sessions(&self, user_id: i32, token_hash: Option<&str>) -> DbResult<()>;
+    async fn delete_expired_sessions(&self, now: DateTime<Utc>) -> DbResult<()>;
+
+    // Setup codes
+    async fn set_setup_code(&self, setup_code: &SetupCodeData) -> DbResult<()>;
+    async fn get_setup_code(&self, user_id: i32) -> DbResult<Option<SetupCodeData>>;
+    async fn delete_setup_code(&self, user_id: i32) -> DbResult<()>;
+
+    // Lockouts
+    async fn add_lockout(&self, lockout: &LockoutData) -> DbResult<LockoutData>;
+    /// All lockouts, or only those still in force at `active_at`
+    async fn get_lockouts(&self, active_at: Option<DateTime<Utc>>) -> DbResult<Vec<LockoutData>>;
+    /// Whether a lockout of the login or the address is in force at `at`
+    async fn is_locked_out(
+        &self,
+        login: &str,
+        address: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> DbResult<bool>;
+    async fn clear_lockout(
+        &self,
+        id: i32,
+        cleared_by: i32,
+        cleared_at: DateTime<Utc>,
+    ) -> DbResult<()>;
+
+    // Audit log
+    async fn add_audit(&self, audit: &AuditData) -> DbResult<()>;
+    /// Newest entries first
+    async fn get_audit_log(&self, filter: &AuditFilter) -> DbResult<Vec<AuditData>>;
 
     // Schedule
-    async fn get_schedule(&self, month: u8, year: u16) -> anyhow::Result<Vec<ScheduleData>>;
-    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> anyhow::Result<()>;
+    /// Workdays from `from` up to, but not including, `to`, ordered by day and user
+    async fn get_schedule(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<ScheduleData>>;
+    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> DbResult<()>;
 
     // Revenue
-    async fn get_revenue(&self, month: u8, year: u16) -> anyhow::Result<Vec<RevenueData>>;
-    async fn set_revenue(&self, revenue: &RevenueData) -> anyhow::Result<()>;
+    /// Revenue from `from` up to, but not including, `to`, ordered by day
+    async fn get_revenue(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<RevenueData>>;
+    async fn set_revenue(&self, revenue: &RevenueData) -> DbResult<()>;
 
     // Payouts
-    async fn get_payouts(&self, month: u8, year: u16) -> anyhow::Result<Vec<PayoutData>>;
-    async fn add_payout(&self, payout: &PayoutData) -> anyhow::Result<()>;
+    /// Payouts from `from` up to, but not including, `to`, ordered by day and user
+    async fn get_payouts(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<PayoutData>>;
+    async fn add_payout(&self, payout: &PayoutData) -> DbResult<()>;
+    async fn delete_payout(&self, user_id: i32, day: NaiveDate) -> DbResult<()>;
 
-    // Salary
-    async fn get_salaries(&self, month: u8, year: u16) -> anyhow::Result<Vec<SalaryData>>;
+    // Closed months
+    /// Ordered by month
+    async fn get_closed_months(&self, year: u16) -> DbResult<Vec<ClosedMonthData>>;
+    /// Fails if the month is closed already
+    async fn close_month(&self, closed: &ClosedMonthData) -> DbResult<()>;
+    async fn reopen_month(&self, month: u8, year: u16) -> DbResult<()>;
+
+    // Pay rates
+    /// Ordered by user and `valid_from`
+    async fn get_pay_rates(&self, user_id: Option<i32>) -> DbResult<Vec<PayRateData>>;
+    async fn set_pay_rate(&self, rate: &PayRateData) -> DbResult<()>;
+    async fn delete_pay_rate(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()>;
+
+    // Commission rules
+    /// Ordered by user and `valid_from`
+    async fn get_commission_rules(&self, user_id: Option<i32>)
+        -> DbResult<Vec<CommissionRuleData>>;
+    async fn set_commission_rule(&self, rule: &CommissionRuleData) -> DbResult<()>;
+    async fn delete_commission_rule(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()>;
+
+    // Holidays
+    /// Holidays from `from` up to, but not including, `to`
+    async fn get_holidays(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<HolidayData>>;
+    async fn set_holiday(&self, holiday: &HolidayData) -> DbResult<()>;
+    async fn delete_holiday(&self, day: NaiveDate) -> DbResult<()>;
 }
 
 impl UserData {
-    pub fn get_pwd_hash(&self, password: impl AsRef<str>) -> String {
-        let pwd = format!("{}#{}", password.as_ref(), self.pwd_salt);
-        utils::sha3(pwd)
+    pub fn set_password(&mut self, password: impl AsRef<str>) -> anyhow::Result<()> {
+        self.pwd_hash = utils::argon2_hash(password)?;
+        self.pwd_salt = String::new();
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: impl AsRef<str>) -> bool {
+        if self.pwd_hash.is_empty() {
+            // No password until the user redeems a setup code
+            false
+        } else if self.needs_rehash() {
+            // Salted SHA3 hashes stored before the switch to Argon2id
+            let pwd = format!("{}#{}", password.as_ref(), self.pwd_salt);
+            self.pwd_hash == utils::sha3(pwd)
+        } else {
+            utils::argon2_verify(password, &self.pwd_hash)
+        }
+    }
+
+    pub fn needs_rehash(&self) -> bool {
+        !self.pwd_hash.starts_with("$argon2id$")
+    }
+}
+
+/// Fails if a migration from `migrator` is missing in `applied` or was edited after applying
+pub fn check_migrations(migrator: &Migrator, applied: &[AppliedMigration]) -> anyhow::Result<()> {
+    for migration in migrator.iter() {
+        match applied.iter().find(|a| a.version == migration.version) {
+            None => bail!("migration {} is not applied", migration.version),
+            Some(a) if a.checksum != migration.checksum => {
+                bail!(
+                    "migration {} changed after it was applied",
+                    migration.version
+                )
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone};
+
+    fn user(login: &str, is_worker: bool, pay: i64, percent: i64) -> UserData {
+        UserData {
+            id: 0,
+            login: login.to_string(),
+            name: login.to_uppercase(),
+            is_admin: !is_worker,
+            is_worker,
+            pay: pay.into(),
+            percent: percent.into(),
+            pwd_hash: "hash".to_string(),
+            pwd_salt: utils::make_uuid(),
+            is_active: true,
+            deactivated_at: None,
+        }
+    }
+
+    async fn find(db: &impl Database, token_hash: &str) -> Option<i32> {
+        let search = UserSearch::Token(token_hash.to_string());
+        db.get_user(&search).await.unwrap().map(|u| u.id)
+    }
+
+    /// Behaviour every `Database` implementation has to share.
+    pub async fn conformance(db: &impl Database) {
+        db.check_ready().await.unwrap();
+
+        // Users
+        let first = db.add_user(&user("first", true, 1000, 10)).await.unwrap();
+        let second = db.add_user(&user("second", true, 500, 20)).await.unwrap();
+        let admin = db.add_user(&user("admin", false, 0, 0)).await.unwrap();
+        assert!(matches!(
+            db.add_user(&user("first", true, 0, 0)).await,
+            Err(DbError::Conflict(_))
+        ));
+
+        let found = db.get_user(&UserSearch::Login("second".to_string())).await;
+        assert_eq!(found.unwrap().unwrap().id, second.id);
+        let found = db.get_user(&UserSearch::Id(-1)).await;
+        assert!(found.unwrap().is_none());
+
+        let renamed = UserData {
+            name: "Renamed".to_string(),
+            ..first.clone()
+        };
+        assert_eq!(db.update_user(&renamed).await.unwrap().name, "Renamed");
+        let deactivated = UserData {
+            is_active: false,
+            deactivated_at: Some(Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap()),
+            ..second.clone()
+        };
+        let updated = db.update_user(&deactivated).await.unwrap();
+        assert!(!updated.is_active);
+        assert_eq!(updated.deactivated_at, deactivated.deactivated_at);
+        // Deactivated users are still there for the history they're part of
+        assert_eq!(db.get_users(None).await.unwrap().len(), 3);
+        db.update_user(&second).await.unwrap();
+        let users = db.get_users(Some(&[first.id, admin.id])).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|u| u.id != second.id));
+
+        // Sessions
+        let now = Utc::now();
+        let session = |token_hash: &str, user_id, expires_at| SessionData {
+            token_hash: token_hash.to_string(),
+            user_id,
+            created_at: now,
+            last_seen_at: now,
+            expires_at,
+            user_agent: "test".to_string(),
+        };
+        let day = chrono::Duration::days(1);
+        db.add_session(&session("phone", admin.id, now + day))
+            .await
+            .unwrap();
+        db.add_session(&session("tablet", admin.id, now - day))
+            .await
+            .unwrap();
+        db.add_session(&session("laptop", first.id, now + day))
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.add_session(&session("nobody", -1, now)).await,
+            Err(DbError::ConstraintViolation(_))
+        ));
+        assert_eq!(find(db, "phone").await.unwrap(), admin.id);
+        assert!(find(db, "tablet").await.is_none());
+        assert!(find(db, "unknown").await.is_none());
+
+        db.touch_session("tablet", now, now + day).await.unwrap();
+        assert_eq!(find(db, "tablet").await.unwrap(), admin.id);
+        db.delete_sessions(admin.id, Some("tablet")).await.unwrap();
+        assert!(find(db, "tablet").await.is_none());
+        assert!(find(db, "phone").await.is_some());
+        db.delete_sessions(admin.id, Some("laptop")).await.unwrap();
+        assert!(find(db, "laptop").await.is_some());
+        db.delete_sessions(admin.id, None).await.unwrap();
+        assert!(find(db, "phone").await.is_none());
+        assert!(find(db, "laptop").await.is_some());
+        db.add_session(&session("old", first.id, now - day))
+            .await
+            .unwrap();
+        db.delete_expired_sessions(now).await.unwrap();
+        // Touching doesn't bring back a deleted session
+        db.touch_session("old", now, now + day).await.unwrap();
+        assert!(find(db, "old").await.is_none());
+        assert!(find(db, "laptop").await.is_some());
+
+        // Setup codes
+        let setup_code = |code_hash: &str| SetupCodeData {
+            user_id: second.id,
+            code_hash: code_hash.to_string(),
+            expires_at: now + day,
+        };
+        assert!(db.get_setup_code(second.id).await.unwrap().is_none());
+        db.set_setup_code(&setup_code("old")).await.unwrap();
+        db.set_setup_code(&setup_code("new")).await.unwrap();
+        let stored = db.get_setup_code(second.id).await.unwrap().unwrap();
+        assert_eq!(stored.code_hash, "new");
+        assert!(db.get_setup_code(first.id).await.unwrap().is_none());
+        db.delete_setup_code(second.id).await.unwrap();
+        assert!(db.get_setup_code(second.id).await.unwrap().is_none());
+
+        // Lockouts
+        let lockout = |login: Option<&str>, address: Option<&str>, locked_until| LockoutData {
+            id: 0,
+            login: login.map(|l| l.to_string()),
+            address: address.map(|a| a.to_string()),
+            locked_at: now - day,
+            locked_until,
+            cleared_at: None,
+            cleared_by: None,
+        };
+        let by_login = db
+            .add_lockout(&lockout(Some("first"), None, now + day))
+            .await
+            .unwrap();
+        let by_address = db
+            .add_lockout(&lockout(None, Some("10.0.0.1"), now + day))
+            .await
+            .unwrap();
+        db.add_lockout(&lockout(Some("second"), None, now - day))
+            .await
+            .unwrap();
+        assert_ne!(by_login.id, by_address.id);
+        assert_eq!(by_address.address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(db.get_lockouts(Some(now)).await.unwrap().len(), 2);
+        assert!(db.is_locked_out("first", None, now).await.unwrap());
+        assert!(db
+            .is_locked_out("other", Some("10.0.0.1"), now)
+            .await
+            .unwrap());
+        assert!(!db
+            .is_locked_out("second", Some("10.0.0.2"), now)
+            .await
+            .unwrap());
+        assert!(!db
+            .is_locked_out("first", None, now + day * 2)
+            .await
+            .unwrap());
+        db.clear_lockout(by_login.id, admin.id, now).await.unwrap();
+        assert!(!db.is_locked_out("first", None, now).await.unwrap());
+        let active = db.get_lockouts(Some(now)).await.unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, by_address.id);
+        let all = db.get_lockouts(None).await.unwrap();
+        assert_eq!(all.len(), 3);
+        let cleared = all.iter().find(|l| l.id == by_login.id).unwrap();
+        assert_eq!(cleared.cleared_by, Some(admin.id));
+        assert!(cleared.cleared_at.is_some());
+
+        // Audit log
+        let audit = |user_id, kind: &str, created_at| AuditData {
+            id: 0,
+            user_id,
+            created_at,
+            kind: kind.to_string(),
+            value_before: None,
+            value_after: Some(format!("{{\"kind\":\"{}\"}}", kind)),
+        };
+        db.add_audit(&audit(admin.id, "SetRevenue", now - day))
+            .await
+            .unwrap();
+        db.add_audit(&audit(first.id, "SetWorkday", now))
+            .await
+            .unwrap();
+        db.add_audit(&audit(admin.id, "AddUser", now))
+            .await
+            .unwrap();
+        assert!(db.add_audit(&audit(-1, "AddUser", now)).await.is_err());
+        let audit_log = |filter| async move { db.get_audit_log(&filter).await.unwrap() };
+        let all = audit_log(AuditFilter {
+            limit: 10,
+            ..Default::default()
+        })
+        .await;
+        let kinds = all.iter().map(|a| a.kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, ["AddUser", "SetWorkday", "SetRevenue"]);
+        assert_eq!(
+            all[0].value_after.as_deref(),
+            Some("{\"kind\":\"AddUser\"}")
+        );
+        assert!(all[0].value_before.is_none());
+        let page = audit_log(AuditFilter {
+            offset: 1,
+            limit: 1,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].kind, "SetWorkday");
+        let by_admin = audit_log(AuditFilter {
+            user_id: Some(admin.id),
+            limit: 10,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(by_admin.len(), 2);
+        let by_kind = audit_log(AuditFilter {
+            kind: Some("SetWorkday".to_string()),
+            limit: 10,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(by_kind.len(), 1);
+        assert_eq!(by_kind[0].user_id, first.id);
+        let yesterday = audit_log(AuditFilter {
+            from: Some(now - day * 2),
+            to: Some(now - day / 2),
+            limit: 10,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(yesterday.len(), 1);
+        assert_eq!(yesterday[0].kind, "SetRevenue");
+
+        // Schedule
+        let date = |month, day| NaiveDate::from_ymd_opt(2023, month, day).unwrap();
+        let (june, july) = (date(6, 1), date(7, 1));
+        let workday = |day, user_id| ScheduleData {
+            day: date(6, day),
+            user_id,
+        };
+        for (day, user_id) in [(1, first.id), (2, first.id), (3, first.id), (1, second.id)] {
+            db.set_schedule(&workday(day, user_id), true).await.unwrap();
+        }
+        db.set_schedule(&workday(2, second.id), true).await.unwrap();
+        db.set_schedule(&workday(2, second.id), true).await.unwrap();
+        db.set_schedule(&workday(5, second.id), true).await.unwrap();
+        db.set_schedule(&workday(5, second.id), false)
+            .await
+            .unwrap();
+        assert!(db.set_schedule(&workday(5, -1), true).await.is_err());
+        let schedule = db.get_schedule(june, july).await.unwrap();
+        let schedule = schedule
+            .iter()
+            .map(|s| (s.day.day(), s.user_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            schedule,
+            [
+                (1, first.id),
+                (1, second.id),
+                (2, first.id),
+                (2, second.id),
+                (3, first.id)
+            ]
+        );
+        assert_eq!(
+            db.get_schedule(date(6, 2), date(6, 3)).await.unwrap().len(),
+            2
+        );
+        assert!(db.get_schedule(july, date(8, 1)).await.unwrap().is_empty());
+
+        // Revenue, day 3 has none and day 4 has nobody scheduled
+        let revenue = |day, with_percent| RevenueData {
+            day: date(6, day),
+            with_percent,
+            without_percent: Decimal::new(500010, 2),
+        };
+        db.set_revenue(&revenue(1, Decimal::ONE)).await.unwrap();
+        for (day, with_percent) in [(1, 1000), (2, 600), (4, 400)] {
+            db.set_revenue(&revenue(day, with_percent.into()))
+                .await
+                .unwrap();
+        }
+        let stored = db.get_revenue(june, july).await.unwrap();
+        assert_eq!(stored.len(), 3);
+        let first_day = &stored[0];
+        assert_eq!(first_day.day, june);
+        assert_eq!(first_day.with_percent, Decimal::from(1000));
+        assert_eq!(first_day.without_percent, Decimal::new(500010, 2));
+
+        // Payouts
+        let payout = |day, month, user_id, amount| PayoutData {
+            day: date(month, day),
+            user_id,
+            amount,
+        };
+        for (day, month, user_id, amount) in [
+            (10, 6, first.id, 10000),
+            (10, 6, first.id, 30010),
+            (20, 6, first.id, 20020),
+            (20, 6, second.id, 5000),
+            (10, 7, first.id, 99900),
+        ] {
+            db.add_payout(&payout(day, month, user_id, Decimal::new(amount, 2)))
+                .await
+                .unwrap();
+        }
+        db.delete_payout(second.id, date(6, 20)).await.unwrap();
+        let stored = db.get_payouts(june, july).await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(
+            stored.iter().map(|p| p.amount).sum::<Decimal>(),
+            Decimal::new(50030, 2)
+        );
+        // Ranges can span months
+        let stored = db.get_payouts(date(6, 15), date(7, 15)).await.unwrap();
+        let days = stored.iter().map(|p| p.day).collect::<Vec<_>>();
+        assert_eq!(days, [date(6, 20), date(7, 10)]);
+
+        // Transactions
+        let august = |day| NaiveDate::from_ymd_opt(2023, 8, day).unwrap();
+        let september = NaiveDate::from_ymd_opt(2023, 9, 1).unwrap();
+        let revenue = RevenueData {
+            day: august(1),
+            with_percent: Decimal::new(100050, 2),
+            without_percent: Decimal::ZERO,
+        };
+        let tx = db.begin().await.unwrap();
+        tx.set_revenue(&revenue).await.unwrap();
+        // Only the transaction sees its writes until it commits
+        assert_eq!(tx.get_revenue(august(1), september).await.unwrap().len(), 1);
+        assert!(db
+            .get_revenue(august(1), september)
+            .await
+            .unwrap()
+            .is_empty());
+        tx.rollback().await.unwrap();
+        assert!(db
+            .get_revenue(august(1), september)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let tx = db.begin().await.unwrap();
+        let workday = ScheduleData {
+            day: august(1),
+            user_id: first.id,
+        };
+        tx.set_schedule(&workday, true).await.unwrap();
+        tx.set_revenue(&revenue).await.unwrap();
+        tx.commit().await.unwrap();
+        let schedule = db.get_schedule(august(1), september).await.unwrap();
+        assert_eq!(schedule.len(), 1);
+        let stored = db.get_revenue(august(1), september).await.unwrap();
+        assert_eq!(stored[0].with_percent, Decimal::new(100050, 2));
+
+        // Dropping a transaction rolls it back
+        let tx = db.begin().await.unwrap();
+        tx.add_payout(&payout(2, 8, second.id, Decimal::ONE))
+            .await
+            .unwrap();
+        drop(tx);
+        assert!(db
+            .get_payouts(august(1), september)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Closed months
+        let closed = |month, snapshot: &str| ClosedMonthData {
+            year: 2023,
+            month,
+            closed_at: now,
+            closed_by: first.id,
+            snapshot: snapshot.to_string(),
+        };
+        db.close_month(&closed(7, "july")).await.unwrap();
+        db.close_month(&closed(6, "june")).await.unwrap();
+        assert!(matches!(
+            db.close_month(&closed(6, "again")).await,
+            Err(DbError::Conflict(_))
+        ));
+        let months = db.get_closed_months(2023).await.unwrap();
+        let months = months
+            .iter()
+            .map(|m| (m.month, m.closed_by, m.snapshot.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(months, [(6, first.id, "june"), (7, first.id, "july")]);
+        assert!(db.get_closed_months(2024).await.unwrap().is_empty());
+        db.reopen_month(6, 2023).await.unwrap();
+        let months = db.get_closed_months(2023).await.unwrap();
+        assert_eq!(months.len(), 1);
+        assert_eq!(months[0].month, 7);
+
+        // Pay rates
+        let rate = |user_id, valid_from, pay: i64| PayRateData {
+            user_id,
+            valid_from,
+            pay: pay.into(),
+            percent: Decimal::new(125, 1),
+        };
+        db.set_pay_rate(&rate(second.id, date(1, 1), 100))
+            .await
+            .unwrap();
+        db.set_pay_rate(&rate(first.id, date(6, 1), 200))
+            .await
+            .unwrap();
+        db.set_pay_rate(&rate(first.id, date(1, 1), 300))
+            .await
+            .unwrap();
+        db.set_pay_rate(&rate(first.id, date(6, 1), 250))
+            .await
+            .unwrap();
+        assert!(db.set_pay_rate(&rate(-1, date(6, 1), 1)).await.is_err());
+        let rates = db.get_pay_rates(None).await.unwrap();
+        let rates = rates
+            .iter()
+            .map(|r| (r.user_id, r.valid_from, r.pay, r.percent))
+            .collect::<Vec<_>>();
+        let percent = Decimal::new(125, 1);
+        assert_eq!(
+            rates,
+            [
+                (first.id, date(1, 1), 300.into(), percent),
+                (first.id, date(6, 1), 250.into(), percent),
+                (second.id, date(1, 1), 100.into(), percent),
+            ]
+        );
+        db.delete_pay_rate(first.id, date(1, 1)).await.unwrap();
+        let rates = db.get_pay_rates(Some(first.id)).await.unwrap();
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[0].pay, Decimal::from(250));
+
+        // Commission rules
+        let rule = |user_id, valid_from, rule: &str| CommissionRuleData {
+            user_id,
+            valid_from,
+            rule: rule.to_string(),
+        };
+        db.set_commission_rule(&rule(second.id, date(6, 1), "june"))
+            .await
+            .unwrap();
+        db.set_commission_rule(&rule(first.id, date(7, 1), "july"))
+            .await
+            .unwrap();
+        db.set_commission_rule(&rule(first.id, date(6, 15), "old"))
+            .await
+            .unwrap();
+        db.set_commission_rule(&rule(first.id, date(6, 15), "mid-june"))
+            .await
+            .unwrap();
+        assert!(db
+            .set_commission_rule(&rule(-1, date(6, 1), "nobody"))
+            .await
+            .is_err());
+        let rules = db.get_commission_rules(None).await.unwrap();
+        let rules = rules
+            .iter()
+            .map(|r| (r.user_id, r.valid_from, r.rule.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                (first.id, date(6, 15), "mid-june"),
+                (first.id, date(7, 1), "july"),
+                (second.id, date(6, 1), "june"),
+            ]
+        );
+        db.delete_commission_rule(first.id, date(7, 1))
+            .await
+            .unwrap();
+        let rules = db.get_commission_rules(Some(first.id)).await.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].rule, "mid-june");
+
+        // Holidays
+        let holiday = |day, name: &str| HolidayData {
+            day,
+            name: name.to_string(),
+        };
+        db.set_holiday(&holiday(date(6, 12), "Russia Day"))
+            .await
+            .unwrap();
+        db.set_holiday(&holiday(date(7, 1), "Next month"))
+            .await
+            .unwrap();
+        db.set_holiday(&holiday(date(6, 1), "Old name"))
+            .await
+            .unwrap();
+        db.set_holiday(&holiday(date(6, 1), "Children's Day"))
+            .await
+            .unwrap();
+        let holidays = db.get_holidays(date(6, 1), date(7, 1)).await.unwrap();
+        let holidays = holidays
+            .iter()
+            .map(|h| (h.day, h.name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            holidays,
+            [(date(6, 1), "Children's Day"), (date(6, 12), "Russia Day")]
+        );
+        db.delete_holiday(date(6, 1)).await.unwrap();
+        assert_eq!(
+            db.get_holidays(date(1, 1), date(12, 31))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Checks
+        assert!(matches!(
+            db.add_user(&user("negative", true, -1, 10)).await,
+            Err(DbError::ConstraintViolation(_))
+        ));
+        let mut invalid = first.clone();
+        invalid.percent = Decimal::from(101);
+        assert!(db.update_user(&invalid).await.is_err());
+        let revenue = RevenueData {
+            day: june,
+            with_percent: Decimal::ZERO,
+            without_percent: Decimal::NEGATIVE_ONE,
+        };
+        assert!(db.set_revenue(&revenue).await.is_err());
+        let payout = PayoutData {
+            day: june,
+            user_id: first.id,
+            amount: Decimal::NEGATIVE_ONE,
+        };
+        assert!(db.add_payout(&payout).await.is_err());
+        let rate = PayRateData {
+            user_id: first.id,
+            valid_from: date(1, 1),
+            pay: Decimal::ZERO,
+            percent: Decimal::NEGATIVE_ONE,
+        };
+        assert!(db.set_pay_rate(&rate).await.is_err());
     }
 }
@@ -0,0 +1,631 @@
+use crate::database::*;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+#[derive(Clone, Default)]
+struct Tables {
+    last_user_id: i32,
+    users: BTreeMap<i32, UserData>,
+    sessions: BTreeMap<String, SessionData>,
+    setup_codes: BTreeMap<i32, SetupCodeData>,
+    lockouts: Vec<LockoutData>,
+    audit_log: Vec<AuditData>,
+    schedule: BTreeSet<(NaiveDate, i32)>,
+    revenue: BTreeMap<NaiveDate, (Decimal, Decimal)>,
+    payouts: BTreeMap<(NaiveDate, i32), Decimal>,
+    closed_months: BTreeMap<(i32, i32), ClosedMonthData>,
+    pay_rates: BTreeMap<(i32, NaiveDate), (Decimal, Decimal)>,
+    commission_rules: BTreeMap<(i32, NaiveDate), String>,
+    holidays: BTreeMap<NaiveDate, String>,
+}
+
+/// `Database` kept entirely in process memory. Mirrors the constraints of
+/// the Postgres schema (unique logins, foreign keys to `users`, checks).
+#[derive(Clone, Default)]
+pub struct DatabaseMemory<T = ()> {
+    tables: Arc<RwLock<Tables>>,
+    /// Held by the open transaction and by every write outside it, so writes
+    /// wait for the transaction instead of making it conflict
+    writer: Arc<Mutex<()>>,
+    transaction: T,
+}
+
+/// Works on its own copy of the tables, which replaces the database's ones
+/// on commit. Nobody else writes while it's open.
+pub type TransactionMemory = DatabaseMemory<Open>;
+
+pub struct Open {
+    database: Arc<RwLock<Tables>>,
+    _writer: OwnedMutexGuard<()>,
+}
+
+impl DatabaseMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T> DatabaseMemory<T> {
+    fn read(&self) -> RwLockReadGuard<'_, Tables> {
+        self.tables.read().unwrap()
+    }
+
+    async fn write<R>(&self, f: impl FnOnce(&mut Tables) -> DbResult<R>) -> DbResult<R> {
+        let _writer = self.writer.lock().await;
+        let mut tables = self.tables.write().unwrap();
+        f(&mut tables)
+    }
+}
+
+impl Tables {
+    fn check_login(&self, user: &UserData) -> DbResult<()> {
+        if self
+            .users
+            .values()
+            .any(|u| u.login == user.login && u.id != user.id)
+        {
+            return Err(DbError::Conflict(format!(
+                "login {} already exists",
+                user.login
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_user(&self, user_id: i32) -> DbResult<()> {
+        if !self.users.contains_key(&user_id) {
+            return Err(DbError::ConstraintViolation(format!(
+                "user {} does not exist",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_payout(&self, payout: &PayoutData) -> DbResult<()> {
+        self.check_user(payout.user_id)?;
+        if payout.amount < Decimal::ZERO {
+            return Err(DbError::ConstraintViolation("negative payout".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn check_revenue(revenue: &RevenueData) -> DbResult<()> {
+    if revenue.with_percent < Decimal::ZERO || revenue.without_percent < Decimal::ZERO {
+        return Err(DbError::ConstraintViolation("negative revenue".to_string()));
+    }
+    Ok(())
+}
+
+fn check_month(year: i32, month: i32) -> DbResult<()> {
+    if year <= 0 || !(1..=12).contains(&month) {
+        return Err(DbError::ConstraintViolation(format!(
+            "invalid month {}.{}",
+            month, year
+        )));
+    }
+    Ok(())
+}
+
+fn check_rate(pay: Decimal, percent: Decimal) -> DbResult<()> {
+    if pay < Decimal::ZERO || !(Decimal::ZERO..=Decimal::ONE_HUNDRED).contains(&percent) {
+        return Err(DbError::ConstraintViolation(format!(
+            "invalid pay {} or percent {}",
+            pay, percent
+        )));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Database for DatabaseMemory {
+    type Transaction = TransactionMemory;
+
+    // Health
+    async fn check_ready(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Transactions
+    async fn begin(&self) -> DbResult<TransactionMemory> {
+        let writer = self.writer.clone().lock_owned().await;
+        let tables = self.read().clone();
+        Ok(DatabaseMemory {
+            transaction: Open {
+                database: self.tables.clone(),
+                _writer: writer,
+            },
+            tables: Arc::new(RwLock::new(tables)),
+            // The database's lock is held, the copy is the transaction's alone
+            writer: Default::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Transaction for TransactionMemory {
+    async fn commit(self) -> DbResult<()> {
+        *self.transaction.database.write().unwrap() = self.read().clone();
+        Ok(())
+    }
+
+    async fn rollback(self) -> DbResult<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Send + Sync> Queries for DatabaseMemory<T> {
+    // Users
+    async fn add_user(&self, user: &UserData) -> DbResult<UserData> {
+        self.write(|tables| {
+            tables.check_login(user)?;
+            check_rate(user.pay, user.percent)?;
+            tables.last_user_id += 1;
+            let user = UserData {
+                id: tables.last_user_id,
+                ..user.clone()
+            };
+            tables.users.insert(user.id, user.clone());
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn get_user(&self, user_search: &UserSearch) -> DbResult<Option<UserData>> {
+        let tables = self.read();
+        let user = match user_search {
+            UserSearch::Id(id) => tables.users.get(id),
+            UserSearch::Login(login) => tables.users.values().find(|u| &u.login == login),
+            UserSearch::Token(token_hash) => tables
+                .sessions
+                .get(token_hash)
+                .filter(|s| s.expires_at > Utc::now())
+                .and_then(|s| tables.users.get(&s.user_id)),
+        };
+        Ok(user.cloned())
+    }
+
+    async fn get_users(&self, ids: Option<&[i32]>) -> DbResult<Vec<UserData>> {
+        let tables = self.read();
+        let users = tables
+            .users
+            .values()
+            .filter(|u| ids.is_none_or(|ids| ids.contains(&u.id)))
+            .cloned()
+            .collect();
+        Ok(users)
+    }
+
+    async fn update_user(&self, user: &UserData) -> DbResult<UserData> {
+        self.write(|tables| {
+            tables.check_user(user.id)?;
+            tables.check_login(user)?;
+            check_rate(user.pay, user.percent)?;
+            tables.users.insert(user.id, user.clone());
+            Ok(user.clone())
+        })
+        .await
+    }
+
+    // Sessions
+    async fn add_session(&self, session: &SessionData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(session.user_id)?;
+            if tables.sessions.contains_key(&session.token_hash) {
+                return Err(DbError::Conflict("session already exists".to_string()));
+            }
+            tables
+                .sessions
+                .insert(session.token_hash.clone(), session.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn touch_session(
+        &self,
+        token_hash: &str,
+        last_seen_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        self.write(|tables| {
+            if let Some(session) = tables.sessions.get_mut(token_hash) {
+                session.last_seen_at = last_seen_at;
+                session.expires_at = expires_at;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_sessions(&self, user_id: i32, token_hash: Option<&str>) -> DbResult<()> {
+        self.write(|tables| {
+            tables.sessions.retain(|hash, s| {
+                s.user_id != user_id || token_hash.is_some_and(|token_hash| token_hash != hash)
+            });
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_expired_sessions(&self, now: DateTime<Utc>) -> DbResult<()> {
+        self.write(|tables| {
+            tables.sessions.retain(|_, s| s.expires_at > now);
+            Ok(())
+        })
+        .await
+    }
+
+    // Setup codes
+    async fn set_setup_code(&self, setup_code: &SetupCodeData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(setup_code.user_id)?;
+            tables
+                .setup_codes
+                .insert(setup_code.user_id, setup_code.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_setup_code(&self, user_id: i32) -> DbResult<Option<SetupCodeData>> {
+        let tables = self.read();
+        Ok(tables.setup_codes.get(&user_id).cloned())
+    }
+
+    async fn delete_setup_code(&self, user_id: i32) -> DbResult<()> {
+        self.write(|tables| {
+            tables.setup_codes.remove(&user_id);
+            Ok(())
+        })
+        .await
+    }
+
+    // Lockouts
+    async fn add_lockout(&self, lockout: &LockoutData) -> DbResult<LockoutData> {
+        self.write(|tables| {
+            let lockout = LockoutData {
+                id: tables.lockouts.len() as i32 + 1,
+                ..lockout.clone()
+            };
+            tables.lockouts.push(lockout.clone());
+            Ok(lockout)
+        })
+        .await
+    }
+
+    async fn get_lockouts(&self, active_at: Option<DateTime<Utc>>) -> DbResult<Vec<LockoutData>> {
+        let tables = self.read();
+        let lockouts = tables
+            .lockouts
+            .iter()
+            .filter(|l| active_at.is_none_or(|now| l.locked_until > now && l.cleared_at.is_none()))
+            .cloned()
+            .collect();
+        Ok(lockouts)
+    }
+
+    async fn is_locked_out(
+        &self,
+        login: &str,
+        address: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let tables = self.read();
+        Ok(tables.lockouts.iter().any(|l| {
+            l.locked_until > at
+                && l.cleared_at.is_none()
+                && (l.login.as_deref() == Some(login)
+                    || address.is_some() && l.address.as_deref() == address)
+        }))
+    }
+
+    async fn clear_lockout(
+        &self,
+        id: i32,
+        cleared_by: i32,
+        cleared_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(cleared_by)?;
+            if let Some(lockout) = tables
+                .lockouts
+                .iter_mut()
+                .find(|l| l.id == id && l.cleared_at.is_none())
+            {
+                lockout.cleared_at = Some(cleared_at);
+                lockout.cleared_by = Some(cleared_by);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // Audit log
+    async fn add_audit(&self, audit: &AuditData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(audit.user_id)?;
+            let audit = AuditData {
+                id: tables.audit_log.len() as i32 + 1,
+                ..audit.clone()
+            };
+            tables.audit_log.push(audit);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_audit_log(&self, filter: &AuditFilter) -> DbResult<Vec<AuditData>> {
+        let tables = self.read();
+        let audit_log = tables
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|a| filter.user_id.is_none_or(|id| a.user_id == id))
+            .filter(|a| filter.from.is_none_or(|from| a.created_at >= from))
+            .filter(|a| filter.to.is_none_or(|to| a.created_at < to))
+            .filter(|a| filter.kind.as_ref().is_none_or(|kind| &a.kind == kind))
+            .skip(filter.offset as usize)
+            .take(filter.limit as usize)
+            .cloned()
+            .collect();
+        Ok(audit_log)
+    }
+
+    // Schedule
+    async fn get_schedule(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<ScheduleData>> {
+        let tables = self.read();
+        let schedule = tables
+            .schedule
+            .range((from, i32::MIN)..(to, i32::MIN))
+            .map(|&(day, user_id)| ScheduleData { day, user_id })
+            .collect();
+        Ok(schedule)
+    }
+
+    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> DbResult<()> {
+        self.write(|tables| {
+            let key = (schedule.day, schedule.user_id);
+            if working {
+                tables.check_user(schedule.user_id)?;
+                tables.schedule.insert(key);
+            } else {
+                tables.schedule.remove(&key);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // Revenue
+    async fn get_revenue(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<RevenueData>> {
+        let tables = self.read();
+        let revenue = tables
+            .revenue
+            .range(from..to)
+            .map(|(&day, &(with_percent, without_percent))| RevenueData {
+                day,
+                with_percent,
+                without_percent,
+            })
+            .collect();
+        Ok(revenue)
+    }
+
+    async fn set_revenue(&self, revenue: &RevenueData) -> DbResult<()> {
+        self.write(|tables| {
+            check_revenue(revenue)?;
+            tables
+                .revenue
+                .insert(revenue.day, (revenue.with_percent, revenue.without_percent));
+            Ok(())
+        })
+        .await
+    }
+
+    // Payouts
+    async fn get_payouts(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<PayoutData>> {
+        let tables = self.read();
+        let payouts = tables
+            .payouts
+            .range((from, i32::MIN)..(to, i32::MIN))
+            .map(|(&(day, user_id), &amount)| PayoutData {
+                day,
+                user_id,
+                amount,
+            })
+            .collect();
+        Ok(payouts)
+    }
+
+    async fn add_payout(&self, payout: &PayoutData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_payout(payout)?;
+            tables
+                .payouts
+                .insert((payout.day, payout.user_id), payout.amount);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_payout(&self, user_id: i32, day: NaiveDate) -> DbResult<()> {
+        self.write(|tables| {
+            tables.payouts.remove(&(day, user_id));
+            Ok(())
+        })
+        .await
+    }
+
+    // Commission rules
+    async fn get_commission_rules(
+        &self,
+        user_id: Option<i32>,
+    ) -> DbResult<Vec<CommissionRuleData>> {
+        let tables = self.read();
+        let rules = tables
+            .commission_rules
+            .iter()
+            .filter(|((id, _), _)| user_id.is_none_or(|user_id| *id == user_id))
+            .map(|(&(user_id, valid_from), rule)| CommissionRuleData {
+                user_id,
+                valid_from,
+                rule: rule.clone(),
+            })
+            .collect();
+        Ok(rules)
+    }
+
+    async fn set_commission_rule(&self, rule: &CommissionRuleData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(rule.user_id)?;
+            tables
+                .commission_rules
+                .insert((rule.user_id, rule.valid_from), rule.rule.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_commission_rule(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        self.write(|tables| {
+            tables.commission_rules.remove(&(user_id, valid_from));
+            Ok(())
+        })
+        .await
+    }
+
+    // Holidays
+    async fn get_holidays(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<HolidayData>> {
+        let tables = self.read();
+        let holidays = tables
+            .holidays
+            .range(from..to)
+            .map(|(&day, name)| HolidayData {
+                day,
+                name: name.clone(),
+            })
+            .collect();
+        Ok(holidays)
+    }
+
+    async fn set_holiday(&self, holiday: &HolidayData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.holidays.insert(holiday.day, holiday.name.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_holiday(&self, day: NaiveDate) -> DbResult<()> {
+        self.write(|tables| {
+            tables.holidays.remove(&day);
+            Ok(())
+        })
+        .await
+    }
+
+    // Pay rates
+    async fn get_pay_rates(&self, user_id: Option<i32>) -> DbResult<Vec<PayRateData>> {
+        let tables = self.read();
+        let rates = tables
+            .pay_rates
+            .iter()
+            .filter(|((id, _), _)| user_id.is_none_or(|user_id| *id == user_id))
+            .map(|(&(user_id, valid_from), &(pay, percent))| PayRateData {
+                user_id,
+                valid_from,
+                pay,
+                percent,
+            })
+            .collect();
+        Ok(rates)
+    }
+
+    async fn set_pay_rate(&self, rate: &PayRateData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(rate.user_id)?;
+            check_rate(rate.pay, rate.percent)?;
+            tables
+                .pay_rates
+                .insert((rate.user_id, rate.valid_from), (rate.pay, rate.percent));
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_pay_rate(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        self.write(|tables| {
+            tables.pay_rates.remove(&(user_id, valid_from));
+            Ok(())
+        })
+        .await
+    }
+
+    // Closed months
+    async fn get_closed_months(&self, year: u16) -> DbResult<Vec<ClosedMonthData>> {
+        let tables = self.read();
+        let year = year as i32;
+        let months = tables
+            .closed_months
+            .range((year, i32::MIN)..=(year, i32::MAX))
+            .map(|(_, closed)| closed.clone())
+            .collect();
+        Ok(months)
+    }
+
+    async fn close_month(&self, closed: &ClosedMonthData) -> DbResult<()> {
+        self.write(|tables| {
+            tables.check_user(closed.closed_by)?;
+            check_month(closed.year, closed.month)?;
+            let key = (closed.year, closed.month);
+            if tables.closed_months.contains_key(&key) {
+                return Err(DbError::Conflict(format!(
+                    "month {}.{} is closed already",
+                    closed.month, closed.year
+                )));
+            }
+            tables.closed_months.insert(key, closed.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reopen_month(&self, month: u8, year: u16) -> DbResult<()> {
+        self.write(|tables| {
+            tables.closed_months.remove(&(year as i32, month as i32));
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::database::tests::conformance(&DatabaseMemory::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_write_waits_for_transaction() {
+        let db = DatabaseMemory::new();
+        let tx = db.begin().await.unwrap();
+        let write = tokio::spawn({
+            let db = db.clone();
+            async move { db.touch_session("hash", Utc::now(), Utc::now()).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!write.is_finished());
+        tx.commit().await.unwrap();
+        write.await.unwrap().unwrap();
+    }
+}
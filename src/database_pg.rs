@@ -1,32 +1,106 @@
+use crate::config::PoolConfig;
 use crate::database::*;
 use async_trait::async_trait;
-use sqlx::postgres::{PgPool, PgPoolOptions};
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::postgres::{PgConnection, PgPool, PgPoolOptions, Postgres};
+use std::ops::DerefMut;
+use tokio::sync::{Mutex, MutexGuard};
 
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Runs queries on connections from a pool, or on the one of a transaction
 #[derive(Clone)]
-pub struct DatabasePg {
-    pool: PgPool,
+pub struct DatabasePg<C = PgPool> {
+    conn: C,
+}
+
+pub type TransactionPg = DatabasePg<Mutex<sqlx::Transaction<'static, Postgres>>>;
+
+pub type Connection<'a> = Box<dyn DerefMut<Target = PgConnection> + Send + 'a>;
+
+/// Where `DatabasePg` gets a connection for the next query
+#[async_trait]
+pub trait Connect: Send + Sync {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>>;
+}
+
+#[async_trait]
+impl Connect for PgPool {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>> {
+        Ok(Box::new(self.acquire().await?))
+    }
+}
+
+#[async_trait]
+impl Connect for Mutex<sqlx::Transaction<'static, Postgres>> {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>> {
+        Ok(Box::new(MutexGuard::map(self.lock().await, |tx| &mut **tx)))
+    }
 }
 
 impl DatabasePg {
-    pub async fn connect(database_url: impl AsRef<str>) -> anyhow::Result<Self> {
+    pub async fn connect(database_url: impl AsRef<str>, pool: &PoolConfig) -> anyhow::Result<Self> {
         let db = PgPoolOptions::new()
-            .max_connections(3)
+            .max_connections(pool.max_connections)
+            .min_connections(pool.min_connections)
+            .acquire_timeout(pool.acquire_timeout())
+            .idle_timeout(pool.idle_timeout())
             .connect(database_url.as_ref())
             .await?;
-        sqlx::migrate!().run(&db).await?;
-        Ok(Self { pool: db })
+        MIGRATOR.run(&db).await?;
+        Ok(Self { conn: db })
     }
 }
 
 #[async_trait]
 impl Database for DatabasePg {
+    type Transaction = TransactionPg;
+
+    // Health
+    async fn check_ready(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await?;
+        let applied = conn.list_applied_migrations().await?;
+        check_migrations(&MIGRATOR, &applied)
+    }
+
+    // Transactions
+    async fn begin(&self) -> DbResult<TransactionPg> {
+        let mut tx = self.conn.begin().await?;
+        // Checks then writes behave as if nobody else ran in between
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+            .execute(&mut *tx)
+            .await?;
+        Ok(DatabasePg {
+            conn: Mutex::new(tx),
+        })
+    }
+}
+
+#[async_trait]
+impl Transaction for TransactionPg {
+    async fn commit(self) -> DbResult<()> {
+        self.conn.into_inner().commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(self) -> DbResult<()> {
+        self.conn.into_inner().rollback().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: Connect> Queries for DatabasePg<C> {
     // Users
-    async fn add_user(&self, user: &UserData) -> anyhow::Result<UserData> {
+    async fn add_user(&self, user: &UserData) -> DbResult<UserData> {
         let user = sqlx::query_as!(
             UserData,
             r#"INSERT INTO
-        users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt, token)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *"#,
+        users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+        is_active, deactivated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *"#,
             user.login,
             user.name,
             user.is_admin,
@@ -35,58 +109,65 @@ impl Database for DatabasePg {
             user.percent,
             user.pwd_hash,
             user.pwd_salt,
-            user.token
+            user.is_active,
+            user.deactivated_at
         )
-        .fetch_one(&self.pool)
+        .fetch_one(&mut **self.conn.connection().await?)
         .await?;
         Ok(user)
     }
 
-    async fn get_user(&self, user_search: &UserSearch) -> anyhow::Result<Option<UserData>> {
+    async fn get_user(&self, user_search: &UserSearch) -> DbResult<Option<UserData>> {
         let user = match user_search {
             UserSearch::Id(id) => {
                 sqlx::query_as!(UserData, r#"SELECT * FROM users WHERE id = $1"#, id)
-                    .fetch_optional(&self.pool)
+                    .fetch_optional(&mut **self.conn.connection().await?)
                     .await?
             }
 
             UserSearch::Login(login) => {
                 sqlx::query_as!(UserData, r#"SELECT * FROM users WHERE login = $1"#, login)
-                    .fetch_optional(&self.pool)
+                    .fetch_optional(&mut **self.conn.connection().await?)
                     .await?
             }
 
-            UserSearch::Token(token) => {
-                sqlx::query_as!(UserData, r#"SELECT * FROM users WHERE token = $1"#, token)
-                    .fetch_optional(&self.pool)
-                    .await?
+            UserSearch::Token(token_hash) => {
+                sqlx::query_as!(
+                    UserData,
+                    r#"SELECT u.* FROM users u
+                    JOIN sessions s ON s.user_id = u.id
+                    WHERE s.token_hash = $1 AND s.expires_at > NOW()"#,
+                    token_hash
+                )
+                .fetch_optional(&mut **self.conn.connection().await?)
+                .await?
             }
         };
         Ok(user)
     }
 
-    async fn get_users(&self, ids: Option<&[i32]>) -> anyhow::Result<Vec<UserData>> {
+    async fn get_users(&self, ids: Option<&[i32]>) -> DbResult<Vec<UserData>> {
         let users = match ids {
             None => {
                 sqlx::query_as!(UserData, r#"SELECT * FROM users"#)
-                    .fetch_all(&self.pool)
+                    .fetch_all(&mut **self.conn.connection().await?)
                     .await?
             }
             Some(ids) => {
                 sqlx::query_as!(UserData, r#"SELECT * FROM users WHERE id = ANY($1)"#, ids)
-                    .fetch_all(&self.pool)
+                    .fetch_all(&mut **self.conn.connection().await?)
                     .await?
             }
         };
         Ok(users)
     }
 
-    async fn update_user(&self, user: &UserData) -> anyhow::Result<UserData> {
+    async fn update_user(&self, user: &UserData) -> DbResult<UserData> {
         let user = sqlx::query_as!(
             UserData,
             r#"UPDATE users
         SET login = $2, name = $3, is_admin = $4, is_worker = $5, pay = $6,
-        percent = $7, pwd_hash = $8, pwd_salt = $9, token = $10
+        percent = $7, pwd_hash = $8, pwd_salt = $9, is_active = $10, deactivated_at = $11
         WHERE id = $1 RETURNING *"#,
             user.id,
             user.login,
@@ -97,160 +178,597 @@ impl Database for DatabasePg {
             user.percent,
             user.pwd_hash,
             user.pwd_salt,
-            user.token
+            user.is_active,
+            user.deactivated_at
         )
-        .fetch_one(&self.pool)
+        .fetch_one(&mut **self.conn.connection().await?)
         .await?;
         Ok(user)
     }
 
+    // Sessions
+    async fn add_session(&self, session: &SessionData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO sessions VALUES ($1, $2, $3, $4, $5, $6)"#,
+            session.token_hash,
+            session.user_id,
+            session.created_at,
+            session.last_seen_at,
+            session.expires_at,
+            session.user_agent,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn touch_session(
+        &self,
+        token_hash: &str,
+        last_seen_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            r#"UPDATE sessions SET last_seen_at = $2, expires_at = $3 WHERE token_hash = $1"#,
+            token_hash,
+            last_seen_at,
+            expires_at,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sessions(&self, user_id: i32, token_hash: Option<&str>) -> DbResult<()> {
+        sqlx::query!(
+            r#"DELETE FROM sessions
+            WHERE user_id = $1 AND ($2::VARCHAR IS NULL OR token_hash = $2)"#,
+            user_id,
+            token_hash,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_expired_sessions(&self, now: DateTime<Utc>) -> DbResult<()> {
+        sqlx::query!(r#"DELETE FROM sessions WHERE expires_at <= $1"#, now)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Setup codes
+    async fn set_setup_code(&self, setup_code: &SetupCodeData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO setup_codes VALUES ($1, $2, $3)
+            ON CONFLICT(user_id) DO UPDATE
+            SET code_hash = $2, expires_at = $3"#,
+            setup_code.user_id,
+            setup_code.code_hash,
+            setup_code.expires_at,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_setup_code(&self, user_id: i32) -> DbResult<Option<SetupCodeData>> {
+        let setup_code = sqlx::query_as!(
+            SetupCodeData,
+            r#"SELECT * FROM setup_codes WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_optional(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(setup_code)
+    }
+
+    async fn delete_setup_code(&self, user_id: i32) -> DbResult<()> {
+        sqlx::query!(r#"DELETE FROM setup_codes WHERE user_id = $1"#, user_id)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Lockouts
+    async fn add_lockout(&self, lockout: &LockoutData) -> DbResult<LockoutData> {
+        let lockout = sqlx::query_as!(
+            LockoutData,
+            r#"INSERT INTO
+        lockouts(login, address, locked_at, locked_until, cleared_at, cleared_by)
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING *"#,
+            lockout.login,
+            lockout.address,
+            lockout.locked_at,
+            lockout.locked_until,
+            lockout.cleared_at,
+            lockout.cleared_by,
+        )
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(lockout)
+    }
+
+    async fn get_lockouts(&self, active_at: Option<DateTime<Utc>>) -> DbResult<Vec<LockoutData>> {
+        let lockouts = sqlx::query_as!(
+            LockoutData,
+            r#"SELECT * FROM lockouts
+            WHERE $1::TIMESTAMPTZ IS NULL OR (locked_until > $1 AND cleared_at IS NULL)
+            ORDER BY id"#,
+            active_at,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(lockouts)
+    }
+
+    async fn is_locked_out(
+        &self,
+        login: &str,
+        address: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let locked = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM lockouts
+                WHERE cleared_at IS NULL AND locked_until > $3 AND (login = $1 OR address = $2)
+            ) AS "locked!""#,
+            login,
+            address,
+            at,
+        )
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(locked)
+    }
+
+    async fn clear_lockout(
+        &self,
+        id: i32,
+        cleared_by: i32,
+        cleared_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            r#"UPDATE lockouts SET cleared_at = $2, cleared_by = $3
+            WHERE id = $1 AND cleared_at IS NULL"#,
+            id,
+            cleared_at,
+            cleared_by,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Audit log
+    async fn add_audit(&self, audit: &AuditData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO
+        audit_log(user_id, created_at, kind, value_before, value_after)
+        VALUES ($1, $2, $3, $4, $5)"#,
+            audit.user_id,
+            audit.created_at,
+            audit.kind,
+            audit.value_before,
+            audit.value_after,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, filter: &AuditFilter) -> DbResult<Vec<AuditData>> {
+        let audit_log = sqlx::query_as!(
+            AuditData,
+            r#"SELECT * FROM audit_log
+            WHERE ($1::INTEGER IS NULL OR user_id = $1)
+              AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2)
+              AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3)
+              AND ($4::VARCHAR IS NULL OR kind = $4)
+            ORDER BY id DESC
+            OFFSET $5 LIMIT $6"#,
+            filter.user_id,
+            filter.from,
+            filter.to,
+            filter.kind,
+            filter.offset,
+            filter.limit,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(audit_log)
+    }
+
     // Schedule
-    async fn get_schedule(&self, month: u8, year: u16) -> anyhow::Result<Vec<ScheduleData>> {
+    async fn get_schedule(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<ScheduleData>> {
         let schedule = sqlx::query_as!(
             ScheduleData,
-            r#"SELECT * FROM schedule WHERE month = $1 AND year = $2"#,
-            month as i32,
-            year as i32,
+            r#"SELECT day, user_id FROM schedule
+            WHERE day >= $1 AND day < $2
+            ORDER BY day, user_id"#,
+            from,
+            to,
         )
-        .fetch_all(&self.pool)
+        .fetch_all(&mut **self.conn.connection().await?)
         .await?;
         Ok(schedule)
     }
 
-    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> anyhow::Result<()> {
+    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> DbResult<()> {
         if working {
             sqlx::query!(
-                r#"INSERT INTO schedule VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING"#,
+                r#"INSERT INTO schedule(day, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
                 schedule.day,
-                schedule.month,
-                schedule.year,
                 schedule.user_id
             )
-            .execute(&self.pool)
+            .execute(&mut **self.conn.connection().await?)
             .await?;
         } else {
             sqlx::query!(
-                r#"DELETE FROM schedule
-                WHERE day = $1 AND month = $2 AND year = $3 AND user_id = $4"#,
+                r#"DELETE FROM schedule WHERE day = $1 AND user_id = $2"#,
                 schedule.day,
-                schedule.month,
-                schedule.year,
                 schedule.user_id
             )
-            .execute(&self.pool)
+            .execute(&mut **self.conn.connection().await?)
             .await?;
         }
         Ok(())
     }
 
     // Revenue
-    async fn get_revenue(&self, month: u8, year: u16) -> anyhow::Result<Vec<RevenueData>> {
-        let schedule = sqlx::query_as!(
+    async fn get_revenue(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<RevenueData>> {
+        let revenue = sqlx::query_as!(
             RevenueData,
-            r#"SELECT * FROM revenue WHERE month = $1 AND year = $2"#,
-            month as i32,
-            year as i32,
+            r#"SELECT day, with_percent, without_percent FROM revenue
+            WHERE day >= $1 AND day < $2
+            ORDER BY day"#,
+            from,
+            to,
         )
-        .fetch_all(&self.pool)
+        .fetch_all(&mut **self.conn.connection().await?)
         .await?;
-        Ok(schedule)
+        Ok(revenue)
     }
 
-    async fn set_revenue(&self, revenue: &RevenueData) -> anyhow::Result<()> {
+    async fn set_revenue(&self, revenue: &RevenueData) -> DbResult<()> {
         sqlx::query!(
-            r#"INSERT INTO revenue VALUES ($1, $2, $3, $4, $5)
-            ON CONFLICT(day, month, year) DO UPDATE
-            SET with_percent = $4, without_percent = $5"#,
+            r#"INSERT INTO revenue(day, with_percent, without_percent) VALUES ($1, $2, $3)
+            ON CONFLICT(day) DO UPDATE
+            SET with_percent = $2, without_percent = $3"#,
             revenue.day,
-            revenue.month,
-            revenue.year,
             revenue.with_percent,
             revenue.without_percent,
         )
-        .execute(&self.pool)
+        .execute(&mut **self.conn.connection().await?)
         .await?;
         Ok(())
     }
 
     // Payouts
-    async fn get_payouts(&self, month: u8, year: u16) -> anyhow::Result<Vec<PayoutData>> {
-        let schedule = sqlx::query_as!(
+    async fn get_payouts(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<PayoutData>> {
+        let payouts = sqlx::query_as!(
             PayoutData,
-            r#"SELECT * FROM payouts WHERE month = $1 AND year = $2"#,
-            month as i32,
-            year as i32,
+            r#"SELECT day, user_id, amount FROM payouts
+            WHERE day >= $1 AND day < $2
+            ORDER BY day, user_id"#,
+            from,
+            to,
         )
-        .fetch_all(&self.pool)
+        .fetch_all(&mut **self.conn.connection().await?)
         .await?;
-        Ok(schedule)
+        Ok(payouts)
     }
 
-    async fn add_payout(&self, payout: &PayoutData) -> anyhow::Result<()> {
+    async fn add_payout(&self, payout: &PayoutData) -> DbResult<()> {
         sqlx::query!(
-            r#"INSERT INTO payouts VALUES ($1, $2, $3, $4, $5)
-            ON CONFLICT(day, month, year, user_id) DO UPDATE
-            SET amount = $5"#,
+            r#"INSERT INTO payouts(day, user_id, amount) VALUES ($1, $2, $3)
+            ON CONFLICT(day, user_id) DO UPDATE
+            SET amount = $3"#,
             payout.day,
-            payout.month,
-            payout.year,
             payout.user_id,
             payout.amount,
         )
-        .execute(&self.pool)
+        .execute(&mut **self.conn.connection().await?)
         .await?;
         Ok(())
     }
 
-    // Salary
-    async fn get_salaries(&self, month: u8, year: u16) -> anyhow::Result<Vec<SalaryData>> {
-        let schedule = sqlx::query_as!(
-            SalaryData,
-            r#"
-SELECT u.id AS "user_id!",
-       (u.pay * COALESCE(s.working_days, 0) + COALESCE(SUM(s.with_percent * u.percent / 100), 0)) AS "amount_owed!",
-       COALESCE(p.amount_paid, 0) AS "amount_paid!"
-FROM users u
-LEFT JOIN
-  (SELECT s.user_id,
-          COUNT(*) AS working_days,
-          SUM(r.with_percent / n.num_users) AS with_percent
-   FROM schedule s
-   JOIN revenue r ON s.day = r.day
-   AND s.month = r.month
-   AND s.year = r.year
-   JOIN
-     (SELECT DAY,
-             MONTH,
-             YEAR,
-             COUNT(DISTINCT user_id) AS num_users
-      FROM schedule
-      WHERE month = $1
-        AND year = $2
-      GROUP BY DAY,
-               MONTH,
-               YEAR) n ON s.day = n.day
-   AND s.month = n.month
-   AND s.year = n.year
-   WHERE s.month = $1
-     AND s.year = $2
-   GROUP BY s.user_id) s ON u.id = s.user_id
-LEFT JOIN
-  (SELECT user_id,
-          SUM(amount) AS amount_paid
-   FROM payouts
-   WHERE month = $1
-     AND year = $2
-   GROUP BY user_id) p ON u.id = p.user_id
-WHERE u.is_worker
-GROUP BY u.id,
-         u.pay,
-         s.working_days,
-         p.amount_paid;"#,
+    async fn delete_payout(&self, user_id: i32, day: NaiveDate) -> DbResult<()> {
+        sqlx::query!(
+            r#"DELETE FROM payouts WHERE day = $1 AND user_id = $2"#,
+            day,
+            user_id
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Commission rules
+    async fn get_commission_rules(
+        &self,
+        user_id: Option<i32>,
+    ) -> DbResult<Vec<CommissionRuleData>> {
+        let rules = sqlx::query_as!(
+            CommissionRuleData,
+            r#"SELECT * FROM commission_rules
+            WHERE $1::INTEGER IS NULL OR user_id = $1
+            ORDER BY user_id, valid_from"#,
+            user_id,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(rules)
+    }
+
+    async fn set_commission_rule(&self, rule: &CommissionRuleData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO commission_rules VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, valid_from) DO UPDATE SET rule = $3"#,
+            rule.user_id,
+            rule.valid_from,
+            rule.rule,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_commission_rule(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        sqlx::query!(
+            r#"DELETE FROM commission_rules WHERE user_id = $1 AND valid_from = $2"#,
+            user_id,
+            valid_from,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Holidays
+    async fn get_holidays(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<HolidayData>> {
+        let holidays = sqlx::query_as!(
+            HolidayData,
+            r#"SELECT * FROM holidays WHERE day >= $1 AND day < $2 ORDER BY day"#,
+            from,
+            to,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(holidays)
+    }
+
+    async fn set_holiday(&self, holiday: &HolidayData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO holidays VALUES ($1, $2)
+            ON CONFLICT (day) DO UPDATE SET name = $2"#,
+            holiday.day,
+            holiday.name,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_holiday(&self, day: NaiveDate) -> DbResult<()> {
+        sqlx::query!(r#"DELETE FROM holidays WHERE day = $1"#, day)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Pay rates
+    async fn get_pay_rates(&self, user_id: Option<i32>) -> DbResult<Vec<PayRateData>> {
+        let rates = sqlx::query_as!(
+            PayRateData,
+            r#"SELECT * FROM pay_rates
+            WHERE $1::INTEGER IS NULL OR user_id = $1
+            ORDER BY user_id, valid_from"#,
+            user_id,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(rates)
+    }
+
+    async fn set_pay_rate(&self, rate: &PayRateData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO pay_rates VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, valid_from) DO UPDATE SET pay = $3, percent = $4"#,
+            rate.user_id,
+            rate.valid_from,
+            rate.pay,
+            rate.percent,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_pay_rate(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        sqlx::query!(
+            r#"DELETE FROM pay_rates WHERE user_id = $1 AND valid_from = $2"#,
+            user_id,
+            valid_from,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Closed months
+    async fn get_closed_months(&self, year: u16) -> DbResult<Vec<ClosedMonthData>> {
+        let months = sqlx::query_as!(
+            ClosedMonthData,
+            r#"SELECT * FROM closed_months WHERE year = $1 ORDER BY month"#,
+            year as i32,
+        )
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(months)
+    }
+
+    async fn close_month(&self, closed: &ClosedMonthData) -> DbResult<()> {
+        sqlx::query!(
+            r#"INSERT INTO closed_months VALUES ($1, $2, $3, $4, $5)"#,
+            closed.year,
+            closed.month,
+            closed.closed_at,
+            closed.closed_by,
+            closed.snapshot,
+        )
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn reopen_month(&self, month: u8, year: u16) -> DbResult<()> {
+        sqlx::query!(
+            r#"DELETE FROM closed_months WHERE month = $1 AND year = $2"#,
             month as i32,
             year as i32,
         )
-            .fetch_all(&self.pool)
-            .await?;
-        Ok(schedule)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[sqlx::test]
+    async fn test_conformance(pool: PgPool) {
+        crate::database::tests::conformance(&DatabasePg { conn: pool }).await;
+    }
+
+    #[sqlx::test]
+    async fn test_not_ready_without_latest_migration(pool: PgPool) {
+        let db = DatabasePg { conn: pool };
+        db.check_ready().await.unwrap();
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)")
+            .execute(&db.conn)
+            .await
+            .unwrap();
+        assert!(db.check_ready().await.is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrations_convert_data(pool: PgPool) {
+        // Everything before money became decimal and days became dates
+        let before_money = Migrator {
+            migrations: MIGRATOR
+                .migrations
+                .iter()
+                .filter(|m| m.version < 10)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        before_money.run(&pool).await.unwrap();
+        let (user_id,): (i32,) = sqlx::query_as(
+            r#"INSERT INTO users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt)
+            VALUES ('worker', 'Worker', FALSE, TRUE, 1000.5, 12.5, '', '') RETURNING id"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"INSERT INTO payouts VALUES (1, 6, 2023, $1, 12345.670000000002::DOUBLE PRECISION)"#,
+        )
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+
+        let db = DatabasePg { conn: pool };
+        let user = db
+            .get_user(&UserSearch::Id(user_id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (user.pay, user.percent),
+            (Decimal::new(10005, 1), Decimal::new(125, 1))
+        );
+        // The float noise is gone
+        let june = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+        let payouts = db
+            .get_payouts(june, june.succ_opt().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(payouts[0].day, june);
+        assert_eq!(payouts[0].amount, Decimal::new(1234567, 2));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrations_quarantine_invalid_days(pool: PgPool) {
+        // Everything before the checks, invalid days could still be saved
+        let before_checks = Migrator {
+            migrations: MIGRATOR
+                .migrations
+                .iter()
+                .filter(|m| m.version < 9)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        before_checks.run(&pool).await.unwrap();
+        let (user_id,): (i32,) = sqlx::query_as(
+            r#"INSERT INTO users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt)
+            VALUES ('worker', 'Worker', FALSE, TRUE, 1000, 10, '', '') RETURNING id"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        for (day, month) in [(1, 2), (31, 2), (1, 13)] {
+            sqlx::query("INSERT INTO schedule VALUES ($1, $2, 2023, $3)")
+                .bind(day)
+                .bind(month)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO revenue VALUES ($1, $2, 2023, 100::DOUBLE PRECISION, 0::DOUBLE PRECISION)")
+                .bind(day)
+                .bind(month)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO payouts VALUES ($1, $2, 2023, $3, 50::DOUBLE PRECISION)")
+                .bind(day)
+                .bind(month)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        MIGRATOR.run(&pool).await.unwrap();
+
+        for table in ["schedule", "revenue", "payouts"] {
+            let mut quarantined: Vec<(i32, i32)> =
+                sqlx::query_as(&format!("SELECT day, month FROM {table}_quarantine"))
+                    .fetch_all(&pool)
+                    .await
+                    .unwrap();
+            quarantined.sort();
+            assert_eq!(quarantined, vec![(1, 13), (31, 2)], "{table}");
+        }
+        let db = DatabasePg { conn: pool };
+        let february = NaiveDate::from_ymd_opt(2023, 2, 1).unwrap();
+        let next_year = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let days = db.get_schedule(february, next_year).await.unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].day, february);
+        let revenue = db.get_revenue(february, next_year).await.unwrap();
+        assert_eq!(revenue.len(), 1);
+        let payouts = db.get_payouts(february, next_year).await.unwrap();
+        assert_eq!(payouts.len(), 1);
     }
 }
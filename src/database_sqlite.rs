@@ -0,0 +1,822 @@
+use crate::config::PoolConfig;
+use crate::database::*;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions, SqliteRow,
+};
+use sqlx::{FromRow, QueryBuilder, Row};
+use std::ops::DerefMut;
+use std::str::FromStr;
+use tokio::sync::{Mutex, MutexGuard};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_sqlite");
+
+/// Runs queries on connections from a pool, or on the one of a transaction
+#[derive(Clone)]
+pub struct DatabaseSqlite<C = SqlitePool> {
+    conn: C,
+}
+
+pub type TransactionSqlite = DatabaseSqlite<Mutex<sqlx::Transaction<'static, Sqlite>>>;
+
+pub type Connection<'a> = Box<dyn DerefMut<Target = SqliteConnection> + Send + 'a>;
+
+/// Where `DatabaseSqlite` gets a connection for the next query
+#[async_trait]
+pub trait Connect: Send + Sync {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>>;
+}
+
+#[async_trait]
+impl Connect for SqlitePool {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>> {
+        Ok(Box::new(self.acquire().await?))
+    }
+}
+
+#[async_trait]
+impl Connect for Mutex<sqlx::Transaction<'static, Sqlite>> {
+    async fn connection<'a>(&'a self) -> DbResult<Connection<'a>> {
+        Ok(Box::new(MutexGuard::map(self.lock().await, |tx| &mut **tx)))
+    }
+}
+
+impl DatabaseSqlite {
+    pub async fn connect(database_url: impl AsRef<str>, pool: &PoolConfig) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(database_url.as_ref())?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        let db = SqlitePoolOptions::new()
+            .max_connections(pool.max_connections)
+            .min_connections(pool.min_connections)
+            .acquire_timeout(pool.acquire_timeout())
+            .idle_timeout(pool.idle_timeout())
+            .connect_with(options)
+            .await?;
+        MIGRATOR.run(&db).await?;
+        Ok(Self { conn: db })
+    }
+}
+
+/// Money is stored as decimal text, SQLite would turn a numeric column into floats.
+/// Tables with money columns are read with explicit column lists, the migration that
+/// made them text moved the columns, and `*` would be stale on connections opened before it.
+fn decimal(row: &SqliteRow, column: &str) -> sqlx::Result<Decimal> {
+    let text: String = row.try_get(column)?;
+    // Values converted from floats by the migration can be in scientific notation
+    Decimal::from_str(&text)
+        .or_else(|_| Decimal::from_scientific(&text))
+        .map_err(|e| sqlx::Error::ColumnDecode {
+            index: column.to_string(),
+            source: Box::new(e),
+        })
+}
+
+impl FromRow<'_, SqliteRow> for UserData {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            login: row.try_get("login")?,
+            name: row.try_get("name")?,
+            is_admin: row.try_get("is_admin")?,
+            is_worker: row.try_get("is_worker")?,
+            pay: decimal(row, "pay")?,
+            percent: decimal(row, "percent")?,
+            pwd_hash: row.try_get("pwd_hash")?,
+            pwd_salt: row.try_get("pwd_salt")?,
+            is_active: row.try_get("is_active")?,
+            deactivated_at: row.try_get("deactivated_at")?,
+        })
+    }
+}
+
+impl FromRow<'_, SqliteRow> for RevenueData {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        Ok(Self {
+            day: row.try_get("day")?,
+            with_percent: decimal(row, "with_percent")?,
+            without_percent: decimal(row, "without_percent")?,
+        })
+    }
+}
+
+impl FromRow<'_, SqliteRow> for PayoutData {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        Ok(Self {
+            day: row.try_get("day")?,
+            user_id: row.try_get("user_id")?,
+            amount: decimal(row, "amount")?,
+        })
+    }
+}
+
+impl FromRow<'_, SqliteRow> for PayRateData {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        Ok(Self {
+            user_id: row.try_get("user_id")?,
+            valid_from: row.try_get("valid_from")?,
+            pay: decimal(row, "pay")?,
+            percent: decimal(row, "percent")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Database for DatabaseSqlite {
+    type Transaction = TransactionSqlite;
+
+    // Health
+    async fn check_ready(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await?;
+        let applied = conn.list_applied_migrations().await?;
+        check_migrations(&MIGRATOR, &applied)
+    }
+
+    // Transactions
+    async fn begin(&self) -> DbResult<TransactionSqlite> {
+        // SQLite runs one writer at a time, transactions are serializable already
+        let tx = self.conn.begin().await?;
+        Ok(DatabaseSqlite {
+            conn: Mutex::new(tx),
+        })
+    }
+}
+
+#[async_trait]
+impl Transaction for TransactionSqlite {
+    async fn commit(self) -> DbResult<()> {
+        self.conn.into_inner().commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(self) -> DbResult<()> {
+        self.conn.into_inner().rollback().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: Connect> Queries for DatabaseSqlite<C> {
+    // Users
+    async fn add_user(&self, user: &UserData) -> DbResult<UserData> {
+        let user = sqlx::query_as(
+            r#"INSERT INTO
+        users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+        is_active, deactivated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+        is_active, deactivated_at"#,
+        )
+        .bind(&user.login)
+        .bind(&user.name)
+        .bind(user.is_admin)
+        .bind(user.is_worker)
+        .bind(user.pay.to_string())
+        .bind(user.percent.to_string())
+        .bind(&user.pwd_hash)
+        .bind(&user.pwd_salt)
+        .bind(user.is_active)
+        .bind(user.deactivated_at)
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(user)
+    }
+
+    async fn get_user(&self, user_search: &UserSearch) -> DbResult<Option<UserData>> {
+        let user = match user_search {
+            UserSearch::Id(id) => sqlx::query_as(
+                r#"SELECT id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+                    is_active, deactivated_at FROM users WHERE id = $1"#,
+            )
+            .bind(id)
+            .fetch_optional(&mut **self.conn.connection().await?)
+            .await?,
+
+            UserSearch::Login(login) => sqlx::query_as(
+                r#"SELECT id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+                    is_active, deactivated_at FROM users WHERE login = $1"#,
+            )
+            .bind(login)
+            .fetch_optional(&mut **self.conn.connection().await?)
+            .await?,
+
+            UserSearch::Token(token_hash) => {
+                sqlx::query_as(
+                    r#"SELECT u.id, u.login, u.name, u.is_admin, u.is_worker, u.pay, u.percent,
+                    u.pwd_hash, u.pwd_salt, u.is_active, u.deactivated_at FROM users u
+                    JOIN sessions s ON s.user_id = u.id
+                    WHERE s.token_hash = $1 AND julianday(s.expires_at) > julianday('now')"#,
+                )
+                .bind(token_hash)
+                .fetch_optional(&mut **self.conn.connection().await?)
+                .await?
+            }
+        };
+        Ok(user)
+    }
+
+    async fn get_users(&self, ids: Option<&[i32]>) -> DbResult<Vec<UserData>> {
+        let users = match ids {
+            None => sqlx::query_as(
+                r#"SELECT id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+                    is_active, deactivated_at FROM users"#,
+            )
+            .fetch_all(&mut **self.conn.connection().await?)
+            .await?,
+            Some(ids) => {
+                let mut query = QueryBuilder::new(
+                    r#"SELECT id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+                    is_active, deactivated_at FROM users WHERE id IN ("#,
+                );
+                let mut separated = query.separated(", ");
+                for id in ids {
+                    separated.push_bind(id);
+                }
+                separated.push_unseparated(")");
+                query
+                    .build_query_as()
+                    .fetch_all(&mut **self.conn.connection().await?)
+                    .await?
+            }
+        };
+        Ok(users)
+    }
+
+    async fn update_user(&self, user: &UserData) -> DbResult<UserData> {
+        let user = sqlx::query_as(
+            r#"UPDATE users
+        SET login = $2, name = $3, is_admin = $4, is_worker = $5, pay = $6,
+        percent = $7, pwd_hash = $8, pwd_salt = $9, is_active = $10, deactivated_at = $11
+        WHERE id = $1 RETURNING id, login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt,
+        is_active, deactivated_at"#,
+        )
+        .bind(user.id)
+        .bind(&user.login)
+        .bind(&user.name)
+        .bind(user.is_admin)
+        .bind(user.is_worker)
+        .bind(user.pay.to_string())
+        .bind(user.percent.to_string())
+        .bind(&user.pwd_hash)
+        .bind(&user.pwd_salt)
+        .bind(user.is_active)
+        .bind(user.deactivated_at)
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(user)
+    }
+
+    // Sessions
+    async fn add_session(&self, session: &SessionData) -> DbResult<()> {
+        sqlx::query(r#"INSERT INTO sessions VALUES ($1, $2, $3, $4, $5, $6)"#)
+            .bind(&session.token_hash)
+            .bind(session.user_id)
+            .bind(session.created_at)
+            .bind(session.last_seen_at)
+            .bind(session.expires_at)
+            .bind(&session.user_agent)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn touch_session(
+        &self,
+        token_hash: &str,
+        last_seen_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        sqlx::query(
+            r#"UPDATE sessions SET last_seen_at = $2, expires_at = $3 WHERE token_hash = $1"#,
+        )
+        .bind(token_hash)
+        .bind(last_seen_at)
+        .bind(expires_at)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sessions(&self, user_id: i32, token_hash: Option<&str>) -> DbResult<()> {
+        sqlx::query(
+            r#"DELETE FROM sessions
+            WHERE user_id = $1 AND ($2 IS NULL OR token_hash = $2)"#,
+        )
+        .bind(user_id)
+        .bind(token_hash)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_expired_sessions(&self, now: DateTime<Utc>) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM sessions WHERE julianday(expires_at) <= julianday($1)"#)
+            .bind(now)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Setup codes
+    async fn set_setup_code(&self, setup_code: &SetupCodeData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO setup_codes VALUES ($1, $2, $3)
+            ON CONFLICT(user_id) DO UPDATE
+            SET code_hash = $2, expires_at = $3"#,
+        )
+        .bind(setup_code.user_id)
+        .bind(&setup_code.code_hash)
+        .bind(setup_code.expires_at)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_setup_code(&self, user_id: i32) -> DbResult<Option<SetupCodeData>> {
+        let setup_code = sqlx::query_as(r#"SELECT * FROM setup_codes WHERE user_id = $1"#)
+            .bind(user_id)
+            .fetch_optional(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(setup_code)
+    }
+
+    async fn delete_setup_code(&self, user_id: i32) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM setup_codes WHERE user_id = $1"#)
+            .bind(user_id)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Lockouts
+    async fn add_lockout(&self, lockout: &LockoutData) -> DbResult<LockoutData> {
+        let lockout = sqlx::query_as(
+            r#"INSERT INTO
+        lockouts(login, address, locked_at, locked_until, cleared_at, cleared_by)
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING *"#,
+        )
+        .bind(&lockout.login)
+        .bind(&lockout.address)
+        .bind(lockout.locked_at)
+        .bind(lockout.locked_until)
+        .bind(lockout.cleared_at)
+        .bind(lockout.cleared_by)
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(lockout)
+    }
+
+    async fn get_lockouts(&self, active_at: Option<DateTime<Utc>>) -> DbResult<Vec<LockoutData>> {
+        let lockouts = sqlx::query_as(
+            r#"SELECT * FROM lockouts
+            WHERE $1 IS NULL
+               OR (julianday(locked_until) > julianday($1) AND cleared_at IS NULL)
+            ORDER BY id"#,
+        )
+        .bind(active_at)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(lockouts)
+    }
+
+    async fn is_locked_out(
+        &self,
+        login: &str,
+        address: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let locked = sqlx::query_scalar(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM lockouts
+                WHERE cleared_at IS NULL
+                  AND julianday(locked_until) > julianday($3)
+                  AND (login = $1 OR address = $2)
+            )"#,
+        )
+        .bind(login)
+        .bind(address)
+        .bind(at)
+        .fetch_one(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(locked)
+    }
+
+    async fn clear_lockout(
+        &self,
+        id: i32,
+        cleared_by: i32,
+        cleared_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        sqlx::query(
+            r#"UPDATE lockouts SET cleared_at = $2, cleared_by = $3
+            WHERE id = $1 AND cleared_at IS NULL"#,
+        )
+        .bind(id)
+        .bind(cleared_at)
+        .bind(cleared_by)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Audit log
+    async fn add_audit(&self, audit: &AuditData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO
+        audit_log(user_id, created_at, kind, value_before, value_after)
+        VALUES ($1, $2, $3, $4, $5)"#,
+        )
+        .bind(audit.user_id)
+        .bind(audit.created_at)
+        .bind(&audit.kind)
+        .bind(&audit.value_before)
+        .bind(&audit.value_after)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, filter: &AuditFilter) -> DbResult<Vec<AuditData>> {
+        let audit_log = sqlx::query_as(
+            r#"SELECT * FROM audit_log
+            WHERE ($1 IS NULL OR user_id = $1)
+              AND ($2 IS NULL OR julianday(created_at) >= julianday($2))
+              AND ($3 IS NULL OR julianday(created_at) < julianday($3))
+              AND ($4 IS NULL OR kind = $4)
+            ORDER BY id DESC
+            LIMIT $6 OFFSET $5"#,
+        )
+        .bind(filter.user_id)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(&filter.kind)
+        .bind(filter.offset)
+        .bind(filter.limit)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(audit_log)
+    }
+
+    // Schedule
+    async fn get_schedule(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<ScheduleData>> {
+        let schedule = sqlx::query_as(
+            r#"SELECT day, user_id FROM schedule
+            WHERE day >= $1 AND day < $2
+            ORDER BY day, user_id"#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(schedule)
+    }
+
+    async fn set_schedule(&self, schedule: &ScheduleData, working: bool) -> DbResult<()> {
+        if working {
+            sqlx::query(
+                r#"INSERT INTO schedule(day, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+            )
+            .bind(schedule.day)
+            .bind(schedule.user_id)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        } else {
+            sqlx::query(r#"DELETE FROM schedule WHERE day = $1 AND user_id = $2"#)
+                .bind(schedule.day)
+                .bind(schedule.user_id)
+                .execute(&mut **self.conn.connection().await?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Revenue
+    async fn get_revenue(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<RevenueData>> {
+        let revenue = sqlx::query_as(
+            r#"SELECT day, with_percent, without_percent FROM revenue
+            WHERE day >= $1 AND day < $2
+            ORDER BY day"#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(revenue)
+    }
+
+    async fn set_revenue(&self, revenue: &RevenueData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO revenue(day, with_percent, without_percent) VALUES ($1, $2, $3)
+            ON CONFLICT(day) DO UPDATE
+            SET with_percent = $2, without_percent = $3"#,
+        )
+        .bind(revenue.day)
+        .bind(revenue.with_percent.to_string())
+        .bind(revenue.without_percent.to_string())
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    // Payouts
+    async fn get_payouts(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<PayoutData>> {
+        let payouts = sqlx::query_as(
+            r#"SELECT day, user_id, amount FROM payouts
+            WHERE day >= $1 AND day < $2
+            ORDER BY day, user_id"#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(payouts)
+    }
+
+    async fn add_payout(&self, payout: &PayoutData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO payouts(day, user_id, amount) VALUES ($1, $2, $3)
+            ON CONFLICT(day, user_id) DO UPDATE
+            SET amount = $3"#,
+        )
+        .bind(payout.day)
+        .bind(payout.user_id)
+        .bind(payout.amount.to_string())
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_payout(&self, user_id: i32, day: NaiveDate) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM payouts WHERE day = $1 AND user_id = $2"#)
+            .bind(day)
+            .bind(user_id)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Commission rules
+    async fn get_commission_rules(
+        &self,
+        user_id: Option<i32>,
+    ) -> DbResult<Vec<CommissionRuleData>> {
+        let rules = sqlx::query_as(
+            r#"SELECT * FROM commission_rules
+            WHERE $1 IS NULL OR user_id = $1
+            ORDER BY user_id, valid_from"#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(rules)
+    }
+
+    async fn set_commission_rule(&self, rule: &CommissionRuleData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO commission_rules VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, valid_from) DO UPDATE SET rule = $3"#,
+        )
+        .bind(rule.user_id)
+        .bind(rule.valid_from)
+        .bind(&rule.rule)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_commission_rule(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM commission_rules WHERE user_id = $1 AND valid_from = $2"#)
+            .bind(user_id)
+            .bind(valid_from)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Holidays
+    async fn get_holidays(&self, from: NaiveDate, to: NaiveDate) -> DbResult<Vec<HolidayData>> {
+        let holidays =
+            sqlx::query_as(r#"SELECT * FROM holidays WHERE day >= $1 AND day < $2 ORDER BY day"#)
+                .bind(from)
+                .bind(to)
+                .fetch_all(&mut **self.conn.connection().await?)
+                .await?;
+        Ok(holidays)
+    }
+
+    async fn set_holiday(&self, holiday: &HolidayData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO holidays VALUES ($1, $2)
+            ON CONFLICT (day) DO UPDATE SET name = $2"#,
+        )
+        .bind(holiday.day)
+        .bind(&holiday.name)
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_holiday(&self, day: NaiveDate) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM holidays WHERE day = $1"#)
+            .bind(day)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Pay rates
+    async fn get_pay_rates(&self, user_id: Option<i32>) -> DbResult<Vec<PayRateData>> {
+        let rates = sqlx::query_as(
+            r#"SELECT user_id, valid_from, pay, percent FROM pay_rates
+            WHERE $1 IS NULL OR user_id = $1
+            ORDER BY user_id, valid_from"#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(rates)
+    }
+
+    async fn set_pay_rate(&self, rate: &PayRateData) -> DbResult<()> {
+        sqlx::query(
+            r#"INSERT INTO pay_rates(user_id, valid_from, pay, percent)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, valid_from) DO UPDATE SET pay = $3, percent = $4"#,
+        )
+        .bind(rate.user_id)
+        .bind(rate.valid_from)
+        .bind(rate.pay.to_string())
+        .bind(rate.percent.to_string())
+        .execute(&mut **self.conn.connection().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_pay_rate(&self, user_id: i32, valid_from: NaiveDate) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM pay_rates WHERE user_id = $1 AND valid_from = $2"#)
+            .bind(user_id)
+            .bind(valid_from)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    // Closed months
+    async fn get_closed_months(&self, year: u16) -> DbResult<Vec<ClosedMonthData>> {
+        let months =
+            sqlx::query_as(r#"SELECT * FROM closed_months WHERE year = $1 ORDER BY month"#)
+                .bind(year as i32)
+                .fetch_all(&mut **self.conn.connection().await?)
+                .await?;
+        Ok(months)
+    }
+
+    async fn close_month(&self, closed: &ClosedMonthData) -> DbResult<()> {
+        sqlx::query(r#"INSERT INTO closed_months VALUES ($1, $2, $3, $4, $5)"#)
+            .bind(closed.year)
+            .bind(closed.month)
+            .bind(closed.closed_at)
+            .bind(closed.closed_by)
+            .bind(&closed.snapshot)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn reopen_month(&self, month: u8, year: u16) -> DbResult<()> {
+        sqlx::query(r#"DELETE FROM closed_months WHERE month = $1 AND year = $2"#)
+            .bind(month as i32)
+            .bind(year as i32)
+            .execute(&mut **self.conn.connection().await?)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations_sqlite")]
+    async fn test_conformance(pool: SqlitePool) {
+        crate::database::tests::conformance(&DatabaseSqlite { conn: pool }).await;
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrations_convert_data(pool: SqlitePool) {
+        // Everything before money became decimal and days became dates
+        let before_money = Migrator {
+            migrations: MIGRATOR
+                .migrations
+                .iter()
+                .filter(|m| m.version < 10)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        before_money.run(&pool).await.unwrap();
+        let (user_id,): (i32,) = sqlx::query_as(
+            r#"INSERT INTO users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt)
+            VALUES ('worker', 'Worker', FALSE, TRUE, 1000.5, 12.5, '', '') RETURNING id"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query(r#"INSERT INTO payouts VALUES (1, 6, 2023, $1, 12345.670000000002)"#)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+
+        let db = DatabaseSqlite { conn: pool };
+        let user = db
+            .get_user(&UserSearch::Id(user_id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (user.pay, user.percent),
+            (Decimal::new(10005, 1), Decimal::new(125, 1))
+        );
+        // The float noise is gone
+        let june = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+        let payouts = db
+            .get_payouts(june, june.succ_opt().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(payouts[0].day, june);
+        assert_eq!(payouts[0].amount, Decimal::new(1234567, 2));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrations_quarantine_invalid_days(pool: SqlitePool) {
+        // Everything before the checks, invalid days could still be saved
+        let before_checks = Migrator {
+            migrations: MIGRATOR
+                .migrations
+                .iter()
+                .filter(|m| m.version < 9)
+                .cloned()
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        before_checks.run(&pool).await.unwrap();
+        let (user_id,): (i32,) = sqlx::query_as(
+            r#"INSERT INTO users(login, name, is_admin, is_worker, pay, percent, pwd_hash, pwd_salt)
+            VALUES ('worker', 'Worker', FALSE, TRUE, 1000, 10, '', '') RETURNING id"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        for (day, month) in [(1, 2), (31, 2), (1, 13)] {
+            sqlx::query("INSERT INTO schedule VALUES ($1, $2, 2023, $3)")
+                .bind(day)
+                .bind(month)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO revenue VALUES ($1, $2, 2023, 100, 0)")
+                .bind(day)
+                .bind(month)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO payouts VALUES ($1, $2, 2023, $3, 50)")
+                .bind(day)
+                .bind(month)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        MIGRATOR.run(&pool).await.unwrap();
+
+        for table in ["schedule", "revenue", "payouts"] {
+            let mut quarantined: Vec<(i32, i32)> =
+                sqlx::query_as(&format!("SELECT day, month FROM {table}_quarantine"))
+                    .fetch_all(&pool)
+                    .await
+                    .unwrap();
+            quarantined.sort();
+            assert_eq!(quarantined, vec![(1, 13), (31, 2)], "{table}");
+        }
+        let db = DatabaseSqlite { conn: pool };
+        let february = NaiveDate::from_ymd_opt(2023, 2, 1).unwrap();
+        let next_year = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let days = db.get_schedule(february, next_year).await.unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].day, february);
+        let revenue = db.get_revenue(february, next_year).await.unwrap();
+        assert_eq!(revenue.len(), 1);
+        let payouts = db.get_payouts(february, next_year).await.unwrap();
+        assert_eq!(payouts.len(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+use chrono::{Datelike, NaiveDate};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use rust_xlsxwriter::{ExcelDateTime, Format as CellFormat, Workbook};
+use serde::Deserialize;
+
+/// Lets Excel know a CSV file is UTF-8, names are in Cyrillic
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Xlsx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dataset {
+    Schedule,
+    Revenue,
+    Payouts,
+    Salaries,
+}
+
+impl Dataset {
+    /// The sheets of a workbook, in order
+    pub const ALL: [Dataset; 4] = [
+        Dataset::Schedule,
+        Dataset::Revenue,
+        Dataset::Payouts,
+        Dataset::Salaries,
+    ];
+
+    /// Used in file names
+    pub fn name(self) -> &'static str {
+        match self {
+            Dataset::Schedule => "schedule",
+            Dataset::Revenue => "revenue",
+            Dataset::Payouts => "payouts",
+            Dataset::Salaries => "salaries",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Dataset::Schedule => "График",
+            Dataset::Revenue => "Выручка",
+            Dataset::Payouts => "Выплаты",
+            Dataset::Salaries => "Зарплата",
+        }
+    }
+}
+
+/// What an admin asks to export, dates are `YYYY-MM-DD` and both included.
+/// A CSV file holds one dataset, a workbook holds them all.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportRequest {
+    pub from: String,
+    pub to: String,
+    pub format: Format,
+    pub dataset: Option<Dataset>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Text(String),
+    Number(i64),
+    Money(Decimal),
+    Date(NaiveDate),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sheet {
+    pub dataset: Dataset,
+    pub header: Vec<&'static str>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+    pub name: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+pub fn csv(sheet: &Sheet) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(UTF8_BOM.to_vec());
+    writer.write_record(&sheet.header)?;
+    for row in &sheet.rows {
+        writer.write_record(row.iter().map(|cell| match cell {
+            Cell::Text(text) => text.clone(),
+            Cell::Number(number) => number.to_string(),
+            Cell::Money(amount) => amount.to_string(),
+            Cell::Date(day) => day.format("%Y-%m-%d").to_string(),
+        }))?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// One worksheet per sheet, with a bold header row that stays in view
+pub fn xlsx(sheets: &[Sheet]) -> anyhow::Result<Vec<u8>> {
+    let bold = CellFormat::new().set_bold();
+    let date = CellFormat::new().set_num_format("dd.mm.yyyy");
+    let money = CellFormat::new().set_num_format("0.00");
+
+    let mut workbook = Workbook::new();
+    for sheet in sheets {
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name(sheet.dataset.title())?;
+        for (col, title) in (0..).zip(&sheet.header) {
+            worksheet.write_string_with_format(0, col, *title, &bold)?;
+        }
+        for (row, cells) in (1..).zip(&sheet.rows) {
+            for (col, cell) in (0..).zip(cells) {
+                match cell {
+                    Cell::Text(text) => worksheet.write_string(row, col, text)?,
+                    Cell::Number(number) => worksheet.write_number(row, col, *number as f64)?,
+                    Cell::Money(amount) => worksheet.write_number_with_format(
+                        row,
+                        col,
+                        amount.to_f64().unwrap_or_default(),
+                        &money,
+                    )?,
+                    Cell::Date(day) => {
+                        let day = ExcelDateTime::from_ymd(
+                            day.year() as u16,
+                            day.month() as u8,
+                            day.day() as u8,
+                        )?;
+                        worksheet.write_date_with_format(row, col, day, &date)?
+                    }
+                };
+            }
+        }
+        worksheet.set_freeze_panes(1, 0)?;
+        worksheet.autofit();
+    }
+    Ok(workbook.save_to_buffer()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn revenue() -> Sheet {
+        Sheet {
+            dataset: Dataset::Revenue,
+            header: vec!["Дата", "С процентом", "Без процента"],
+            rows: vec![
+                vec![
+                    Cell::Date(NaiveDate::from_ymd_opt(2023, 6, 1).unwrap()),
+                    Cell::Money(dec!(1000.10)),
+                    Cell::Money(dec!(5000)),
+                ],
+                vec![
+                    Cell::Text("Итого, \"всё\"".to_string()),
+                    Cell::Number(2),
+                    Cell::Money(dec!(0.05)),
+                ],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_csv() {
+        let data = csv(&revenue()).unwrap();
+        assert!(data.starts_with(UTF8_BOM));
+        let text = String::from_utf8(data[UTF8_BOM.len()..].to_vec()).unwrap();
+        assert_eq!(
+            text,
+            "Дата,С процентом,Без процента\n\
+             2023-06-01,1000.10,5000\n\
+             \"Итого, \"\"всё\"\"\",2,0.05\n"
+        );
+    }
+
+    #[test]
+    fn test_xlsx() {
+        let schedule = Sheet {
+            dataset: Dataset::Schedule,
+            header: vec!["Дата", "Сотрудник"],
+            rows: Vec::new(),
+        };
+        let data = xlsx(&[schedule, revenue()]).unwrap();
+        // A workbook is a zip archive
+        assert!(data.starts_with(b"PK"));
+    }
+}
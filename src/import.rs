@@ -0,0 +1,350 @@
+use crate::database::{PayoutData, RevenueData, ScheduleData};
+use crate::validation::{self, invalid};
+use chrono::NaiveDate;
+use pravda_protocol::{ImportAction, ProtocolError};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dataset {
+    Schedule,
+    Revenue,
+    Payouts,
+}
+
+impl Dataset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Dataset::Schedule => "schedule",
+            Dataset::Revenue => "revenue",
+            Dataset::Payouts => "payouts",
+        }
+    }
+
+    /// The header has to name these, in any order
+    fn columns(self) -> &'static [&'static str] {
+        match self {
+            Dataset::Schedule => &["date", "login"],
+            Dataset::Revenue => &["date", "with_percent", "without_percent"],
+            Dataset::Payouts => &["date", "login", "amount"],
+        }
+    }
+}
+
+/// What an admin asks to import, the CSV file comes as the request body.
+/// A dry run only shows what would change.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportRequest {
+    pub dataset: Dataset,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Row {
+    Workday(ScheduleData),
+    Revenue(RevenueData),
+    Payout(PayoutData),
+}
+
+impl Row {
+    pub fn day(&self) -> NaiveDate {
+        match self {
+            Row::Workday(s) => s.day,
+            Row::Revenue(r) => r.day,
+            Row::Payout(p) => p.day,
+        }
+    }
+
+    /// Two rows with the same key write the same record
+    fn key(&self) -> (NaiveDate, Option<i32>) {
+        match self {
+            Row::Workday(s) => (s.day, Some(s.user_id)),
+            Row::Revenue(r) => (r.day, None),
+            Row::Payout(p) => (p.day, Some(p.user_id)),
+        }
+    }
+
+    /// Amounts are compared as numbers, `100.00` is the same as `100`
+    fn same_values(&self, other: &Row) -> bool {
+        match (self, other) {
+            (Row::Workday(a), Row::Workday(b)) => (a.day, a.user_id) == (b.day, b.user_id),
+            (Row::Revenue(a), Row::Revenue(b)) => {
+                (a.day, a.with_percent, a.without_percent)
+                    == (b.day, b.with_percent, b.without_percent)
+            }
+            (Row::Payout(a), Row::Payout(b)) => {
+                (a.day, a.user_id, a.amount) == (b.day, b.user_id, b.amount)
+            }
+            _ => false,
+        }
+    }
+
+    /// Like the audit log shows values
+    pub fn json(&self) -> Value {
+        match self {
+            Row::Workday(s) => json!({"date": s.day.to_string(), "user_id": s.user_id}),
+            Row::Revenue(r) => json!({
+                "date": r.day.to_string(),
+                "with_percent": r.with_percent,
+                "without_percent": r.without_percent,
+            }),
+            Row::Payout(p) => json!({
+                "date": p.day.to_string(),
+                "user_id": p.user_id,
+                "amount": p.amount,
+            }),
+        }
+    }
+}
+
+/// Rows of the three datasets, as stored or as imported
+#[derive(Clone, Debug, Default)]
+pub struct ImportData {
+    pub schedule: Vec<ScheduleData>,
+    pub revenue: Vec<RevenueData>,
+    pub payouts: Vec<PayoutData>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    /// Line of the file, the header is line 1
+    pub number: u64,
+    pub row: Row,
+}
+
+/// Reads a CSV file with a header and checks every line: dates are
+/// `YYYY-MM-DD`, logins belong to users and amounts aren't negative.
+/// Errors name the field as `lines[N].column`.
+pub fn parse(
+    dataset: Dataset,
+    data: &[u8],
+    logins: &HashMap<String, i32>,
+) -> Result<Vec<Line>, ProtocolError> {
+    let unreadable = |e: csv::Error| invalid("file", &format!("Не удалось прочитать файл: {}", e));
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data);
+    let header = reader.headers().map_err(unreadable)?.clone();
+    let mut columns = HashMap::new();
+    for &column in dataset.columns() {
+        match header.iter().position(|h| h == column) {
+            Some(i) => columns.insert(column, i),
+            None => return Err(invalid("file", &format!("Нет столбца {}", column))),
+        };
+    }
+
+    let mut lines = Vec::new();
+    let mut keys = HashSet::new();
+    for record in reader.records() {
+        let record = record.map_err(unreadable)?;
+        let number = record.position().map_or(0, |p| p.line());
+        let field = |column: &str| format!("lines[{}].{}", number, column);
+        let value = |column: &str| record.get(columns[column]).unwrap_or_default();
+        let user_id = || {
+            logins
+                .get(value("login"))
+                .copied()
+                .ok_or_else(|| invalid(&field("login"), "Нет такого пользователя"))
+        };
+        let amount = |column: &str| validation::amount(&field(column), value(column));
+
+        let day = validation::iso_date(&field("date"), value("date"))?;
+        let row = match dataset {
+            Dataset::Schedule => Row::Workday(ScheduleData {
+                day,
+                user_id: user_id()?,
+            }),
+            Dataset::Revenue => Row::Revenue(RevenueData {
+                day,
+                with_percent: amount("with_percent")?,
+                without_percent: amount("without_percent")?,
+            }),
+            Dataset::Payouts => Row::Payout(PayoutData {
+                day,
+                user_id: user_id()?,
+                amount: amount("amount")?,
+            }),
+        };
+        if !keys.insert(row.key()) {
+            return Err(invalid(&field("date"), "Такая строка уже есть выше"));
+        }
+        lines.push(Line { number, row });
+    }
+    if lines.is_empty() {
+        return Err(invalid("file", "В файле нет строк"));
+    }
+    Ok(lines)
+}
+
+pub struct Change<'a> {
+    pub line: &'a Line,
+    pub action: ImportAction,
+    pub before: Option<Row>,
+}
+
+/// What each line does to the `stored` rows of its days
+pub fn preview<'a>(lines: &'a [Line], stored: &ImportData) -> Vec<Change<'a>> {
+    let stored = stored
+        .schedule
+        .iter()
+        .cloned()
+        .map(Row::Workday)
+        .chain(stored.revenue.iter().cloned().map(Row::Revenue))
+        .chain(stored.payouts.iter().cloned().map(Row::Payout))
+        .map(|row| ((kind(&row), row.key()), row))
+        .collect::<HashMap<_, _>>();
+    lines
+        .iter()
+        .map(|line| {
+            let before = stored.get(&(kind(&line.row), line.row.key())).cloned();
+            let action = match &before {
+                None => ImportAction::Add,
+                Some(before) if before.same_values(&line.row) => ImportAction::Keep,
+                Some(_) => ImportAction::Update,
+            };
+            Change {
+                line,
+                action,
+                before,
+            }
+        })
+        .collect()
+}
+
+fn kind(row: &Row) -> Dataset {
+    match row {
+        Row::Workday(_) => Dataset::Schedule,
+        Row::Revenue(_) => Dataset::Revenue,
+        Row::Payout(_) => Dataset::Payouts,
+    }
+}
+
+/// The rows to write, in the order of the file
+pub fn data(lines: &[Line]) -> ImportData {
+    let mut data = ImportData::default();
+    for line in lines {
+        match &line.row {
+            Row::Workday(s) => data.schedule.push(s.clone()),
+            Row::Revenue(r) => data.revenue.push(r.clone()),
+            Row::Payout(p) => data.payouts.push(p.clone()),
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn logins() -> HashMap<String, i32> {
+        HashMap::from([("anna".to_string(), 1), ("boris".to_string(), 2)])
+    }
+
+    fn field(result: Result<Vec<Line>, ProtocolError>) -> String {
+        match result {
+            Err(ProtocolError::Validation { field, .. }) => field,
+            _ => panic!("Expected a validation error"),
+        }
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2021, 3, day).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = "\u{feff}without_percent,date,with_percent,note\n\
+                    100, 2021-03-01 ,1000.50,\n\
+                    0,2021-03-02,0,closed early\n";
+        let lines = parse(Dataset::Revenue, file.as_bytes(), &logins()).unwrap();
+        assert_eq!(lines.iter().map(|l| l.number).collect::<Vec<_>>(), [2, 3]);
+        match &lines[0].row {
+            Row::Revenue(r) => {
+                assert_eq!(r.day, date(1));
+                assert_eq!(r.with_percent, dec!(1000.50));
+                assert_eq!(r.without_percent, dec!(100));
+            }
+            _ => panic!("Expected revenue"),
+        }
+
+        let file = "date,login,amount\n2021-03-01,boris,500\n2021-03-01,anna,250\n";
+        let data = data(&parse(Dataset::Payouts, file.as_bytes(), &logins()).unwrap());
+        let payouts = data.payouts.iter().map(|p| (p.user_id, p.amount));
+        assert_eq!(
+            payouts.collect::<Vec<_>>(),
+            [(2, dec!(500)), (1, dec!(250))]
+        );
+        assert!(data.schedule.is_empty() && data.revenue.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let schedule = |file: &str| parse(Dataset::Schedule, file.as_bytes(), &logins());
+        assert_eq!(field(schedule("date,name\n2021-03-01,anna\n")), "file");
+        assert_eq!(field(schedule("date,login\n")), "file");
+        assert_eq!(field(schedule("date,login\n2021-03-01\n")), "file");
+        assert_eq!(
+            field(schedule("date,login\n2021-03-01,anna\n2021-02-30,anna\n")),
+            "lines[3].date"
+        );
+        assert_eq!(
+            field(schedule("date,login\n2021-03-01,anna\n2021-03-01,gleb\n")),
+            "lines[3].login"
+        );
+        assert_eq!(
+            field(schedule("date,login\n2021-03-01,anna\n2021-03-01,anna\n")),
+            "lines[3].date"
+        );
+        let revenue = |file: &str| parse(Dataset::Revenue, file.as_bytes(), &logins());
+        assert_eq!(
+            field(revenue(
+                "date,with_percent,without_percent\n2021-03-01,-5,0\n"
+            )),
+            "lines[2].with_percent"
+        );
+        assert_eq!(
+            field(revenue(
+                "date,with_percent,without_percent\n2021-03-01,5,abc\n"
+            )),
+            "lines[2].without_percent"
+        );
+    }
+
+    #[test]
+    fn test_preview() {
+        let file = "date,with_percent,without_percent\n\
+                    2021-03-01,100,0\n\
+                    2021-03-02,200,0\n\
+                    2021-03-03,300,0\n";
+        let lines = parse(Dataset::Revenue, file.as_bytes(), &logins()).unwrap();
+        let revenue = |day, with_percent: Decimal| RevenueData {
+            day: date(day),
+            with_percent,
+            without_percent: Decimal::ZERO,
+        };
+        let stored = ImportData {
+            // Equal amounts with a different scale are the same
+            revenue: vec![revenue(1, dec!(100.00)), revenue(2, dec!(150))],
+            // Other datasets on the same days don't matter
+            payouts: vec![PayoutData {
+                day: date(3),
+                user_id: 1,
+                amount: dec!(300),
+            }],
+            ..Default::default()
+        };
+        let changes = preview(&lines, &stored);
+        let actions = changes.iter().map(|c| c.action).collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [ImportAction::Keep, ImportAction::Update, ImportAction::Add]
+        );
+        assert!(changes[1].before.is_some() && changes[2].before.is_none());
+    }
+}
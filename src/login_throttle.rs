@@ -0,0 +1,176 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct ThrottleConfig {
+    /// Failures allowed before every further attempt has to wait
+    pub backoff_after: u32,
+    /// Wait after the first failure past `backoff_after`, doubled on every next one
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Failures that lock the login or address out completely
+    pub lockout_after: u32,
+    pub lockout_duration: Duration,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ThrottleKey {
+    Login(String),
+    Address(IpAddr),
+}
+
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+/// Counts failed logins per login and per client address
+#[derive(Clone)]
+pub struct LoginThrottle {
+    config: ThrottleConfig,
+    failures: Arc<Mutex<HashMap<ThrottleKey, Failures>>>,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            backoff_after: 3,
+            backoff_base: Duration::seconds(1),
+            backoff_max: Duration::minutes(5),
+            lockout_after: 10,
+            lockout_duration: Duration::minutes(30),
+        }
+    }
+}
+
+impl LoginThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            failures: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.config
+    }
+
+    /// Whether an attempt for any of `keys` comes before its backoff has passed
+    pub fn is_throttled(&self, keys: &[ThrottleKey], now: DateTime<Utc>) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        self.forget_stale(&mut failures, now);
+        keys.iter().any(|key| match failures.get(key) {
+            Some(f) => now < f.last + self.backoff(f.count),
+            None => false,
+        })
+    }
+
+    /// Counts a failed attempt, returns the keys that have to be locked out now
+    pub fn add_failure(&self, keys: &[ThrottleKey], now: DateTime<Utc>) -> Vec<ThrottleKey> {
+        let mut failures = self.failures.lock().unwrap();
+        let mut locked = Vec::new();
+        for key in keys {
+            let f = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            f.count += 1;
+            f.last = now;
+            if f.count >= self.config.lockout_after {
+                failures.remove(key);
+                locked.push(key.clone());
+            }
+        }
+        locked
+    }
+
+    pub fn reset(&self, key: &ThrottleKey) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    fn backoff(&self, count: u32) -> Duration {
+        if count < self.config.backoff_after {
+            return Duration::zero();
+        }
+        let doublings = (count - self.config.backoff_after).min(30);
+        (self.config.backoff_base * 2i32.pow(doublings)).min(self.config.backoff_max)
+    }
+
+    fn forget_stale(&self, failures: &mut HashMap<ThrottleKey, Failures>, now: DateTime<Utc>) {
+        let window = self.config.lockout_duration.max(self.config.backoff_max);
+        failures.retain(|_, f| now < f.last + window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(ThrottleConfig {
+            backoff_after: 2,
+            backoff_base: Duration::seconds(10),
+            backoff_max: Duration::seconds(25),
+            lockout_after: 5,
+            lockout_duration: Duration::minutes(30),
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let throttle = throttle();
+        let keys = [ThrottleKey::Login("worker".to_string())];
+        let now = Utc::now();
+
+        throttle.add_failure(&keys, now);
+        assert!(!throttle.is_throttled(&keys, now));
+        throttle.add_failure(&keys, now);
+        assert!(throttle.is_throttled(&keys, now + Duration::seconds(9)));
+        assert!(!throttle.is_throttled(&keys, now + Duration::seconds(10)));
+
+        throttle.add_failure(&keys, now);
+        assert!(throttle.is_throttled(&keys, now + Duration::seconds(19)));
+        assert!(!throttle.is_throttled(&keys, now + Duration::seconds(20)));
+
+        throttle.add_failure(&keys, now);
+        assert!(throttle.is_throttled(&keys, now + Duration::seconds(24)));
+        assert!(!throttle.is_throttled(&keys, now + Duration::seconds(25)));
+
+        throttle.reset(&keys[0]);
+        assert!(!throttle.is_throttled(&keys, now));
+    }
+
+    #[test]
+    fn test_lockout() {
+        let throttle = throttle();
+        let login = ThrottleKey::Login("worker".to_string());
+        let address = ThrottleKey::Address("10.0.0.1".parse().unwrap());
+        let now = Utc::now();
+
+        let both = [login, address.clone()];
+        for _ in 0..3 {
+            assert!(throttle.add_failure(&both[1..], now).is_empty());
+        }
+        assert!(throttle.add_failure(&both, now).is_empty());
+        let locked = throttle.add_failure(&both, now);
+        assert!(locked == vec![address]);
+        assert!(!throttle.is_throttled(&both[1..], now));
+        assert!(throttle.is_throttled(&both[..1], now));
+    }
+
+    #[test]
+    fn test_stale_failures_forgotten() {
+        let throttle = throttle();
+        let keys = [ThrottleKey::Login("worker".to_string())];
+        let now = Utc::now();
+
+        for _ in 0..4 {
+            throttle.add_failure(&keys, now);
+        }
+        let later = now + Duration::minutes(31);
+        assert!(!throttle.is_throttled(&keys, later));
+        assert!(throttle.add_failure(&keys, later).is_empty());
+    }
+}
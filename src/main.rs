@@ -1,57 +1,260 @@
+mod config;
 mod database;
+mod database_memory;
 mod database_pg;
+#[cfg(feature = "sqlite")]
+mod database_sqlite;
+mod export;
+mod import;
+mod login_throttle;
+mod payroll;
 mod pravda_handler;
+mod report;
+mod schedule_policy;
 mod utils;
+mod validation;
 
+use crate::config::{Config, LogFormat};
+use crate::database::Database;
+use crate::database_memory::DatabaseMemory;
 use crate::database_pg::DatabasePg;
-use crate::pravda_handler::PravdaHandler;
-use axum::extract::State;
+#[cfg(feature = "sqlite")]
+use crate::database_sqlite::DatabaseSqlite;
+use crate::export::ExportRequest;
+use crate::import::ImportRequest;
+use crate::pravda_handler::{Client, PravdaHandler};
+use anyhow::Context;
+use axum::extract::{ConnectInfo, Query, State};
 use axum::{
-    http::{header::HeaderMap, StatusCode},
-    routing::post,
-    Json, Router,
+    body::Bytes,
+    http::{
+        header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_TYPE, USER_AGENT},
+        StatusCode,
+    },
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 use pravda_protocol::{ProtocolError, Request, Response};
-use std::env;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::services::{ServeDir, ServeFile};
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+    let config = Config::load()?;
+
     // initialize tracing
-    tracing_subscriber::fmt::init();
-    dotenvy::dotenv()?;
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log.level);
+    match config.log.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    let database_url = &config.database_url;
+    match database_url.split(':').next() {
+        Some("postgres" | "postgresql") => {
+            let database = DatabasePg::connect(database_url, &config.pool)
+                .await
+                .context("Failed to connect to Postgres")?;
+            serve(database, &config).await
+        }
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") => {
+            let database = DatabaseSqlite::connect(database_url, &config.pool)
+                .await
+                .context("Failed to open the SQLite database")?;
+            serve(database, &config).await
+        }
+        Some("memory") => {
+            warn!("Using the in-memory database, everything is lost when the server stops");
+            serve(DatabaseMemory::new(), &config).await
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Some("sqlite") => anyhow::bail!("SQLite support requires the `sqlite` feature"),
+        _ => anyhow::bail!("Unsupported DATABASE_URL scheme"),
+    }
+}
 
-    let database = DatabasePg::connect(env::var("DATABASE_URL")?).await?;
-    let handler = PravdaHandler::new(database);
+async fn serve<T>(database: T, config: &Config) -> anyhow::Result<()>
+where
+    T: Database + Clone + Send + Sync + 'static,
+{
+    let handler = PravdaHandler::new(database)
+        .with_login_throttle(config.login.throttle())
+        .with_schedule_policy(config.schedule.policy());
 
-    let dir_server =
-        ServeDir::new("assets").not_found_service(ServeFile::new("assets/not_found.html"));
-    // let index_server = ServeFile::new("assets/index.html");
+    let dir_server = ServeDir::new(&config.assets_dir)
+        .not_found_service(ServeFile::new(config.assets_dir.join("not_found.html")));
 
     // build our application with a route
     let app = Router::new()
-        .route("/api", post(process_request))
+        .route("/api", post(process_request::<T>))
+        .route("/api/export", get(export::<T>))
+        .route("/api/import", post(import::<T>))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(readiness::<T>))
         .fallback_service(dir_server)
-        .with_state(handler);
-
-    // run our app with hyper
-    // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+        .with_state(handler)
+        .layer(Extension(TrustedProxies(
+            config.trusted_proxies.clone().into(),
+        )))
+        .into_make_service_with_connect_info::<SocketAddr>();
+
+    let handle = Handle::new();
+    tokio::spawn(shutdown_on_signal(
+        handle.clone(),
+        config.shutdown_timeout(),
+    ));
+
+    let addr = config.listen;
+    match &config.tls {
+        Some(tls) => {
+            let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .context("Failed to load the TLS certificate and key")?;
+            info!("listening on https://{}", addr);
+            axum_server::bind_rustls(addr, rustls)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+        None => {
+            info!("listening on http://{}", addr);
+            axum_server::bind(addr).handle(handle).serve(app).await
+        }
+    }
+    .with_context(|| format!("Failed to listen on {}", addr))?;
+    info!("Server stopped");
     Ok(())
 }
 
-#[axum::debug_handler]
-async fn process_request(
+/// Stops accepting connections on SIGINT or SIGTERM and gives requests
+/// in flight `drain_timeout` to finish
+async fn shutdown_on_signal(handle: Handle, drain_timeout: Duration) {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    info!(
+        "Shutting down, waiting up to {:?} for requests in flight",
+        drain_timeout
+    );
+    handle.graceful_shutdown(Some(drain_timeout));
+}
+
+async fn readiness<T>(State(handler): State<PravdaHandler<T>>) -> (StatusCode, &'static str)
+where
+    T: Database + Clone + Send + Sync + 'static,
+{
+    match handler.check_ready().await {
+        Ok(_) => (StatusCode::OK, "ready"),
+        Err(e) => {
+            warn!("Not ready: {:?}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+        }
+    }
+}
+
+async fn process_request<T>(
     headers: HeaderMap,
-    State(handler): State<PravdaHandler<DatabasePg>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Extension(proxies): Extension<TrustedProxies>,
+    State(handler): State<PravdaHandler<T>>,
     Json(request): Json<Request>,
-) -> (StatusCode, Json<Response>) {
+) -> (StatusCode, Json<Response>)
+where
+    T: Database + Clone + Send + Sync + 'static,
+{
+    let response = handler
+        .process(request, client(&headers, address, &proxies))
+        .await;
+    (status(&response), Json(response))
+}
+
+/// Sends the file for download, errors come back as JSON like those of `/api`
+async fn export<T>(
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Extension(proxies): Extension<TrustedProxies>,
+    State(handler): State<PravdaHandler<T>>,
+    Query(request): Query<ExportRequest>,
+) -> axum::response::Response
+where
+    T: Database + Clone + Send + Sync + 'static,
+{
+    match handler
+        .export(request, client(&headers, address, &proxies))
+        .await
+    {
+        Ok(file) => {
+            let disposition = format!("attachment; filename=\"{}\"", file.name);
+            (
+                [
+                    (CONTENT_TYPE, file.content_type.to_string()),
+                    (CONTENT_DISPOSITION, disposition),
+                ],
+                file.data,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            let response: Response = Err(e);
+            (status(&response), Json(response)).into_response()
+        }
+    }
+}
+
+/// The CSV file is the body, what it holds goes in the query
+async fn import<T>(
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Extension(proxies): Extension<TrustedProxies>,
+    State(handler): State<PravdaHandler<T>>,
+    Query(request): Query<ImportRequest>,
+    body: Bytes,
+) -> (StatusCode, Json<Response>)
+where
+    T: Database + Clone + Send + Sync + 'static,
+{
+    let response = handler
+        .import(request, &body, client(&headers, address, &proxies))
+        .await;
+    (status(&response), Json(response))
+}
+
+/// Peers allowed to tell the client address in `X-Forwarded-For`
+#[derive(Clone)]
+struct TrustedProxies(Arc<[IpAddr]>);
+
+fn client(headers: &HeaderMap, address: SocketAddr, proxies: &TrustedProxies) -> Client {
     let token = match headers.get("P-Token") {
         None => None,
         Some(token) => match token.to_str() {
@@ -62,17 +265,143 @@ async fn process_request(
             }
         },
     };
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    Client {
+        token,
+        user_agent,
+        address: Some(client_address(headers, address.ip(), &proxies.0)),
+    }
+}
 
-    let response = handler.process(request, token).await;
-    match response {
-        Ok(_) => (StatusCode::OK, Json(response)),
-        Err(ProtocolError::Unknown(_)) => {
-            error!("Unknown error while handling request: {:?}", response);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response))
+/// The peer, or behind trusted proxies the last `X-Forwarded-For` address
+/// none of them added. Earlier ones come from the client and can be forged.
+fn client_address(headers: &HeaderMap, peer: IpAddr, proxies: &[IpAddr]) -> IpAddr {
+    let forwarded = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let mut address = peer;
+    for hop in forwarded.into_iter().rev() {
+        if !proxies.contains(&address) {
+            break;
         }
-        Err(_) => {
-            warn!("User error: {:?}", response);
-            (StatusCode::BAD_REQUEST, Json(response))
+        match hop.parse() {
+            Ok(hop) => address = hop,
+            Err(_) => break,
         }
     }
+    address
+}
+
+fn status(response: &Response) -> StatusCode {
+    let Err(e) = response else {
+        return StatusCode::OK;
+    };
+    // No catch-all, a new error has to be given its status here
+    let status = match e {
+        ProtocolError::Unknown(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ProtocolError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ProtocolError::UnknownToken | ProtocolError::LoginFailed => StatusCode::UNAUTHORIZED,
+        ProtocolError::Forbidden => StatusCode::FORBIDDEN,
+        ProtocolError::NotFound => StatusCode::NOT_FOUND,
+        ProtocolError::Conflict | ProtocolError::UserExist | ProtocolError::MonthClosed { .. } => {
+            StatusCode::CONFLICT
+        }
+        ProtocolError::Validation { .. } => StatusCode::BAD_REQUEST,
+        ProtocolError::ConstraintViolation => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    if status.is_server_error() {
+        error!("Error while handling request: {:?}", response);
+    } else {
+        warn!("User error: {:?}", response);
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pravda_protocol::ResponseData;
+
+    #[test]
+    fn test_status() {
+        let cases = [
+            (Ok(ResponseData::LoggedOut), StatusCode::OK),
+            (
+                Err(ProtocolError::Unknown("".to_string())),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Err(ProtocolError::Unavailable),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (Err(ProtocolError::UnknownToken), StatusCode::UNAUTHORIZED),
+            (Err(ProtocolError::LoginFailed), StatusCode::UNAUTHORIZED),
+            (Err(ProtocolError::Forbidden), StatusCode::FORBIDDEN),
+            (Err(ProtocolError::NotFound), StatusCode::NOT_FOUND),
+            (Err(ProtocolError::Conflict), StatusCode::CONFLICT),
+            (Err(ProtocolError::UserExist), StatusCode::CONFLICT),
+            (
+                Err(ProtocolError::MonthClosed {
+                    year: 2023,
+                    month: 6,
+                }),
+                StatusCode::CONFLICT,
+            ),
+            (
+                Err(ProtocolError::Validation {
+                    field: "day".to_string(),
+                    message: "".to_string(),
+                }),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                Err(ProtocolError::ConstraintViolation),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(status(&response), expected, "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn test_client_address() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append("X-Forwarded-For", "10.0.0.2".parse().unwrap());
+
+        // Only trusted proxies are believed, and only for the hops they added
+        assert_eq!(
+            client_address(&headers, ip("3.3.3.3"), &proxies),
+            ip("3.3.3.3")
+        );
+        assert_eq!(
+            client_address(&headers, ip("10.0.0.1"), &proxies),
+            ip("2.2.2.2")
+        );
+        assert_eq!(
+            client_address(&headers, ip("10.0.0.1"), &[]),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            client_address(&HeaderMap::new(), ip("10.0.0.1"), &proxies),
+            ip("10.0.0.1")
+        );
+        headers.insert("X-Forwarded-For", "garbage".parse().unwrap());
+        assert_eq!(
+            client_address(&headers, ip("10.0.0.1"), &proxies),
+            ip("10.0.0.1")
+        );
+    }
 }
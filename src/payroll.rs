@@ -0,0 +1,1005 @@
+use crate::database::{PayoutData, RevenueData, ScheduleData, UserData};
+use chrono::{Datelike, NaiveDate, Weekday};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Money is paid in kopecks, halves are rounded away from zero.
+///
+/// A worker's share of the day's revenue is rounded, and so are the day's pay,
+/// commission and top-up. Everything else is exact, so the month's totals are
+/// exact sums of what the days show.
+pub fn round_money(amount: Decimal) -> Decimal {
+    amount.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+}
+
+/// How a worker is paid for a day, stored as JSON with an effective date
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommissionRule {
+    pub daily_pay: Decimal,
+    /// Percent of the worker's share of `with_percent`, each tier covers the part
+    /// of the month's share from its `from` up to the next tier
+    pub tiers: Vec<Tier>,
+    /// Percent of the worker's share of `without_percent`
+    pub without_percent: Decimal,
+    /// A working day pays at least this much, multipliers included
+    pub daily_minimum: Decimal,
+    pub weekend_multiplier: Decimal,
+    /// Takes the place of `weekend_multiplier` on holidays
+    pub holiday_multiplier: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tier {
+    pub from: Decimal,
+    pub percent: Decimal,
+}
+
+impl Default for CommissionRule {
+    fn default() -> Self {
+        Self {
+            daily_pay: Decimal::ZERO,
+            tiers: Vec::new(),
+            without_percent: Decimal::ZERO,
+            daily_minimum: Decimal::ZERO,
+            weekend_multiplier: Decimal::ONE,
+            holiday_multiplier: Decimal::ONE,
+        }
+    }
+}
+
+impl CommissionRule {
+    /// The rule of users without one of their own: `pay` a day and `percent` of the share
+    pub fn flat(pay: Decimal, percent: Decimal) -> Self {
+        Self {
+            daily_pay: pay,
+            tiers: vec![Tier {
+                from: Decimal::ZERO,
+                percent,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Commission on the part of the month's share from `before` to `after`
+    fn tiered(&self, before: Decimal, after: Decimal) -> Decimal {
+        let ends = self.tiers.iter().skip(1).map(|t| t.from);
+        self.tiers
+            .iter()
+            .zip(ends.map(Some).chain([None]))
+            .map(|(tier, end)| {
+                let from = before.max(tier.from);
+                let to = end.map_or(after, |end| after.min(end));
+                (to - from).max(Decimal::ZERO) * tier.percent / Decimal::ONE_HUNDRED
+            })
+            .sum()
+    }
+}
+
+/// A rule that applies to a user's days starting with `valid_from`
+#[derive(Debug, Clone)]
+pub struct UserRule {
+    pub user_id: i32,
+    pub valid_from: NaiveDate,
+    pub rule: CommissionRule,
+}
+
+/// `pay` and `percent` of a user starting with `valid_from`
+#[derive(Debug, Clone)]
+pub struct PayRate {
+    pub user_id: i32,
+    pub valid_from: NaiveDate,
+    pub pay: Decimal,
+    pub percent: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    pub pay_rates: Vec<PayRate>,
+    pub rules: Vec<UserRule>,
+    pub holidays: BTreeSet<NaiveDate>,
+}
+
+impl Rules {
+    /// The user's own rule in effect on `date`, or their pay rate as a flat rule
+    fn rule_for(&self, user: &UserData, date: NaiveDate) -> CommissionRule {
+        self.rules
+            .iter()
+            .filter(|r| r.user_id == user.id && r.valid_from <= date)
+            .max_by_key(|r| r.valid_from)
+            .map(|r| r.rule.clone())
+            .unwrap_or_else(|| {
+                let (pay, percent) = self.pay_rate(user, date);
+                CommissionRule::flat(pay, percent)
+            })
+    }
+
+    /// The rate in effect on `date`. Days before the first rate use the first one,
+    /// and users without any rates are paid what their user entry says.
+    fn pay_rate(&self, user: &UserData, date: NaiveDate) -> (Decimal, Decimal) {
+        let rates = self.pay_rates.iter().filter(|r| r.user_id == user.id);
+        let rate = rates
+            .clone()
+            .filter(|r| r.valid_from <= date)
+            .max_by_key(|r| r.valid_from)
+            .or_else(|| rates.min_by_key(|r| r.valid_from));
+        match rate {
+            Some(rate) => (rate.pay, rate.percent),
+            None => (user.pay, user.percent),
+        }
+    }
+
+    fn multiplier(&self, rule: &CommissionRule, date: NaiveDate) -> Decimal {
+        if self.holidays.contains(&date) {
+            rule.holiday_multiplier
+        } else if matches!(date.weekday(), Weekday::Sat | Weekday::Sun) {
+            rule.weekend_multiplier
+        } else {
+            Decimal::ONE
+        }
+    }
+}
+
+/// One worker's salary for a month
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payroll {
+    pub user_id: i32,
+    /// Days worked that have a revenue entry, other days don't count
+    pub working_days: u32,
+    pub base_pay: Decimal,
+    pub commissions: Vec<DayCommission>,
+    pub paid: Decimal,
+    /// Everything earned in the month: base pay, commission and top-ups
+    pub total: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DayCommission {
+    pub day: NaiveDate,
+    /// The day's `with_percent`, shared by `workers`
+    pub revenue: Decimal,
+    pub workers: u32,
+    /// The day's pay, multiplier included
+    pub base_pay: Decimal,
+    /// Weekend or holiday multiplier, applied to both pay and commission
+    pub multiplier: Decimal,
+    pub amount: Decimal,
+    /// Added to reach the daily minimum
+    pub top_up: Decimal,
+}
+
+impl Payroll {
+    pub fn balance(&self) -> Decimal {
+        self.total - self.paid
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            id: self.user_id,
+            days_worked: self.working_days,
+            base_pay: self.base_pay,
+            commission: self
+                .commissions
+                .iter()
+                .map(|c| SnapshotDay {
+                    day: c.day.day(),
+                    revenue: c.revenue,
+                    workers: c.workers,
+                    base_pay: c.base_pay,
+                    multiplier: c.multiplier,
+                    amount: c.amount,
+                    top_up: c.top_up,
+                })
+                .collect(),
+            paid: self.paid,
+            total: self.total,
+        }
+    }
+
+    /// The salary a snapshot of `year`/`month` holds, `None` if it has days
+    /// the month doesn't
+    pub fn from_snapshot(snapshot: Snapshot, year: i32, month: u32) -> Option<Self> {
+        let commissions = snapshot
+            .commission
+            .into_iter()
+            .map(|c| {
+                Some(DayCommission {
+                    day: NaiveDate::from_ymd_opt(year, month, c.day)?,
+                    revenue: c.revenue,
+                    workers: c.workers,
+                    base_pay: c.base_pay,
+                    multiplier: c.multiplier,
+                    amount: c.amount,
+                    top_up: c.top_up,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            user_id: snapshot.id,
+            working_days: snapshot.days_worked,
+            base_pay: snapshot.base_pay,
+            commissions,
+            paid: snapshot.paid,
+            total: snapshot.total,
+        })
+    }
+}
+
+/// A salary as a closed month stores it, amounts are written as decimal strings.
+/// Fields are named like those of the protocol's `Salary`, so snapshots stored
+/// as floats before still read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: i32,
+    pub days_worked: u32,
+    pub base_pay: Decimal,
+    pub commission: Vec<SnapshotDay>,
+    pub paid: Decimal,
+    pub total: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotDay {
+    pub day: u32,
+    pub revenue: Decimal,
+    pub workers: u32,
+    pub base_pay: Decimal,
+    pub multiplier: Decimal,
+    pub amount: Decimal,
+    pub top_up: Decimal,
+}
+
+/// Salaries for one month, ordered by user id.
+///
+/// Each day's revenue is split evenly between everyone scheduled on it, and
+/// every worker is paid for their part by the rule in effect on that day.
+/// Users that aren't workers anymore or were deactivated are still paid for the
+/// days they worked, but aren't listed without days or payouts.
+/// Amounts of a day are rounded with `round_money`.
+/// Schedule entries of users missing from `users` keep their part of the split,
+/// but nobody gets paid for them.
+pub fn calculate(
+    users: &[UserData],
+    schedule: &[ScheduleData],
+    revenue: &[RevenueData],
+    payouts: &[PayoutData],
+    rules: &Rules,
+) -> Vec<Payroll> {
+    let revenue = revenue
+        .iter()
+        .map(|r| (r.day, (r.with_percent, r.without_percent)))
+        .collect::<HashMap<_, _>>();
+    let schedule = schedule
+        .iter()
+        .map(|s| (s.day, s.user_id))
+        .collect::<BTreeSet<_>>();
+    let mut workers = HashMap::<NaiveDate, u32>::new();
+    for &(day, _) in &schedule {
+        *workers.entry(day).or_default() += 1;
+    }
+
+    let mut days = BTreeMap::<i32, Vec<NaiveDate>>::new();
+    for &(day, user_id) in &schedule {
+        if revenue.contains_key(&day) {
+            days.entry(user_id).or_default().push(day);
+        }
+    }
+    let mut paid = HashMap::<i32, Decimal>::new();
+    for payout in payouts {
+        *paid.entry(payout.user_id).or_default() += payout.amount;
+    }
+
+    let mut users = users
+        .iter()
+        .filter(|u| {
+            (u.is_active && u.is_worker) || days.contains_key(&u.id) || paid.contains_key(&u.id)
+        })
+        .collect::<Vec<_>>();
+    users.sort_by_key(|u| u.id);
+    users
+        .into_iter()
+        .map(|u| {
+            let days = days.get(&u.id).map(Vec::as_slice).unwrap_or_default();
+            let mut share = Decimal::ZERO;
+            let commissions = days
+                .iter()
+                .map(|&day| {
+                    let (with_percent, without_percent) = revenue[&day];
+                    let workers = workers[&day];
+                    let rule = rules.rule_for(u, day);
+                    let multiplier = rules.multiplier(&rule, day);
+
+                    let before = share;
+                    share += round_money(with_percent / Decimal::from(workers));
+                    let commission = rule.tiered(before, share)
+                        + round_money(without_percent / Decimal::from(workers))
+                            * rule.without_percent
+                            / Decimal::ONE_HUNDRED;
+                    let base_pay = round_money(rule.daily_pay * multiplier);
+                    let amount = round_money(commission * multiplier);
+                    let top_up = rule.daily_minimum - base_pay - amount;
+                    DayCommission {
+                        day,
+                        revenue: with_percent,
+                        workers,
+                        base_pay,
+                        multiplier,
+                        amount,
+                        top_up: round_money(top_up.max(Decimal::ZERO)),
+                    }
+                })
+                .collect::<Vec<_>>();
+            let base_pay = commissions.iter().map(|c| c.base_pay).sum::<Decimal>();
+            let total = base_pay
+                + commissions
+                    .iter()
+                    .map(|c| c.amount + c.top_up)
+                    .sum::<Decimal>();
+            Payroll {
+                user_id: u.id,
+                working_days: days.len() as u32,
+                base_pay,
+                commissions,
+                paid: paid.get(&u.id).copied().unwrap_or_default(),
+                total,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+
+    fn user(id: i32, is_worker: bool, pay: Decimal, percent: Decimal) -> UserData {
+        UserData {
+            id,
+            login: format!("user{}", id),
+            name: format!("User {}", id),
+            is_admin: false,
+            is_worker,
+            pay,
+            percent,
+            pwd_hash: String::new(),
+            pwd_salt: String::new(),
+            is_active: true,
+            deactivated_at: None,
+        }
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 6, day).unwrap()
+    }
+
+    fn workday(day: u32, user_id: i32) -> ScheduleData {
+        ScheduleData {
+            day: date(day),
+            user_id,
+        }
+    }
+
+    fn revenue(day: u32, with_percent: Decimal) -> RevenueData {
+        RevenueData {
+            day: date(day),
+            with_percent,
+            without_percent: dec!(5000),
+        }
+    }
+
+    fn payout(day: u32, user_id: i32, amount: Decimal) -> PayoutData {
+        PayoutData {
+            day: date(day),
+            user_id,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_calculate() {
+        let users = [
+            user(1, true, dec!(1000), dec!(10)),
+            user(2, true, dec!(500), dec!(20)),
+            user(3, false, dec!(0), dec!(0)),
+            user(4, true, dec!(700), dec!(5)),
+        ];
+        let schedule = [workday(1, 1), workday(2, 1), workday(3, 1), workday(1, 2)];
+        // Day 3 has no revenue and day 4 nobody scheduled
+        let revenue = [
+            revenue(1, dec!(1000)),
+            revenue(2, dec!(600)),
+            revenue(4, dec!(400)),
+        ];
+        let payouts = [payout(10, 1, dec!(100)), payout(20, 1, dec!(400))];
+
+        let payroll = calculate(&users, &schedule, &revenue, &payouts, &Rules::default());
+        assert_eq!(payroll.len(), 3);
+        assert_eq!(
+            payroll[0],
+            Payroll {
+                user_id: 1,
+                working_days: 2,
+                base_pay: dec!(2000),
+                commissions: vec![
+                    DayCommission {
+                        day: date(1),
+                        revenue: dec!(1000),
+                        workers: 2,
+                        base_pay: dec!(1000),
+                        multiplier: dec!(1),
+                        amount: dec!(50),
+                        top_up: dec!(0),
+                    },
+                    DayCommission {
+                        day: date(2),
+                        revenue: dec!(600),
+                        workers: 1,
+                        base_pay: dec!(1000),
+                        multiplier: dec!(1),
+                        amount: dec!(60),
+                        top_up: dec!(0),
+                    },
+                ],
+                paid: dec!(500),
+                total: dec!(2110),
+            }
+        );
+        assert_eq!(payroll[0].balance(), dec!(1610));
+        assert_eq!(payroll[1].total, dec!(500) + dec!(100));
+        assert_eq!(payroll[2].user_id, 4);
+        assert_eq!(payroll[2].total, dec!(0));
+    }
+
+    #[test]
+    fn test_former_worker_is_paid() {
+        let users = [
+            user(1, false, dec!(1000), dec!(10)),
+            user(2, false, dec!(0), dec!(0)),
+        ];
+        let payroll = calculate(
+            &users,
+            &[workday(1, 1)],
+            &[revenue(1, dec!(500))],
+            &[],
+            &Rules::default(),
+        );
+        assert_eq!(payroll.len(), 1);
+        assert_eq!(payroll[0].user_id, 1);
+        assert_eq!(payroll[0].total, dec!(1050));
+    }
+
+    #[test]
+    fn test_deactivated_worker_listed_only_with_days() {
+        let mut users = [
+            user(1, true, dec!(1000), dec!(0)),
+            user(2, true, dec!(1000), dec!(0)),
+            user(3, true, dec!(1000), dec!(0)),
+        ];
+        for user in &mut users[1..] {
+            user.is_active = false;
+        }
+        let payroll = calculate(
+            &users,
+            &[workday(1, 2)],
+            &[revenue(1, dec!(500))],
+            &[],
+            &Rules::default(),
+        );
+        let listed = payroll.iter().map(|p| p.user_id).collect::<Vec<_>>();
+        assert_eq!(listed, [1, 2]);
+        assert_eq!(payroll[1].total, dec!(1000));
+    }
+
+    #[test]
+    fn test_deleted_user_keeps_share() {
+        let users = [user(1, true, dec!(0), dec!(100))];
+        let schedule = [workday(1, 1), workday(1, 2)];
+        let payroll = calculate(
+            &users,
+            &schedule,
+            &[revenue(1, dec!(500))],
+            &[payout(1, 2, dec!(10))],
+            &Rules::default(),
+        );
+        assert_eq!(payroll.len(), 1);
+        assert_eq!(payroll[0].total, dec!(250));
+    }
+
+    fn tiers(tiers: &[(Decimal, Decimal)]) -> Vec<Tier> {
+        tiers
+            .iter()
+            .map(|&(from, percent)| Tier { from, percent })
+            .collect()
+    }
+
+    #[test]
+    fn test_pay_rates() {
+        let users = [
+            user(1, true, dec!(0), dec!(0)),
+            user(2, true, dec!(300), dec!(30)),
+        ];
+        let schedule = [
+            workday(1, 1),
+            workday(10, 1),
+            workday(20, 1),
+            workday(20, 2),
+        ];
+        let revenue = [
+            revenue(1, dec!(1000)),
+            revenue(10, dec!(1000)),
+            revenue(20, dec!(2000)),
+        ];
+        let rate = |valid_from, pay, percent| PayRate {
+            user_id: 1,
+            valid_from,
+            pay,
+            percent,
+        };
+        let rules = Rules {
+            // The first rate also covers the 1st
+            pay_rates: vec![
+                rate(date(15), dec!(200), dec!(20)),
+                rate(date(5), dec!(100), dec!(10)),
+            ],
+            rules: vec![UserRule {
+                user_id: 1,
+                valid_from: date(25),
+                rule: CommissionRule::default(),
+            }],
+            holidays: BTreeSet::new(),
+        };
+        let payroll = calculate(&users, &schedule, &revenue, &[], &rules);
+        let days = payroll[0]
+            .commissions
+            .iter()
+            .map(|c| (c.base_pay, c.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            days,
+            [
+                (dec!(100), dec!(100)),
+                (dec!(100), dec!(100)),
+                (dec!(200), dec!(200))
+            ]
+        );
+        // Without rates of their own
+        assert_eq!(payroll[1].total, dec!(300) + dec!(300));
+    }
+
+    #[test]
+    fn test_tiers() {
+        let users = [user(1, true, dec!(0), dec!(0))];
+        // Thursday the 1st, Friday the 2nd and Monday the 5th
+        let schedule = [workday(1, 1), workday(2, 1), workday(5, 1)];
+        let revenue = [
+            revenue(1, dec!(800)),
+            revenue(2, dec!(800)),
+            revenue(5, dec!(800)),
+        ];
+        let rules = Rules {
+            pay_rates: Vec::new(),
+            rules: vec![UserRule {
+                user_id: 1,
+                valid_from: date(1),
+                rule: CommissionRule {
+                    tiers: tiers(&[
+                        (dec!(0), dec!(10)),
+                        (dec!(1000), dec!(20)),
+                        (dec!(2000), dec!(50)),
+                    ]),
+                    ..Default::default()
+                },
+            }],
+            holidays: BTreeSet::new(),
+        };
+        let payroll = calculate(&users, &schedule, &revenue, &[], &rules);
+        let amounts = payroll[0]
+            .commissions
+            .iter()
+            .map(|c| c.amount)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            amounts,
+            [dec!(80), dec!(20) + dec!(120), dec!(80) + dec!(200)]
+        );
+        assert_eq!(payroll[0].total, dec!(500));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let users = [user(1, true, dec!(1000.10), dec!(10))];
+        let payroll = calculate(
+            &users,
+            &[workday(1, 1)],
+            &[revenue(1, dec!(333.33))],
+            &[],
+            &Rules::default(),
+        );
+        let stored = serde_json::to_value(payroll[0].snapshot()).unwrap();
+        assert_eq!(stored["total"], "1033.43");
+        let snapshot = serde_json::from_value(stored).unwrap();
+        assert_eq!(
+            Payroll::from_snapshot(snapshot, 2023, 6).as_ref(),
+            Some(&payroll[0])
+        );
+
+        // Written from the protocol's salary before amounts were decimal
+        let legacy = r#"{"id": 1, "total": 1033.43, "paid": 0.0, "days_worked": 1,
+            "base_pay": 1000.1, "balance": 1033.43, "commission": [{"day": 1,
+            "revenue": 333.33, "workers": 1, "base_pay": 1000.1, "multiplier": 1.0,
+            "amount": 33.33, "top_up": 0.0}]}"#;
+        let snapshot = serde_json::from_str::<Snapshot>(legacy).unwrap();
+        let legacy = Payroll::from_snapshot(snapshot, 2023, 6).unwrap();
+        assert_eq!(legacy.total, dec!(1033.43));
+        assert_eq!(legacy.commissions, payroll[0].commissions);
+        let mut day_31 = payroll[0].snapshot();
+        day_31.commission[0].day = 31;
+        assert!(Payroll::from_snapshot(day_31, 2023, 6).is_none());
+    }
+
+    #[test]
+    fn test_rules() {
+        let users = [
+            user(1, true, dec!(100), dec!(10)),
+            user(2, true, dec!(100), dec!(10)),
+        ];
+        // Thursday the 1st, Saturday the 3rd and Monday the 12th
+        let schedule = [workday(1, 1), workday(3, 1), workday(12, 1), workday(12, 2)];
+        let revenue = [
+            revenue(1, dec!(1000)),
+            revenue(3, dec!(1000)),
+            revenue(12, dec!(1000)),
+        ];
+        let rules = Rules {
+            pay_rates: Vec::new(),
+            rules: vec![
+                UserRule {
+                    user_id: 1,
+                    valid_from: date(2),
+                    rule: CommissionRule {
+                        daily_pay: dec!(200),
+                        tiers: tiers(&[(dec!(0), dec!(5))]),
+                        without_percent: dec!(1),
+                        daily_minimum: dec!(400),
+                        weekend_multiplier: dec!(2),
+                        holiday_multiplier: dec!(3),
+                    },
+                },
+                // Not in effect yet
+                UserRule {
+                    user_id: 2,
+                    valid_from: date(13),
+                    rule: CommissionRule::default(),
+                },
+            ],
+            holidays: [date(12)].into(),
+        };
+        let payroll = calculate(&users, &schedule, &revenue, &[], &rules);
+        let days = &payroll[0].commissions;
+        // The default rule before the 2nd
+        assert_eq!(
+            (days[0].base_pay, days[0].amount, days[0].top_up),
+            (dec!(100), dec!(100), dec!(0))
+        );
+        // Weekend: 200 * 2 pay, (5% of 1000 + 1% of 5000) * 2 commission
+        assert_eq!(days[1].multiplier, dec!(2));
+        assert_eq!(
+            (days[1].base_pay, days[1].amount, days[1].top_up),
+            (dec!(400), dec!(200), dec!(0))
+        );
+        // Holiday shared by two: (5% of 500 + 1% of 2500) * 3
+        assert_eq!(days[2].multiplier, dec!(3));
+        assert_eq!(
+            (days[2].base_pay, days[2].amount, days[2].top_up),
+            (dec!(600), dec!(150), dec!(0))
+        );
+        assert_eq!(payroll[0].base_pay, dec!(1100));
+        assert_eq!(payroll[0].total, dec!(1550));
+        assert_eq!(payroll[1].total, dec!(100) + dec!(50));
+
+        let mut rules = rules;
+        rules.rules[0].rule.daily_pay = dec!(0);
+        let payroll = calculate(&users, &schedule, &revenue, &[], &rules);
+        let days = &payroll[0].commissions;
+        assert_eq!(
+            (days[1].base_pay, days[1].amount, days[1].top_up),
+            (dec!(0), dec!(200), dec!(200))
+        );
+        assert_eq!(days[2].top_up, dec!(250));
+        assert_eq!(
+            payroll[0].total,
+            dec!(100) + dec!(100) + dec!(400) + dec!(400)
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    struct Month {
+        /// `is_worker`, `pay` and `percent` of users 1 to `USERS`
+        users: Vec<(bool, Decimal, Decimal)>,
+        schedule: Vec<ScheduleData>,
+        revenue: Vec<RevenueData>,
+        payouts: Vec<PayoutData>,
+        rules: Rules,
+    }
+
+    const USERS: i32 = 6;
+
+    fn month() -> impl Strategy<Value = Month> {
+        let users = vec(
+            (any::<bool>(), 0..5000u32, 0..100u32),
+            USERS as usize..=USERS as usize,
+        );
+        let workdays = vec((1..=30u32, 1..=USERS + 2), 0..60);
+        let days = prop::collection::btree_map(1..=30u32, 0..100_000u32, 0..30);
+        let paid = vec((1..=30u32, 1..=USERS + 2, 0..10_000u32), 0..10);
+        (users, workdays, days, paid).prop_map(|(users, workdays, days, paid)| Month {
+            rules: Rules::default(),
+            users: users
+                .into_iter()
+                .map(|(is_worker, pay, percent)| {
+                    (is_worker, Decimal::from(pay), Decimal::from(percent))
+                })
+                .collect(),
+            schedule: workdays
+                .into_iter()
+                .map(|(day, user_id)| workday(day, user_id))
+                .collect(),
+            revenue: days
+                .into_iter()
+                .map(|(day, amount)| revenue(day, Decimal::from(amount)))
+                .collect(),
+            payouts: paid
+                .into_iter()
+                .map(|(day, user_id, amount)| payout(day, user_id, Decimal::from(amount)))
+                .collect(),
+        })
+    }
+
+    fn rule() -> impl Strategy<Value = CommissionRule> {
+        let tiers = prop::collection::btree_map(0..50_000u32, 0..100u32, 0..4);
+        (0..5000u32, tiers, 0..10u32, 0..5000u32, 1..4u32, 1..4u32).prop_map(
+            |(daily_pay, tiers, without_percent, daily_minimum, weekend, holiday)| CommissionRule {
+                daily_pay: Decimal::from(daily_pay),
+                tiers: tiers
+                    .into_iter()
+                    .map(|(from, percent)| Tier {
+                        from: Decimal::from(from),
+                        percent: Decimal::from(percent),
+                    })
+                    .collect(),
+                without_percent: Decimal::from(without_percent),
+                daily_minimum: Decimal::from(daily_minimum),
+                weekend_multiplier: Decimal::from(weekend),
+                holiday_multiplier: Decimal::from(holiday),
+            },
+        )
+    }
+
+    /// A month where users may have their own rules
+    fn month_with_rules() -> impl Strategy<Value = Month> {
+        let rates = vec((1..=USERS, 1..=30u32, 0..5000u32, 0..100u32), 0..8);
+        let rules = vec((1..=USERS, 1..=30u32, rule()), 0..8);
+        let holidays = prop::collection::btree_set(1..=30u32, 0..4);
+        (month(), rates, rules, holidays).prop_map(|(mut month, rates, rules, holidays)| {
+            month.rules = Rules {
+                pay_rates: rates
+                    .into_iter()
+                    .map(|(user_id, day, pay, percent)| PayRate {
+                        user_id,
+                        valid_from: date(day),
+                        pay: Decimal::from(pay),
+                        percent: Decimal::from(percent),
+                    })
+                    .collect(),
+                rules: rules
+                    .into_iter()
+                    .map(|(user_id, day, rule)| UserRule {
+                        user_id,
+                        valid_from: date(day),
+                        rule,
+                    })
+                    .collect(),
+                holidays: holidays.into_iter().map(date).collect(),
+            };
+            month
+        })
+    }
+
+    impl Month {
+        fn users(&self) -> Vec<UserData> {
+            (1..)
+                .zip(&self.users)
+                .map(|(id, &(is_worker, pay, percent))| user(id, is_worker, pay, percent))
+                .collect()
+        }
+
+        fn calculate(&self) -> Vec<Payroll> {
+            calculate(
+                &self.users(),
+                &self.schedule,
+                &self.revenue,
+                &self.payouts,
+                &self.rules,
+            )
+        }
+
+        fn calculate_without(&self, user_id: i32) -> Vec<Payroll> {
+            let mut users = self.users();
+            users.retain(|u| u.id != user_id);
+            calculate(
+                &users,
+                &self.schedule,
+                &self.revenue,
+                &self.payouts,
+                &self.rules,
+            )
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_totals_add_up(month in month_with_rules()) {
+            for p in month.calculate() {
+                let commission = p.commissions.iter().map(|c| c.amount + c.top_up).sum::<Decimal>();
+                prop_assert_eq!(p.total, p.base_pay + commission);
+                prop_assert_eq!(p.working_days as usize, p.commissions.len());
+                prop_assert!(p.total >= dec!(0));
+            }
+        }
+
+        #[test]
+        fn test_commission_never_exceeds_revenue(month in month()) {
+            let payroll = month.calculate();
+            for r in &month.revenue {
+                let days = payroll
+                    .iter()
+                    .flat_map(|p| p.commissions.iter())
+                    .filter(|c| c.day == r.day)
+                    .collect::<Vec<_>>();
+                let shared = days.iter().map(|c| c.amount).sum::<Decimal>();
+                // Every worker's part may be rounded up by half a kopeck
+                let rounding = dec!(0.005) * Decimal::from(days.len());
+                prop_assert!(shared <= r.with_percent + rounding);
+            }
+        }
+
+        #[test]
+        fn test_unscheduled_revenue_ignored(month in month(), day in 1..=30u32, amount in 0..100_000u32) {
+            prop_assume!(!month.schedule.iter().any(|s| s.day == date(day)));
+            let mut with_revenue = month.clone();
+            with_revenue.revenue.retain(|r| r.day != date(day));
+            with_revenue.revenue.push(revenue(day, Decimal::from(amount)));
+            prop_assert_eq!(month.calculate(), with_revenue.calculate());
+        }
+
+        #[test]
+        fn test_everyone_who_worked_or_was_paid_is_listed(month in month()) {
+            let payroll = month.calculate();
+            let worked_days = |id| {
+                let mut days = month
+                    .schedule
+                    .iter()
+                    .filter(|s| s.user_id == id && month.revenue.iter().any(|r| r.day == s.day))
+                    .map(|s| s.day)
+                    .collect::<Vec<_>>();
+                days.sort();
+                days.dedup();
+                days
+            };
+            for u in &month.users() {
+                let paid = month
+                    .payouts
+                    .iter()
+                    .filter(|p| p.user_id == u.id)
+                    .map(|p| p.amount)
+                    .sum::<Decimal>();
+                let days = worked_days(u.id);
+                match payroll.iter().find(|p| p.user_id == u.id) {
+                    Some(p) => {
+                        prop_assert_eq!(p.working_days as usize, days.len());
+                        prop_assert_eq!(p.paid, paid);
+                    }
+                    None => {
+                        prop_assert!(!(u.is_active && u.is_worker) && days.is_empty());
+                        prop_assert!(!month.payouts.iter().any(|p| p.user_id == u.id));
+                    }
+                }
+            }
+            prop_assert!(payroll.iter().all(|p| p.user_id <= USERS));
+        }
+
+        #[test]
+        fn test_removing_user_keeps_others(month in month_with_rules(), removed in 1..=USERS, is_worker: bool) {
+            let before = month.calculate();
+            let after = month.calculate_without(removed);
+            let others = |payroll: &[Payroll]| {
+                payroll
+                    .iter()
+                    .filter(|p| p.user_id != removed)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            prop_assert_eq!(others(&before), others(&after));
+
+            // Toggling `is_worker` only decides whether an idle user is listed
+            let mut changed = month.clone();
+            changed.users[removed as usize - 1].0 = is_worker;
+            let after = changed.calculate();
+            prop_assert_eq!(others(&before), others(&after));
+            let find = |payroll: &[Payroll]| payroll.iter().find(|p| p.user_id == removed).cloned();
+            if let (Some(b), Some(a)) = (find(&before), find(&after)) {
+                prop_assert_eq!(b, a);
+            }
+        }
+
+        #[test]
+        fn test_days_pay_at_least_minimum(month in month_with_rules()) {
+            let users = month.users();
+            for p in month.calculate() {
+                let user = users.iter().find(|u| u.id == p.user_id).unwrap();
+                for c in &p.commissions {
+                    let rule = month.rules.rule_for(user, c.day);
+                    prop_assert!(c.base_pay + c.amount + c.top_up >= rule.daily_minimum - dec!(0.005));
+                    prop_assert!(c.amount >= dec!(0) && c.top_up >= dec!(0));
+                }
+            }
+        }
+
+        #[test]
+        fn test_default_rules_match_pay_and_percent(month in month()) {
+            let users = month.users();
+            for p in month.calculate() {
+                let user = users.iter().find(|u| u.id == p.user_id).unwrap();
+                for c in &p.commissions {
+                    prop_assert_eq!(c.base_pay, user.pay);
+                    prop_assert_eq!(c.top_up, dec!(0));
+                    let share = round_money(c.revenue / Decimal::from(c.workers)) * user.percent / dec!(100);
+                    prop_assert_eq!(c.amount, round_money(share));
+                }
+            }
+        }
+
+        #[test]
+        fn test_new_rate_keeps_earlier_days(
+            month in month_with_rules(),
+            user_id in 1..=USERS,
+            day in 2..=30u32,
+            pay in 0..5000u32,
+            percent in 0..100u32,
+        ) {
+            let rate = |valid_from, pay, percent| PayRate {
+                user_id,
+                valid_from,
+                pay,
+                percent,
+            };
+            let mut before = month;
+            before.rules.pay_rates.retain(|r| {
+                r.user_id != user_id || ![date(1), date(day)].contains(&r.valid_from)
+            });
+            before.rules.pay_rates.push(rate(date(1), dec!(100), dec!(10)));
+            let mut after = before.clone();
+            after.rules.pay_rates.push(rate(date(day), Decimal::from(pay), Decimal::from(percent)));
+            let earlier = |month: &Month| {
+                month
+                    .calculate()
+                    .into_iter()
+                    .filter(|p| p.user_id == user_id)
+                    .flat_map(|p| p.commissions)
+                    .filter(|c| c.day < date(day))
+                    .collect::<Vec<_>>()
+            };
+            prop_assert_eq!(earlier(&before), earlier(&after));
+        }
+
+        #[test]
+        fn test_duplicate_workdays_count_once(month in month_with_rules()) {
+            let mut doubled = month.clone();
+            doubled.schedule.extend(month.schedule.iter().map(|s| workday(s.day.day(), s.user_id)));
+            prop_assert_eq!(month.calculate(), doubled.calculate());
+        }
+    }
+}
@@ -1,46 +1,86 @@
 use crate::database::*;
+use crate::export::{self, ExportRequest};
+use crate::import::{self, ImportData, ImportRequest};
+use crate::login_throttle::{LoginThrottle, ThrottleConfig, ThrottleKey};
+use crate::payroll;
+use crate::report;
+use crate::schedule_policy::SchedulePolicy;
 use crate::utils;
+use crate::validation::{self, date, decimal};
+use chrono::{DateTime, Datelike, Days, Duration, Months, NaiveDate, Utc};
 use pravda_protocol::*;
-use std::collections::{HashMap, HashSet};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use tracing::warn;
+
+const SESSION_LIFETIME_DAYS: i64 = 30;
+const SETUP_CODE_LIFETIME_DAYS: i64 = 3;
+const AUDIT_PAGE_SIZE_MAX: u32 = 200;
+
+/// What the HTTP layer knows about the sender of a request
+#[derive(Default)]
+pub struct Client {
+    pub token: Option<String>,
+    pub user_agent: String,
+    pub address: Option<IpAddr>,
+}
 
 #[derive(Clone)]
 pub struct PravdaHandler<T: Database> {
     database: T,
+    throttle: LoginThrottle,
+    schedule_policy: SchedulePolicy,
 }
 
 impl<T: Database> PravdaHandler<T> {
     pub fn new(database: T) -> Self {
-        Self { database }
+        Self {
+            database,
+            throttle: LoginThrottle::new(ThrottleConfig::default()),
+            schedule_policy: SchedulePolicy::default(),
+        }
+    }
+
+    pub fn with_login_throttle(mut self, config: ThrottleConfig) -> Self {
+        self.throttle = LoginThrottle::new(config);
+        self
+    }
+
+    pub fn with_schedule_policy(mut self, policy: SchedulePolicy) -> Self {
+        self.schedule_policy = policy;
+        self
     }
 
-    pub async fn process(&self, request: Request, token: Option<String>) -> Response {
+    pub async fn check_ready(&self) -> anyhow::Result<()> {
+        self.database.check_ready().await
+    }
+
+    pub async fn process(&self, request: Request, client: Client) -> Response {
         if let Request::User(UserRequest::Login { login, password }) = request {
-            return self.login(login, password).await;
+            return self.login(login, password, client).await;
+        }
+        if let Request::User(UserRequest::SetupPassword {
+            login,
+            code,
+            password,
+        }) = request
+        {
+            return self.setup_password(login, code, password, client).await;
         }
 
-        let user = match token {
-            Some(token) => match self.database.get_user(&UserSearch::Token(token)).await {
-                Ok(user) => match user {
-                    None => return Err(ProtocolError::UnknownToken),
-                    Some(user) => user,
-                },
-                Err(e) => return Err(ProtocolError::Unknown(e.to_string())),
-            },
-            None => return Err(ProtocolError::Forbidden),
-        };
-
-        return match request {
+        let (user, token_hash) = self.authenticate(client.token).await?;
+        if matches!(request, Request::Admin(_)) && !user.is_admin {
+            return Err(ProtocolError::Forbidden);
+        }
+        validation::validate(&request)?;
+        match request {
             Request::User(user_request) => match user_request {
                 UserRequest::Login { .. } => panic!("Can't be login here!"),
-                UserRequest::GetUserInfo => Ok(ResponseData::UserInfo(User {
-                    id: user.id,
-                    login: user.login,
-                    name: user.name,
-                    is_admin: user.is_admin,
-                    is_worker: user.is_worker,
-                    pay: user.pay,
-                    percent: user.percent,
-                })),
+                UserRequest::SetupPassword { .. } => panic!("Can't be password setup here!"),
+                UserRequest::GetUserInfo => Ok(ResponseData::UserInfo(user_info(&user))),
                 UserRequest::GetSchedule { year, month } => self.get_schedule(year, month).await,
                 UserRequest::SetWorkday {
                     year,
@@ -48,7 +88,7 @@ impl<T: Database> PravdaHandler<T> {
                     day,
                     is_working,
                 } => {
-                    self.set_workday(user.id, year, month, day, is_working)
+                    self.set_workday(None, user.id, year, month, day, is_working)
                         .await
                 }
                 UserRequest::ChangePassword {
@@ -56,62 +96,541 @@ impl<T: Database> PravdaHandler<T> {
                     new_password,
                 } => self.set_password(user, old_password, new_password).await,
                 UserRequest::GetUserNames { ids } => self.get_user_names(ids).await,
+                UserRequest::Logout { all } => {
+                    let token_hash = if all { None } else { Some(token_hash) };
+                    self.logout(user.id, token_hash).await
+                }
             },
-            Request::Admin(admin_request) => {
-                if !user.is_admin {
-                    return Err(ProtocolError::Forbidden);
+            Request::Admin(admin_request) => match admin_request {
+                AdminRequest::GetUsers => self.get_users().await,
+                AdminRequest::AddUser(new_user) => self.add_user(user.id, new_user).await,
+                AdminRequest::ResetPassword { id } => self.reset_password(user.id, id).await,
+                AdminRequest::UpdateUser(new_user) => self.update_user(user.id, new_user).await,
+                AdminRequest::GetRevenue { year, month } => self.get_revenue(year, month).await,
+                AdminRequest::SetRevenue {
+                    year,
+                    month,
+                    revenue,
+                } => self.set_revenue(user.id, year, month, revenue).await,
+                AdminRequest::GetSalaryCalculation { year, month } => {
+                    self.get_salary_calculation(year, month).await
                 }
-                match admin_request {
-                    AdminRequest::GetUsers => self.get_users().await,
-                    AdminRequest::AddUser(user) => self.add_user(user).await,
-                    AdminRequest::ResetPassword { id } => self.reset_password(id).await,
-                    AdminRequest::UpdateUser(user) => self.update_user(user).await,
-                    AdminRequest::GetRevenue { year, month } => self.get_revenue(year, month).await,
-                    AdminRequest::SetRevenue {
-                        year,
-                        month,
-                        revenue,
-                    } => self.set_revenue(year, month, revenue).await,
-                    AdminRequest::GetSalaryCalculation { year, month } => {
-                        self.get_salary_calculation(year, month).await
+                AdminRequest::GetPayouts { year, month } => self.get_payouts(year, month).await,
+                AdminRequest::AddPayout {
+                    year,
+                    month,
+                    payout,
+                } => self.add_payout(user.id, year, month, payout).await,
+                AdminRequest::UpdatePayout {
+                    year,
+                    month,
+                    payout,
+                } => self.update_payout(user.id, year, month, payout).await,
+                AdminRequest::DeletePayout {
+                    year,
+                    month,
+                    day,
+                    user_id,
+                } => self.delete_payout(user.id, year, month, day, user_id).await,
+                AdminRequest::GetLockouts => self.get_lockouts().await,
+                AdminRequest::ClearLockout { id } => self.clear_lockout(user.id, id).await,
+                AdminRequest::GetAuditLog {
+                    user_id,
+                    from,
+                    to,
+                    kind,
+                    page,
+                    page_size,
+                } => {
+                    self.get_audit_log(user_id, from, to, kind, page, page_size)
+                        .await
+                }
+                AdminRequest::SetWorkday {
+                    user_id,
+                    year,
+                    month,
+                    day,
+                    is_working,
+                } => {
+                    self.set_workday(Some(user.id), user_id, year, month, day, is_working)
+                        .await
+                }
+                AdminRequest::GetClosedMonths { year } => self.get_closed_months(year).await,
+                AdminRequest::CloseMonth { year, month } => {
+                    self.close_month(user.id, year, month).await
+                }
+                AdminRequest::ReopenMonth { year, month } => {
+                    self.reopen_month(user.id, year, month).await
+                }
+                AdminRequest::GetPayRates { user_id } => self.get_pay_rates(user_id).await,
+                AdminRequest::SetPayRate(rate) => self.set_pay_rate(user.id, rate).await,
+                AdminRequest::DeletePayRate {
+                    user_id,
+                    year,
+                    month,
+                    day,
+                } => {
+                    self.delete_pay_rate(user.id, user_id, year, month, day)
+                        .await
+                }
+                AdminRequest::GetCommissionRules { user_id } => {
+                    self.get_commission_rules(user_id).await
+                }
+                AdminRequest::SetCommissionRule(rule) => {
+                    self.set_commission_rule(user.id, rule).await
+                }
+                AdminRequest::DeleteCommissionRule {
+                    user_id,
+                    year,
+                    month,
+                    day,
+                } => {
+                    self.delete_commission_rule(user.id, user_id, year, month, day)
+                        .await
+                }
+                AdminRequest::GetHolidays { year } => self.get_holidays(year).await,
+                AdminRequest::SetHoliday(holiday) => self.set_holiday(user.id, holiday).await,
+                AdminRequest::DeleteHoliday { year, month, day } => {
+                    self.delete_holiday(user.id, year, month, day).await
+                }
+                AdminRequest::GetReport { from, to } => {
+                    let (from, to) = validation::period(&from, &to)?;
+                    self.get_report(from, to).await
+                }
+                AdminRequest::GetYearToDateReport { year } => {
+                    self.get_year_to_date_report(year).await
+                }
+                AdminRequest::DeactivateUser { id } => self.set_active(user.id, id, false).await,
+                AdminRequest::ReactivateUser { id } => self.set_active(user.id, id, true).await,
+                AdminRequest::GetDeactivatedUsers => self.get_deactivated_users().await,
+            },
+        }
+    }
+
+    /// Files for the accountant, only admins may export
+    pub async fn export(
+        &self,
+        request: ExportRequest,
+        client: Client,
+    ) -> Result<export::File, ProtocolError> {
+        let (user, _) = self.authenticate(client.token).await?;
+        if !user.is_admin {
+            return Err(ProtocolError::Forbidden);
+        }
+        let (from, to) = validation::iso_period(&request.from, &request.to)?;
+        let names = match self.database.get_users(None).await {
+            Ok(users) => users.into_iter().map(|u| (u.id, u.name)).collect(),
+            Err(e) => return Err(e.into()),
+        };
+        match request.format {
+            export::Format::Csv => {
+                let Some(dataset) = request.dataset else {
+                    return Err(validation::invalid("dataset", "Не выбрано, что выгружать"));
+                };
+                let sheet = self.export_sheet(dataset, from, to, &names).await?;
+                Ok(export::File {
+                    name: format!("{}_{}_{}.csv", dataset.name(), from, to),
+                    content_type: "text/csv; charset=utf-8",
+                    data: export::csv(&sheet).map_err(|e| ProtocolError::Unknown(e.to_string()))?,
+                })
+            }
+            export::Format::Xlsx => {
+                let mut sheets = Vec::new();
+                for dataset in export::Dataset::ALL {
+                    sheets.push(self.export_sheet(dataset, from, to, &names).await?);
+                }
+                Ok(export::File {
+                    name: format!("pravda_{}_{}.xlsx", from, to),
+                    content_type:
+                        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                    data: export::xlsx(&sheets)
+                        .map_err(|e| ProtocolError::Unknown(e.to_string()))?,
+                })
+            }
+        }
+    }
+
+    /// Checks every line of a CSV file first, then writes them all at once
+    /// unless it's a dry run. Lines in closed months fail the whole file.
+    pub async fn import(&self, request: ImportRequest, data: &[u8], client: Client) -> Response {
+        let (user, _) = self.authenticate(client.token).await?;
+        if !user.is_admin {
+            return Err(ProtocolError::Forbidden);
+        }
+        // Deactivated users can't be scheduled, the rest of their history can be imported
+        let logins = match self.database.get_users(None).await {
+            Ok(users) => users
+                .into_iter()
+                .filter(|u| u.is_active || request.dataset != import::Dataset::Schedule)
+                .map(|u| (u.login, u.id))
+                .collect(),
+            Err(e) => return Err(e.into()),
+        };
+        let lines = import::parse(request.dataset, data, &logins)?;
+        let days = lines.iter().map(|l| l.row.day()).collect::<BTreeSet<_>>();
+        let (Some(&from), Some(&last)) = (days.first(), days.last()) else {
+            return Err(validation::invalid("file", "В файле нет строк"));
+        };
+        let next = last + Days::new(1);
+        // What the preview compares with is what the import overwrites
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let months = days
+            .iter()
+            .map(|d| (d.year() as u16, d.month() as u8))
+            .collect::<BTreeSet<_>>();
+        for (year, month) in months {
+            ensure_open(&tx, year, month).await?;
+        }
+        let stored = ImportData {
+            schedule: match tx.get_schedule(from, next).await {
+                Ok(schedule) => schedule,
+                Err(e) => return Err(e.into()),
+            },
+            revenue: match tx.get_revenue(from, next).await {
+                Ok(revenue) => revenue,
+                Err(e) => return Err(e.into()),
+            },
+            payouts: match tx.get_payouts(from, next).await {
+                Ok(payouts) => payouts,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let changes = import::preview(&lines, &stored)
+            .into_iter()
+            .map(|c| ImportChange {
+                line: c.line.number,
+                action: c.action,
+                before: c.before.map(|row| row.json().to_string()),
+                after: c.line.row.json().to_string(),
+            })
+            .collect();
+
+        if request.dry_run {
+            if let Err(e) = tx.rollback().await {
+                return Err(e.into());
+            }
+        } else {
+            if let Err(e) = write_import(&tx, &import::data(&lines)).await {
+                return Err(e.into());
+            }
+            let summary = json!({
+                "dataset": request.dataset.name(),
+                "from": from.to_string(),
+                "to": last.to_string(),
+                "lines": lines.len(),
+            });
+            audit(&tx, user.id, "Import", None, Some(summary)).await?;
+            if let Err(e) = tx.commit().await {
+                return Err(e.into());
+            }
+        }
+        Ok(ResponseData::Import(ImportResult {
+            dry_run: request.dry_run,
+            changes,
+        }))
+    }
+
+    /// Salaries are those of every month the period touches, as
+    /// `get_salary_calculation` returns them
+    async fn export_sheet(
+        &self,
+        dataset: export::Dataset,
+        from: NaiveDate,
+        to: NaiveDate,
+        names: &HashMap<UserId, String>,
+    ) -> Result<export::Sheet, ProtocolError> {
+        use export::Cell;
+
+        let next = to + Days::new(1);
+        let name = |id: UserId| Cell::Text(names.get(&id).cloned().unwrap_or_default());
+        let (header, rows) = match dataset {
+            export::Dataset::Schedule => {
+                let schedule = match self.database.get_schedule(from, next).await {
+                    Ok(schedule) => schedule,
+                    Err(e) => return Err(e.into()),
+                };
+                let rows = schedule
+                    .into_iter()
+                    .map(|s| {
+                        vec![
+                            Cell::Date(s.day),
+                            Cell::Number(s.user_id.into()),
+                            name(s.user_id),
+                        ]
+                    })
+                    .collect();
+                (vec!["Дата", "ID", "Сотрудник"], rows)
+            }
+            export::Dataset::Revenue => {
+                let revenue = match self.database.get_revenue(from, next).await {
+                    Ok(revenue) => revenue,
+                    Err(e) => return Err(e.into()),
+                };
+                let rows = revenue
+                    .into_iter()
+                    .map(|r| {
+                        vec![
+                            Cell::Date(r.day),
+                            Cell::Money(r.with_percent),
+                            Cell::Money(r.without_percent),
+                        ]
+                    })
+                    .collect();
+                (vec!["Дата", "С процентом", "Без процента"], rows)
+            }
+            export::Dataset::Payouts => {
+                let payouts = match self.database.get_payouts(from, next).await {
+                    Ok(payouts) => payouts,
+                    Err(e) => return Err(e.into()),
+                };
+                let rows = payouts
+                    .into_iter()
+                    .map(|p| {
+                        vec![
+                            Cell::Date(p.day),
+                            Cell::Number(p.user_id.into()),
+                            name(p.user_id),
+                            Cell::Money(p.amount),
+                        ]
+                    })
+                    .collect();
+                (vec!["Дата", "ID", "Сотрудник", "Сумма"], rows)
+            }
+            export::Dataset::Salaries => {
+                let mut rows = Vec::new();
+                for (year, month) in months(from, to) {
+                    for p in self.salaries(year, month).await? {
+                        rows.push(vec![
+                            Cell::Number(year.into()),
+                            Cell::Number(month.into()),
+                            Cell::Number(p.user_id.into()),
+                            name(p.user_id),
+                            Cell::Number(p.working_days.into()),
+                            Cell::Money(p.base_pay),
+                            Cell::Money(p.total),
+                            Cell::Money(p.paid),
+                            Cell::Money(p.balance()),
+                        ]);
                     }
                 }
+                let header = vec![
+                    "Год",
+                    "Месяц",
+                    "ID",
+                    "Сотрудник",
+                    "Дней",
+                    "Оклад",
+                    "Начислено",
+                    "Выплачено",
+                    "Остаток",
+                ];
+                (header, rows)
             }
         };
+        Ok(export::Sheet {
+            dataset,
+            header,
+            rows,
+        })
+    }
+
+    /// The user a session token belongs to and the token's hash, the session
+    /// is extended on every use
+    async fn authenticate(
+        &self,
+        token: Option<String>,
+    ) -> Result<(UserData, String), ProtocolError> {
+        let token_hash = match token {
+            Some(token) => utils::sha3(token),
+            None => return Err(ProtocolError::Forbidden),
+        };
+        let user = match self
+            .database
+            .get_user(&UserSearch::Token(token_hash.clone()))
+            .await
+        {
+            Ok(user) => match user {
+                Some(user) if user.is_active => user,
+                _ => return Err(ProtocolError::UnknownToken),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let now = Utc::now();
+        let expires_at = now + Duration::days(SESSION_LIFETIME_DAYS);
+        if let Err(e) = self
+            .database
+            .touch_session(&token_hash, now, expires_at)
+            .await
+        {
+            return Err(e.into());
+        }
+        Ok((user, token_hash))
     }
 
-    async fn login(&self, login: String, password: String) -> Response {
+    async fn login(&self, login: String, password: String, client: Client) -> Response {
+        let now = Utc::now();
+        let keys = self.check_throttle(&login, client.address, now).await?;
+
         let mut user = match self.database.get_user(&UserSearch::Login(login)).await {
             Ok(user) => match user {
-                None => return Err(ProtocolError::LoginFailed),
-                Some(user) => user,
+                Some(user) if user.is_active => user,
+                _ => return self.login_failed(&keys, now).await,
             },
-            Err(_) => return Err(ProtocolError::LoginFailed),
-        };
-        if user.pwd_hash == user.get_pwd_hash(password) {
-            user.token = utils::make_uuid();
-            match self.database.update_user(&user).await {
-                Ok(user) => Ok(ResponseData::Login {
-                    token: user.token,
-                    id: user.id,
-                }),
-                Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        if user.check_password(&password) {
+            for key in &keys {
+                self.throttle.reset(key);
+            }
+            if user.needs_rehash() {
+                if let Err(e) = user.set_password(&password) {
+                    return Err(ProtocolError::Unknown(e.to_string()));
+                }
+                if let Err(e) = self.database.update_user(&user).await {
+                    return Err(e.into());
+                }
+            }
+            // Expired sessions are never looked up again, nothing else removes them
+            if let Err(e) = self.database.delete_expired_sessions(now).await {
+                return Err(e.into());
+            }
+            let token = utils::make_uuid();
+            let session = SessionData {
+                token_hash: utils::sha3(&token),
+                user_id: user.id,
+                created_at: now,
+                last_seen_at: now,
+                expires_at: now + Duration::days(SESSION_LIFETIME_DAYS),
+                user_agent: client.user_agent,
+            };
+            match self.database.add_session(&session).await {
+                Ok(_) => Ok(ResponseData::Login { token, id: user.id }),
+                Err(e) => Err(e.into()),
             }
         } else {
-            Err(ProtocolError::LoginFailed)
+            self.login_failed(&keys, now).await
+        }
+    }
+
+    /// The keys failures of a login attempt count against, unless the login
+    /// or the address is locked out or has to wait
+    async fn check_throttle(
+        &self,
+        login: &str,
+        address: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ThrottleKey>, ProtocolError> {
+        let mut keys = vec![ThrottleKey::Login(login.to_string())];
+        keys.extend(address.map(ThrottleKey::Address));
+
+        let address = address.map(|a| a.to_string());
+        match self
+            .database
+            .is_locked_out(login, address.as_deref(), now)
+            .await
+        {
+            Ok(false) => {}
+            Ok(true) => return Err(ProtocolError::LoginFailed),
+            Err(e) => return Err(e.into()),
+        }
+        if self.throttle.is_throttled(&keys, now) {
+            return Err(ProtocolError::LoginFailed);
+        }
+        Ok(keys)
+    }
+
+    async fn login_failed(&self, keys: &[ThrottleKey], now: DateTime<Utc>) -> Response {
+        for key in self.throttle.add_failure(keys, now) {
+            let (login, address) = match key {
+                ThrottleKey::Login(login) => (Some(login), None),
+                ThrottleKey::Address(address) => (None, Some(address.to_string())),
+            };
+            warn!("Locking out login {:?} address {:?}", login, address);
+            let lockout = LockoutData {
+                id: 0,
+                login,
+                address,
+                locked_at: now,
+                locked_until: now + self.throttle.config().lockout_duration,
+                cleared_at: None,
+                cleared_by: None,
+            };
+            if let Err(e) = self.database.add_lockout(&lockout).await {
+                return Err(e.into());
+            }
+        }
+        Err(ProtocolError::LoginFailed)
+    }
+
+    /// Wrong codes count as failed logins, the code is used up in the same
+    /// transaction that sets the password so it works only once
+    async fn setup_password(
+        &self,
+        login: String,
+        code: String,
+        password: String,
+        client: Client,
+    ) -> Response {
+        let now = Utc::now();
+        let keys = self.check_throttle(&login, client.address, now).await?;
+        validation::password("password", &password)?;
+
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let mut user = match setup_code_user(&tx, login, &code, now).await {
+            Ok(Some(user)) => user,
+            // The failure is written once the transaction is over
+            Ok(None) => match tx.rollback().await {
+                Ok(_) => return self.login_failed(&keys, now).await,
+                Err(e) => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Err(e) = user.set_password(password) {
+            return Err(ProtocolError::Unknown(e.to_string()));
+        }
+        if let Err(e) = tx.update_user(&user).await {
+            return Err(e.into());
+        }
+        if let Err(e) = tx.delete_setup_code(user.id).await {
+            return Err(e.into());
+        }
+        audit(&tx, user.id, "SetupPassword", None, None).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        for key in &keys {
+            self.throttle.reset(key);
+        }
+        Ok(ResponseData::PasswordChanged)
+    }
+
+    async fn logout(&self, user_id: UserId, token_hash: Option<String>) -> Response {
+        match self
+            .database
+            .delete_sessions(user_id, token_hash.as_deref())
+            .await
+        {
+            Ok(_) => Ok(ResponseData::LoggedOut),
+            Err(e) => Err(e.into()),
         }
     }
 
     async fn get_schedule(&self, year: u16, month: u8) -> Response {
-        let schedule = match self.database.get_schedule(month, year).await {
+        let (first, next) = validation::month_range(year, month)?;
+        let schedule = match self.database.get_schedule(first, next).await {
             Ok(s) => s,
-            Err(e) => return Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => return Err(e.into()),
         };
-        let days_in_month = utils::get_days_in_month(year, month);
-        let false_vec = (0..=days_in_month)
-            .into_iter()
-            .map(|_| false)
-            .collect::<Vec<bool>>();
+        let days_in_month = utils::get_days_in_month(year, month)
+            .ok_or_else(|| validation::invalid("month", "Месяц должен быть от 1 до 12"))?;
+        let false_vec = (0..=days_in_month).map(|_| false).collect::<Vec<bool>>();
 
         let users = schedule.iter().map(|s| s.user_id).collect::<HashSet<i32>>();
         let schedule = users
@@ -121,7 +640,7 @@ impl<T: Database> PravdaHandler<T> {
                 for day in schedule
                     .iter()
                     .filter(|s| s.user_id == uid)
-                    .map(|s| s.day as usize)
+                    .map(|s| s.day.day() as usize)
                 {
                     vec[day] = true
                 }
@@ -136,30 +655,67 @@ impl<T: Database> PravdaHandler<T> {
         })
     }
 
+    /// Workers change their own schedule within the policy, `admin_id` is set
+    /// when an admin changes it on their behalf and may change any day
     async fn set_workday(
         &self,
+        admin_id: Option<UserId>,
         user_id: UserId,
         year: u16,
         month: u8,
         day: u8,
         is_working: bool,
     ) -> Response {
-        match self
-            .database
-            .set_schedule(
-                &ScheduleData {
-                    day: day as i32,
-                    month: month as i32,
-                    year: year as i32,
-                    user_id,
-                },
-                is_working,
-            )
+        let date = date(year, month, day)?;
+        let policy = &self.schedule_policy;
+        if admin_id.is_none() {
+            if let Err(violation) = policy.check_date(date, utils::local_now()) {
+                return Err(validation::invalid("day", violation.message()));
+            }
+        }
+        // The number of workers is checked against the schedule it's written to
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        let user = match tx.get_user(&UserSearch::Id(user_id)).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        let workers = match tx.get_schedule(date, date + Days::new(1)).await {
+            Ok(schedule) => schedule.into_iter().map(|s| s.user_id).collect::<Vec<_>>(),
+            Err(e) => return Err(e.into()),
+        };
+        let was_working = workers.contains(&user_id);
+        if is_working && !was_working {
+            if !user.is_active {
+                return Err(validation::invalid("user_id", "Пользователь отключён"));
+            }
+            if let Err(violation) = policy.check_workday(user.is_worker, workers.len()) {
+                return Err(validation::invalid("day", violation.message()));
+            }
+        }
+        if let Err(e) = tx
+            .set_schedule(&ScheduleData { day: date, user_id }, is_working)
             .await
         {
-            Ok(_) => self.get_schedule(year, month).await,
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            return Err(e.into());
         }
+        let workday = |is_working| json!({"year": year, "month": month, "day": day, "user_id": user_id, "is_working": is_working});
+        audit(
+            &tx,
+            admin_id.unwrap_or(user_id),
+            "SetWorkday",
+            Some(workday(was_working)),
+            Some(workday(is_working)),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_schedule(year, month).await
     }
 
     async fn set_password(
@@ -169,14 +725,23 @@ impl<T: Database> PravdaHandler<T> {
         new_password: String,
     ) -> Response {
         let mut user = user;
-        if user.pwd_hash != user.get_pwd_hash(old_password) {
+        if !user.check_password(old_password) {
             return Err(ProtocolError::LoginFailed);
         }
-        user.pwd_salt = utils::make_uuid();
-        user.pwd_hash = user.get_pwd_hash(new_password);
-        match self.database.update_user(&user).await {
+        if let Err(e) = user.set_password(new_password) {
+            return Err(ProtocolError::Unknown(e.to_string()));
+        }
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = tx.update_user(&user).await {
+            return Err(e.into());
+        }
+        audit(&tx, user.id, "ChangePassword", None, None).await?;
+        match tx.commit().await {
             Ok(_) => Ok(ResponseData::PasswordChanged),
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -185,139 +750,3596 @@ impl<T: Database> PravdaHandler<T> {
             Ok(users) => Ok(ResponseData::UserNames {
                 names: users.into_iter().map(|u| (u.id, u.name)).collect(),
             }),
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => Err(e.into()),
         }
     }
 
+    /// Only active users, past salaries and names still see everyone
     async fn get_users(&self) -> Response {
         match self.database.get_users(None).await {
             Ok(users) => Ok(ResponseData::Users(
                 users
-                    .into_iter()
-                    .map(|u| User {
-                        id: u.id,
-                        login: u.login,
-                        name: u.name,
-                        is_admin: u.is_admin,
-                        is_worker: u.is_worker,
-                        pay: u.pay,
-                        percent: u.percent,
-                    })
+                    .iter()
+                    .filter(|u| u.is_active)
+                    .map(user_info)
                     .collect(),
             )),
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => Err(e.into()),
         }
     }
 
-    async fn add_user(&self, user: User) -> Response {
-        if let Ok(Some(_)) = self
-            .database
-            .get_user(&UserSearch::Login(user.login.clone()))
-            .await
-        {
-            return Err(ProtocolError::UserExist);
+    async fn get_deactivated_users(&self) -> Response {
+        match self.database.get_users(None).await {
+            Ok(users) => Ok(ResponseData::Users(
+                users
+                    .iter()
+                    .filter(|u| !u.is_active)
+                    .map(user_info)
+                    .collect(),
+            )),
+            Err(e) => Err(e.into()),
         }
-        let mut user = UserData {
+    }
+
+    async fn add_user(&self, admin_id: UserId, user: User) -> Response {
+        let user = UserData {
             id: 0,
             login: user.login,
             name: user.name,
             is_admin: user.is_admin,
             is_worker: user.is_worker,
+            pay: decimal("pay", user.pay)?,
+            percent: decimal("percent", user.percent)?,
+            pwd_hash: "".to_string(),
+            pwd_salt: "".to_string(),
+            is_active: true,
+            deactivated_at: None,
+        };
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        match tx.get_user(&UserSearch::Login(user.login.clone())).await {
+            Ok(Some(_)) => return Err(ProtocolError::UserExist),
+            Ok(None) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let user = match tx.add_user(&user).await {
+            Ok(user) => user,
+            // Someone added the same login after the check above
+            Err(DbError::Conflict(_)) => return Err(ProtocolError::UserExist),
+            Err(e) => return Err(e.into()),
+        };
+        let rate = PayRateData {
+            user_id: user.id,
+            valid_from: utils::today(),
             pay: user.pay,
             percent: user.percent,
-            pwd_hash: "".to_string(),
-            pwd_salt: utils::make_uuid(),
-            token: utils::make_uuid(),
         };
-        user.pwd_hash = user.get_pwd_hash("Qwer4321");
-        match self.database.add_user(&user).await {
-            Ok(_) => self.get_users().await,
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+        if let Err(e) = tx.set_pay_rate(&rate).await {
+            return Err(e.into());
+        }
+        audit(
+            &tx,
+            admin_id,
+            "AddUser",
+            None,
+            Some(json!(user_info(&user))),
+        )
+        .await?;
+        let setup_code = issue_setup_code(&tx, user.id).await?;
+        match tx.commit().await {
+            Ok(_) => Ok(setup_code),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn reset_password(&self, admin_id: UserId, id: UserId) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let mut user = match tx.get_user(&UserSearch::Id(id)).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        user.pwd_hash = "".to_string();
+        user.pwd_salt = "".to_string();
+        if let Err(e) = tx.update_user(&user).await {
+            return Err(e.into());
+        }
+        if let Err(e) = tx.delete_sessions(user.id, None).await {
+            return Err(e.into());
+        }
+        audit(
+            &tx,
+            admin_id,
+            "ResetPassword",
+            None,
+            Some(json!({ "id": id })),
+        )
+        .await?;
+        let setup_code = issue_setup_code(&tx, user.id).await?;
+        match tx.commit().await {
+            Ok(_) => Ok(setup_code),
+            Err(e) => Err(e.into()),
         }
     }
 
-    async fn reset_password(&self, id: UserId) -> Response {
-        if let Ok(Some(mut user)) = self.database.get_user(&UserSearch::Id(id)).await {
-            user.pwd_salt = utils::make_uuid();
-            user.pwd_hash = user.get_pwd_hash("Qwer4321");
-            user.token = utils::make_uuid();
-            match self.database.update_user(&user).await {
-                Ok(_) => Ok(ResponseData::PasswordReset),
-                Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+    async fn update_user(&self, admin_id: UserId, new_user: User) -> Response {
+        let pay = decimal("pay", new_user.pay)?;
+        let percent = decimal("percent", new_user.percent)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let mut user = match tx.get_user(&UserSearch::Id(new_user.id)).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        let before = json!(user_info(&user));
+        if user.pay != pay || user.percent != percent {
+            // Only days from today on are paid at the new rate
+            if let Err(e) = tx
+                .set_pay_rate(&PayRateData {
+                    user_id: user.id,
+                    valid_from: utils::today(),
+                    pay,
+                    percent,
+                })
+                .await
+            {
+                return Err(e.into());
             }
-        } else {
-            Err(ProtocolError::Unknown(
-                "Не удалось найти пользователя".to_string(),
-            ))
-        }
-    }
-
-    async fn update_user(&self, new_user: User) -> Response {
-        if let Ok(Some(mut user)) = self.database.get_user(&UserSearch::Id(new_user.id)).await {
-            user.name = new_user.name;
-            user.is_worker = new_user.is_worker;
-            user.is_admin = new_user.is_admin;
-            user.pay = new_user.pay;
-            user.percent = new_user.percent;
-            match self.database.update_user(&user).await {
-                Ok(_) => self.get_users().await,
-                Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+        }
+        user.name = new_user.name;
+        user.is_worker = new_user.is_worker;
+        user.is_admin = new_user.is_admin;
+        user.pay = pay;
+        user.percent = percent;
+        if let Err(e) = tx.update_user(&user).await {
+            return Err(e.into());
+        }
+        let after = json!(user_info(&user));
+        audit(&tx, admin_id, "UpdateUser", Some(before), Some(after)).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_users().await
+    }
+
+    /// Deactivation revokes the user's sessions, the user and their history
+    /// stay so past months are calculated as before
+    async fn set_active(&self, admin_id: UserId, id: UserId, is_active: bool) -> Response {
+        if id == admin_id && !is_active {
+            return Err(validation::invalid("id", "Нельзя отключить самого себя"));
+        }
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let mut user = match tx.get_user(&UserSearch::Id(id)).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        if user.is_active == is_active {
+            return self.get_users().await;
+        }
+        let state = |user: &UserData| json!({"id": id, "is_active": user.is_active, "deactivated_at": user.deactivated_at.map(|t| t.timestamp())});
+        let before = state(&user);
+        user.is_active = is_active;
+        user.deactivated_at = if is_active { None } else { Some(Utc::now()) };
+        if let Err(e) = tx.update_user(&user).await {
+            return Err(e.into());
+        }
+        if !is_active {
+            if let Err(e) = tx.delete_sessions(id, None).await {
+                return Err(e.into());
             }
+        }
+        let after = state(&user);
+        let kind = if is_active {
+            "ReactivateUser"
         } else {
-            Err(ProtocolError::Unknown(
-                "Не удалось найти пользователя".to_string(),
-            ))
+            "DeactivateUser"
+        };
+        audit(&tx, admin_id, kind, Some(before), Some(after)).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
         }
+        self.get_users().await
     }
 
     async fn get_revenue(&self, year: u16, month: u8) -> Response {
-        match self.database.get_revenue(month, year).await {
+        let (first, next) = validation::month_range(year, month)?;
+        match self.database.get_revenue(first, next).await {
             Ok(revenue) => Ok(ResponseData::Revenue {
                 year,
                 month,
                 revenue: revenue
                     .into_iter()
                     .map(|r| Revenue {
-                        day: r.day as u8,
-                        with_percent: r.with_percent,
-                        without_percent: r.without_percent,
+                        day: r.day.day() as u8,
+                        with_percent: float(r.with_percent),
+                        without_percent: float(r.without_percent),
                     })
                     .collect(),
             }),
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            Err(e) => Err(e.into()),
         }
     }
 
-    async fn set_revenue(&self, year: u16, month: u8, revenue: Revenue) -> Response {
-        match self
-            .database
+    async fn set_revenue(
+        &self,
+        admin_id: UserId,
+        year: u16,
+        month: u8,
+        revenue: Revenue,
+    ) -> Response {
+        let day = date(year, month, revenue.day)?;
+        let with_percent = decimal("revenue.with_percent", revenue.with_percent)?;
+        let without_percent = decimal("revenue.without_percent", revenue.without_percent)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        let before = match tx.get_revenue(day, day + Days::new(1)).await {
+            Ok(rows) => rows.into_iter().next().map(|r| Revenue {
+                day: revenue.day,
+                with_percent: float(r.with_percent),
+                without_percent: float(r.without_percent),
+            }),
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = tx
             .set_revenue(&RevenueData {
-                day: revenue.day as i32,
-                month: month as i32,
-                year: year as i32,
-                with_percent: revenue.with_percent,
-                without_percent: revenue.without_percent,
+                day,
+                with_percent,
+                without_percent,
             })
             .await
         {
-            Ok(_) => self.get_revenue(year, month).await,
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+            return Err(e.into());
         }
+        let snapshot = |revenue: Revenue| json!({"year": year, "month": month, "revenue": revenue});
+        audit(
+            &tx,
+            admin_id,
+            "SetRevenue",
+            before.map(snapshot),
+            Some(snapshot(revenue)),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_revenue(year, month).await
     }
 
     async fn get_salary_calculation(&self, year: u16, month: u8) -> Response {
-        match self.database.get_salaries(month, year).await {
-            Ok(salaries) => Ok(ResponseData::SalaryCalculation {
-                salaries: salaries
-                    .into_iter()
-                    .map(|s| Salary {
-                        id: s.user_id,
-                        total: s.amount_owed + s.amount_paid,
-                        paid: s.amount_paid,
-                    })
-                    .collect(),
-            }),
-            Err(e) => Err(ProtocolError::Unknown(e.to_string())),
+        let payroll = self.salaries(year, month).await?;
+        Ok(ResponseData::SalaryCalculation {
+            salaries: payroll.iter().map(salary).collect(),
+        })
+    }
+
+    /// The snapshot of a closed month, the salaries as they stand otherwise
+    async fn salaries(&self, year: u16, month: u8) -> Result<Vec<payroll::Payroll>, ProtocolError> {
+        match self.get_closed_month(year, month).await? {
+            Some(closed) => read_snapshot(&closed),
+            None => calculate_payroll(&self.database, year, month).await,
         }
     }
+
+    /// Earnings come from each month's salaries, so closed months report what
+    /// their snapshot says
+    async fn get_report(&self, from: NaiveDate, to: NaiveDate) -> Response {
+        let next = to + Days::new(1);
+        let revenue = match self.database.get_revenue(from, next).await {
+            Ok(revenue) => revenue,
+            Err(e) => return Err(e.into()),
+        };
+        let payouts = match self.database.get_payouts(from, next).await {
+            Ok(payouts) => payouts,
+            Err(e) => return Err(e.into()),
+        };
+        let mut earnings = Vec::new();
+        for (year, month) in months(from, to) {
+            for p in self.salaries(year, month).await? {
+                for c in p.commissions {
+                    earnings.push(report::DayEarning {
+                        user_id: p.user_id,
+                        day: c.day,
+                        amount: c.base_pay + c.amount + c.top_up,
+                    });
+                }
+            }
+        }
+
+        let report = report::summarize(from, to, &revenue, &earnings, &payouts);
+        Ok(ResponseData::Report(Report {
+            from: protocol_date(from),
+            to: protocol_date(to),
+            with_percent: float(report.totals.with_percent),
+            without_percent: float(report.totals.without_percent),
+            workers: worker_totals(&report.totals),
+            months: report
+                .months
+                .iter()
+                .map(|(&(year, month), totals)| MonthReport {
+                    year: year as u16,
+                    month: month as u8,
+                    with_percent: float(totals.with_percent),
+                    without_percent: float(totals.without_percent),
+                    workers: worker_totals(totals),
+                })
+                .collect(),
+        }))
+    }
+
+    /// From the start of the year up to today, or the whole year once it's over
+    async fn get_year_to_date_report(&self, year: u16) -> Response {
+        let first = date(year, 1, 1)?;
+        let last = date(year, 12, 31)?.min(utils::today());
+        if last < first {
+            return Err(validation::invalid("year", "Этот год ещё не начался"));
+        }
+        self.get_report(first, last).await
+    }
+
+    async fn get_closed_month(
+        &self,
+        year: u16,
+        month: u8,
+    ) -> Result<Option<ClosedMonthData>, ProtocolError> {
+        match self.database.get_closed_months(year).await {
+            Ok(months) => Ok(months.into_iter().find(|m| m.month == month as i32)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_closed_months(&self, year: u16) -> Response {
+        match self.database.get_closed_months(year).await {
+            Ok(months) => Ok(ResponseData::ClosedMonths {
+                year,
+                months: months
+                    .into_iter()
+                    .map(|m| ClosedMonth {
+                        year,
+                        month: m.month as u8,
+                        closed_at: m.closed_at.timestamp(),
+                        closed_by: m.closed_by,
+                    })
+                    .collect(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn close_month(&self, admin_id: UserId, year: u16, month: u8) -> Response {
+        date(year, month, 1)?;
+        // The snapshot is what the month holds when it's closed
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        let payroll = calculate_payroll(&tx, year, month).await?;
+        let snapshot = payroll.iter().map(payroll::Payroll::snapshot);
+        let closed = ClosedMonthData {
+            year: year as i32,
+            month: month as i32,
+            closed_at: Utc::now(),
+            closed_by: admin_id,
+            snapshot: json!(snapshot.collect::<Vec<_>>()).to_string(),
+        };
+        if let Err(e) = tx.close_month(&closed).await {
+            return Err(e.into());
+        }
+        audit(
+            &tx,
+            admin_id,
+            "CloseMonth",
+            None,
+            Some(json!({"year": year, "month": month})),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        Ok(ResponseData::SalaryCalculation {
+            salaries: payroll.iter().map(salary).collect(),
+        })
+    }
+
+    async fn reopen_month(&self, admin_id: UserId, year: u16, month: u8) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let closed = match tx.get_closed_months(year).await {
+            Ok(months) => months.into_iter().find(|m| m.month == month as i32),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(closed) = closed else {
+            return Err(validation::invalid("month", "Месяц не закрыт"));
+        };
+        if let Err(e) = tx.reopen_month(month, year).await {
+            return Err(e.into());
+        }
+        let salaries = serde_json::from_str::<Value>(&closed.snapshot).ok();
+        let before = json!({
+            "year": year,
+            "month": month,
+            "closed_at": closed.closed_at.timestamp(),
+            "closed_by": closed.closed_by,
+            "salaries": salaries,
+        });
+        audit(&tx, admin_id, "ReopenMonth", Some(before), None).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_closed_months(year).await
+    }
+
+    async fn get_payouts(&self, year: u16, month: u8) -> Response {
+        let (first, next) = validation::month_range(year, month)?;
+        match self.database.get_payouts(first, next).await {
+            Ok(payouts) => Ok(ResponseData::Payouts {
+                year,
+                month,
+                payouts: payouts
+                    .into_iter()
+                    .map(|p| Payout {
+                        day: p.day.day() as u8,
+                        user_id: p.user_id,
+                        amount: float(p.amount),
+                    })
+                    .collect(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn add_payout(&self, admin_id: UserId, year: u16, month: u8, payout: Payout) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        match tx.get_user(&UserSearch::Id(payout.user_id)).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        }
+        // A second payout on the same day is a correction, which is what UpdatePayout is for
+        if get_payout(&tx, year, month, payout.day, payout.user_id)
+            .await?
+            .is_some()
+        {
+            return Err(ProtocolError::Conflict);
+        }
+        save_payout(&tx, admin_id, "AddPayout", year, month, None, payout).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_salary_calculation(year, month).await
+    }
+
+    async fn update_payout(
+        &self,
+        admin_id: UserId,
+        year: u16,
+        month: u8,
+        payout: Payout,
+    ) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        let Some(before) = get_payout(&tx, year, month, payout.day, payout.user_id).await? else {
+            return Err(ProtocolError::NotFound);
+        };
+        save_payout(
+            &tx,
+            admin_id,
+            "UpdatePayout",
+            year,
+            month,
+            Some(before),
+            payout,
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_salary_calculation(year, month).await
+    }
+
+    async fn delete_payout(
+        &self,
+        admin_id: UserId,
+        year: u16,
+        month: u8,
+        day: u8,
+        user_id: UserId,
+    ) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        ensure_open(&tx, year, month).await?;
+        let Some(before) = get_payout(&tx, year, month, day, user_id).await? else {
+            return Err(ProtocolError::NotFound);
+        };
+        if let Err(e) = tx.delete_payout(user_id, date(year, month, day)?).await {
+            return Err(e.into());
+        }
+        let snapshot = json!({"year": year, "month": month, "payout": before});
+        audit(&tx, admin_id, "DeletePayout", Some(snapshot), None).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_salary_calculation(year, month).await
+    }
+
+    async fn get_lockouts(&self) -> Response {
+        match self.database.get_lockouts(None).await {
+            Ok(lockouts) => Ok(ResponseData::Lockouts(
+                lockouts
+                    .into_iter()
+                    .map(|l| Lockout {
+                        id: l.id,
+                        login: l.login,
+                        address: l.address,
+                        locked_at: l.locked_at.timestamp(),
+                        locked_until: l.locked_until.timestamp(),
+                        cleared_at: l.cleared_at.map(|t| t.timestamp()),
+                        cleared_by: l.cleared_by,
+                    })
+                    .collect(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn clear_lockout(&self, admin_id: UserId, id: i32) -> Response {
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let lockout = match tx.get_lockouts(None).await {
+            Ok(lockouts) => lockouts.into_iter().find(|l| l.id == id),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(lockout) = lockout else {
+            return Err(ProtocolError::NotFound);
+        };
+        if let Err(e) = tx.clear_lockout(id, admin_id, Utc::now()).await {
+            return Err(e.into());
+        }
+        audit(
+            &tx,
+            admin_id,
+            "ClearLockout",
+            None,
+            Some(json!({ "id": id })),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        if let Some(login) = lockout.login {
+            self.throttle.reset(&ThrottleKey::Login(login));
+        }
+        if let Some(address) = lockout.address.and_then(|a| a.parse().ok()) {
+            self.throttle.reset(&ThrottleKey::Address(address));
+        }
+        self.get_lockouts().await
+    }
+
+    async fn get_audit_log(
+        &self,
+        user_id: Option<UserId>,
+        from: Option<i64>,
+        to: Option<i64>,
+        kind: Option<String>,
+        page: u32,
+        page_size: u32,
+    ) -> Response {
+        let page_size = page_size.clamp(1, AUDIT_PAGE_SIZE_MAX);
+        let filter = AuditFilter {
+            user_id,
+            from: from.map(|t| validation::timestamp("from", t)).transpose()?,
+            to: to.map(|t| validation::timestamp("to", t)).transpose()?,
+            kind,
+            offset: page as i64 * page_size as i64,
+            limit: page_size as i64,
+        };
+        match self.database.get_audit_log(&filter).await {
+            Ok(audit_log) => Ok(ResponseData::AuditLog {
+                page,
+                entries: audit_log
+                    .into_iter()
+                    .map(|a| AuditEntry {
+                        id: a.id,
+                        user_id: a.user_id,
+                        created_at: a.created_at.timestamp(),
+                        kind: a.kind,
+                        before: a.value_before,
+                        after: a.value_after,
+                    })
+                    .collect(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_pay_rates(&self, user_id: UserId) -> Response {
+        match self.database.get_pay_rates(Some(user_id)).await {
+            Ok(rates) => Ok(ResponseData::PayRates {
+                user_id,
+                rates: rates.iter().map(pay_rate_info).collect(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_pay_rate(&self, admin_id: UserId, rate: PayRate) -> Response {
+        let valid_from = date(rate.year, rate.month, rate.day)?;
+        let pay = decimal("pay", rate.pay)?;
+        let percent = decimal("percent", rate.percent)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        match tx.get_user(&UserSearch::Id(rate.user_id)).await {
+            Ok(Some(user)) if user.is_active => {}
+            Ok(Some(_)) => return Err(validation::invalid("user_id", "Пользователь отключён")),
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        }
+        let rates = match tx.get_pay_rates(Some(rate.user_id)).await {
+            Ok(rates) => rates,
+            Err(e) => return Err(e.into()),
+        };
+        let before = rates
+            .iter()
+            .find(|r| r.valid_from == valid_from)
+            .map(pay_rate_info);
+        if let Err(e) = tx
+            .set_pay_rate(&PayRateData {
+                user_id: rate.user_id,
+                valid_from,
+                pay,
+                percent,
+            })
+            .await
+        {
+            return Err(e.into());
+        }
+        let user_id = rate.user_id;
+        sync_pay(&tx, user_id).await?;
+        audit(
+            &tx,
+            admin_id,
+            "SetPayRate",
+            before.map(|r| json!(r)),
+            Some(json!(rate)),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_pay_rates(user_id).await
+    }
+
+    async fn delete_pay_rate(
+        &self,
+        admin_id: UserId,
+        user_id: UserId,
+        year: u16,
+        month: u8,
+        day: u8,
+    ) -> Response {
+        let valid_from = date(year, month, day)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let rates = match tx.get_pay_rates(Some(user_id)).await {
+            Ok(rates) => rates,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(before) = rates.iter().find(|r| r.valid_from == valid_from) else {
+            return Err(ProtocolError::NotFound);
+        };
+        if rates.len() == 1 {
+            return Err(validation::invalid(
+                "day",
+                "Нельзя удалить единственную ставку",
+            ));
+        }
+        if let Err(e) = tx.delete_pay_rate(user_id, valid_from).await {
+            return Err(e.into());
+        }
+        sync_pay(&tx, user_id).await?;
+        audit(
+            &tx,
+            admin_id,
+            "DeletePayRate",
+            Some(json!(pay_rate_info(before))),
+            None,
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_pay_rates(user_id).await
+    }
+
+    async fn get_commission_rules(&self, user_id: Option<UserId>) -> Response {
+        match self.database.get_commission_rules(user_id).await {
+            Ok(rules) => Ok(ResponseData::CommissionRules(
+                rules
+                    .iter()
+                    .map(commission_rule_info)
+                    .collect::<Result<_, _>>()?,
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_commission_rule(&self, admin_id: UserId, rule: CommissionRule) -> Response {
+        let valid_from = date(rule.year, rule.month, rule.day)?;
+        let stored = payroll::CommissionRule {
+            daily_pay: decimal("daily_pay", rule.daily_pay)?,
+            tiers: rule
+                .tiers
+                .iter()
+                .enumerate()
+                .map(|(i, t)| {
+                    Ok(payroll::Tier {
+                        from: decimal(&format!("tiers[{}].from", i), t.from)?,
+                        percent: decimal(&format!("tiers[{}].percent", i), t.percent)?,
+                    })
+                })
+                .collect::<Result<_, ProtocolError>>()?,
+            without_percent: decimal("without_percent", rule.without_percent)?,
+            daily_minimum: decimal("daily_minimum", rule.daily_minimum)?,
+            weekend_multiplier: decimal("weekend_multiplier", rule.weekend_multiplier)?,
+            holiday_multiplier: decimal("holiday_multiplier", rule.holiday_multiplier)?,
+        };
+        let stored = match serde_json::to_string(&stored) {
+            Ok(stored) => stored,
+            Err(e) => return Err(ProtocolError::Unknown(e.to_string())),
+        };
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        match tx.get_user(&UserSearch::Id(rule.user_id)).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(ProtocolError::NotFound),
+            Err(e) => return Err(e.into()),
+        }
+        let before = get_commission_rule(&tx, rule.user_id, valid_from).await?;
+        if let Err(e) = tx
+            .set_commission_rule(&CommissionRuleData {
+                user_id: rule.user_id,
+                valid_from,
+                rule: stored,
+            })
+            .await
+        {
+            return Err(e.into());
+        }
+        let user_id = rule.user_id;
+        audit(
+            &tx,
+            admin_id,
+            "SetCommissionRule",
+            before.map(|r| json!(r)),
+            Some(json!(rule)),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_commission_rules(Some(user_id)).await
+    }
+
+    async fn delete_commission_rule(
+        &self,
+        admin_id: UserId,
+        user_id: UserId,
+        year: u16,
+        month: u8,
+        day: u8,
+    ) -> Response {
+        let valid_from = date(year, month, day)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(before) = get_commission_rule(&tx, user_id, valid_from).await? else {
+            return Err(ProtocolError::NotFound);
+        };
+        if let Err(e) = tx.delete_commission_rule(user_id, valid_from).await {
+            return Err(e.into());
+        }
+        audit(
+            &tx,
+            admin_id,
+            "DeleteCommissionRule",
+            Some(json!(before)),
+            None,
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_commission_rules(Some(user_id)).await
+    }
+
+    async fn get_holidays(&self, year: u16) -> Response {
+        let first = date(year, 1, 1)?;
+        match self
+            .database
+            .get_holidays(first, first + Months::new(12))
+            .await
+        {
+            Ok(holidays) => Ok(ResponseData::Holidays {
+                year,
+                holidays: holidays
+                    .into_iter()
+                    .map(|h| Holiday {
+                        year,
+                        month: h.day.month() as u8,
+                        day: h.day.day() as u8,
+                        name: h.name,
+                    })
+                    .collect(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_holiday(&self, admin_id: UserId, holiday: Holiday) -> Response {
+        let day = date(holiday.year, holiday.month, holiday.day)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let before = get_holiday(&tx, day).await?;
+        if let Err(e) = tx
+            .set_holiday(&HolidayData {
+                day,
+                name: holiday.name.clone(),
+            })
+            .await
+        {
+            return Err(e.into());
+        }
+        let year = holiday.year;
+        audit(
+            &tx,
+            admin_id,
+            "SetHoliday",
+            before.map(|h| json!(h)),
+            Some(json!(holiday)),
+        )
+        .await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_holidays(year).await
+    }
+
+    async fn delete_holiday(&self, admin_id: UserId, year: u16, month: u8, day: u8) -> Response {
+        let date = date(year, month, day)?;
+        let tx = match self.database.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(before) = get_holiday(&tx, date).await? else {
+            return Err(ProtocolError::NotFound);
+        };
+        if let Err(e) = tx.delete_holiday(date).await {
+            return Err(e.into());
+        }
+        audit(&tx, admin_id, "DeleteHoliday", Some(json!(before)), None).await?;
+        if let Err(e) = tx.commit().await {
+            return Err(e.into());
+        }
+        self.get_holidays(year).await
+    }
+}
+
+async fn issue_setup_code(db: &impl Queries, user_id: UserId) -> Response {
+    let code = utils::make_setup_code();
+    let setup_code = SetupCodeData {
+        user_id,
+        code_hash: utils::sha3(&code),
+        expires_at: Utc::now() + Duration::days(SETUP_CODE_LIFETIME_DAYS),
+    };
+    match db.set_setup_code(&setup_code).await {
+        Ok(_) => Ok(ResponseData::SetupCode { id: user_id, code }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The active user with the login, if `code` is their setup code and hasn't expired
+async fn setup_code_user(
+    db: &impl Queries,
+    login: String,
+    code: &str,
+    now: DateTime<Utc>,
+) -> DbResult<Option<UserData>> {
+    let Some(user) = db.get_user(&UserSearch::Login(login)).await? else {
+        return Ok(None);
+    };
+    let Some(setup_code) = db.get_setup_code(user.id).await? else {
+        return Ok(None);
+    };
+    let code = code.trim().to_uppercase();
+    let valid =
+        user.is_active && setup_code.expires_at > now && setup_code.code_hash == utils::sha3(code);
+    Ok(valid.then_some(user))
+}
+
+/// Salaries as the stored days, revenue and rules give them
+async fn calculate_payroll(
+    db: &impl Queries,
+    year: u16,
+    month: u8,
+) -> Result<Vec<payroll::Payroll>, ProtocolError> {
+    let users = match db.get_users(None).await {
+        Ok(users) => users,
+        Err(e) => return Err(e.into()),
+    };
+    let (first, next) = validation::month_range(year, month)?;
+    let schedule = match db.get_schedule(first, next).await {
+        Ok(schedule) => schedule,
+        Err(e) => return Err(e.into()),
+    };
+    let revenue = match db.get_revenue(first, next).await {
+        Ok(revenue) => revenue,
+        Err(e) => return Err(e.into()),
+    };
+    let payouts = match db.get_payouts(first, next).await {
+        Ok(payouts) => payouts,
+        Err(e) => return Err(e.into()),
+    };
+    let pay_rates = match db.get_pay_rates(None).await {
+        Ok(rates) => rates
+            .into_iter()
+            .map(|r| payroll::PayRate {
+                user_id: r.user_id,
+                valid_from: r.valid_from,
+                pay: r.pay,
+                percent: r.percent,
+            })
+            .collect(),
+        Err(e) => return Err(e.into()),
+    };
+    let rules = match db.get_commission_rules(None).await {
+        Ok(rules) => rules
+            .iter()
+            .map(|r| {
+                Ok(payroll::UserRule {
+                    user_id: r.user_id,
+                    valid_from: r.valid_from,
+                    rule: parse_rule(r)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ProtocolError>>()?,
+        Err(e) => return Err(e.into()),
+    };
+    let holidays = match db.get_holidays(first, next).await {
+        Ok(holidays) => holidays.into_iter().map(|h| h.day).collect(),
+        Err(e) => return Err(e.into()),
+    };
+    let rules = payroll::Rules {
+        pay_rates,
+        rules,
+        holidays,
+    };
+    Ok(payroll::calculate(
+        &users, &schedule, &revenue, &payouts, &rules,
+    ))
+}
+
+/// Schedule, revenue and payouts of closed months can't change, checked in
+/// the transaction that changes them
+async fn ensure_open(db: &impl Queries, year: u16, month: u8) -> Result<(), ProtocolError> {
+    match db.get_closed_months(year).await {
+        Ok(months) if months.iter().any(|m| m.month == month as i32) => {
+            Err(ProtocolError::MonthClosed { year, month })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn get_payout(
+    db: &impl Queries,
+    year: u16,
+    month: u8,
+    day: u8,
+    user_id: UserId,
+) -> Result<Option<Payout>, ProtocolError> {
+    let date = date(year, month, day)?;
+    match db.get_payouts(date, date + Days::new(1)).await {
+        Ok(payouts) => Ok(payouts
+            .into_iter()
+            .find(|p| p.user_id == user_id)
+            .map(|p| Payout {
+                day,
+                user_id: p.user_id,
+                amount: float(p.amount),
+            })),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keeps the user's `pay` and `percent` at the rate in effect today
+async fn sync_pay(db: &impl Queries, user_id: UserId) -> Result<(), ProtocolError> {
+    let rates = match db.get_pay_rates(Some(user_id)).await {
+        Ok(rates) => rates,
+        Err(e) => return Err(e.into()),
+    };
+    let today = utils::today();
+    let rate = rates
+        .iter()
+        .rev()
+        .find(|r| r.valid_from <= today)
+        .or(rates.first());
+    let user = match db.get_user(&UserSearch::Id(user_id)).await {
+        Ok(user) => user,
+        Err(e) => return Err(e.into()),
+    };
+    let (Some(rate), Some(mut user)) = (rate, user) else {
+        return Ok(());
+    };
+    user.pay = rate.pay;
+    user.percent = rate.percent;
+    match db.update_user(&user).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn get_commission_rule(
+    db: &impl Queries,
+    user_id: UserId,
+    valid_from: NaiveDate,
+) -> Result<Option<CommissionRule>, ProtocolError> {
+    match db.get_commission_rules(Some(user_id)).await {
+        Ok(rules) => rules
+            .iter()
+            .find(|r| r.valid_from == valid_from)
+            .map(commission_rule_info)
+            .transpose(),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn get_holiday(db: &impl Queries, day: NaiveDate) -> Result<Option<Holiday>, ProtocolError> {
+    match db.get_holidays(day, day + Duration::days(1)).await {
+        Ok(holidays) => Ok(holidays.into_iter().next().map(|h| Holiday {
+            year: day.year() as u16,
+            month: day.month() as u8,
+            day: day.day() as u8,
+            name: h.name,
+        })),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds or replaces the payout and records it as `kind`
+async fn save_payout(
+    tx: &impl Queries,
+    admin_id: UserId,
+    kind: &str,
+    year: u16,
+    month: u8,
+    before: Option<Payout>,
+    payout: Payout,
+) -> Result<(), ProtocolError> {
+    if let Err(e) = tx
+        .add_payout(&PayoutData {
+            day: date(year, month, payout.day)?,
+            user_id: payout.user_id,
+            amount: decimal("payout.amount", payout.amount)?,
+        })
+        .await
+    {
+        return Err(e.into());
+    }
+    let snapshot = |payout: Payout| json!({"year": year, "month": month, "payout": payout});
+    audit(
+        tx,
+        admin_id,
+        kind,
+        before.map(snapshot),
+        Some(snapshot(payout)),
+    )
+    .await
+}
+
+/// Records an admin or user action in the transaction that makes the change,
+/// `before` and `after` are snapshots of what changed
+async fn audit(
+    db: &impl Queries,
+    user_id: UserId,
+    kind: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<(), ProtocolError> {
+    let audit = AuditData {
+        id: 0,
+        user_id,
+        created_at: Utc::now(),
+        kind: kind.to_string(),
+        value_before: before.map(|v| v.to_string()),
+        value_after: after.map(|v| v.to_string()),
+    };
+    match db.add_audit(&audit).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds the workdays and sets the revenue and payouts, in the order of the file
+async fn write_import(tx: &impl Queries, import: &ImportData) -> DbResult<()> {
+    for schedule in &import.schedule {
+        tx.set_schedule(schedule, true).await?;
+    }
+    for revenue in &import.revenue {
+        tx.set_revenue(revenue).await?;
+    }
+    for payout in &import.payouts {
+        tx.add_payout(payout).await?;
+    }
+    Ok(())
+}
+
+fn pay_rate_info(rate: &PayRateData) -> PayRate {
+    PayRate {
+        user_id: rate.user_id,
+        year: rate.valid_from.year() as u16,
+        month: rate.valid_from.month() as u8,
+        day: rate.valid_from.day() as u8,
+        pay: float(rate.pay),
+        percent: float(rate.percent),
+    }
+}
+
+fn parse_rule(rule: &CommissionRuleData) -> Result<payroll::CommissionRule, ProtocolError> {
+    serde_json::from_str(&rule.rule).map_err(|e| ProtocolError::Unknown(e.to_string()))
+}
+
+fn commission_rule_info(rule: &CommissionRuleData) -> Result<CommissionRule, ProtocolError> {
+    let stored = parse_rule(rule)?;
+    Ok(CommissionRule {
+        user_id: rule.user_id,
+        year: rule.valid_from.year() as u16,
+        month: rule.valid_from.month() as u8,
+        day: rule.valid_from.day() as u8,
+        daily_pay: float(stored.daily_pay),
+        tiers: stored
+            .tiers
+            .into_iter()
+            .map(|t| CommissionTier {
+                from: float(t.from),
+                percent: float(t.percent),
+            })
+            .collect(),
+        without_percent: float(stored.without_percent),
+        daily_minimum: float(stored.daily_minimum),
+        weekend_multiplier: float(stored.weekend_multiplier),
+        holiday_multiplier: float(stored.holiday_multiplier),
+    })
+}
+
+/// The salaries a closed month was closed with
+fn read_snapshot(closed: &ClosedMonthData) -> Result<Vec<payroll::Payroll>, ProtocolError> {
+    let snapshot = serde_json::from_str::<Vec<payroll::Snapshot>>(&closed.snapshot)
+        .map_err(|e| ProtocolError::Unknown(e.to_string()))?;
+    snapshot
+        .into_iter()
+        .map(|s| payroll::Payroll::from_snapshot(s, closed.year, closed.month as u32))
+        .collect::<Option<_>>()
+        .ok_or_else(|| ProtocolError::Unknown("Снимок месяца повреждён".to_string()))
+}
+
+fn salary(p: &payroll::Payroll) -> Salary {
+    Salary {
+        id: p.user_id,
+        total: float(p.total),
+        paid: float(p.paid),
+        days_worked: p.working_days,
+        base_pay: float(p.base_pay),
+        balance: float(p.balance()),
+        commission: p
+            .commissions
+            .iter()
+            .map(|c| Commission {
+                day: c.day.day() as u8,
+                revenue: float(c.revenue),
+                workers: c.workers,
+                base_pay: float(c.base_pay),
+                multiplier: float(c.multiplier),
+                amount: float(c.amount),
+                top_up: float(c.top_up),
+            })
+            .collect(),
+    }
+}
+
+fn user_info(user: &UserData) -> User {
+    User {
+        id: user.id,
+        login: user.login.clone(),
+        name: user.name.clone(),
+        is_admin: user.is_admin,
+        is_worker: user.is_worker,
+        pay: float(user.pay),
+        percent: float(user.percent),
+    }
+}
+
+/// The protocol sends amounts as floats, they're only converted on the way out
+fn float(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+/// Years and months from the one of `from` to the one of `to`
+fn months(from: NaiveDate, to: NaiveDate) -> Vec<(u16, u8)> {
+    let mut months = Vec::new();
+    let mut first = from.with_day(1).unwrap_or(from);
+    while first <= to {
+        months.push((first.year() as u16, first.month() as u8));
+        first = first + Months::new(1);
+    }
+    months
+}
+
+fn protocol_date(day: NaiveDate) -> Date {
+    Date {
+        year: day.year() as u16,
+        month: day.month() as u8,
+        day: day.day() as u8,
+    }
+}
+
+fn worker_totals(totals: &report::Totals) -> Vec<WorkerTotal> {
+    totals
+        .workers
+        .iter()
+        .map(|(&id, w)| WorkerTotal {
+            id,
+            days_worked: w.days_worked,
+            earned: float(w.earned),
+            paid: float(w.paid),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_memory::DatabaseMemory;
+    use crate::schedule_policy::ScheduleViolation;
+
+    async fn add_user(
+        handler: &PravdaHandler<DatabaseMemory>,
+        login: &str,
+        is_admin: bool,
+        pay: f64,
+        percent: f64,
+    ) -> UserId {
+        let mut user = UserData {
+            id: 0,
+            login: login.to_string(),
+            name: login.to_uppercase(),
+            is_admin,
+            is_worker: !is_admin,
+            pay: decimal("pay", pay).unwrap(),
+            percent: decimal("percent", percent).unwrap(),
+            pwd_hash: "".to_string(),
+            pwd_salt: "".to_string(),
+            is_active: true,
+            deactivated_at: None,
+        };
+        user.set_password("password").unwrap();
+        handler.database.add_user(&user).await.unwrap().id
+    }
+
+    async fn login(handler: &PravdaHandler<DatabaseMemory>, login: &str) -> Option<String> {
+        let request = Request::User(UserRequest::Login {
+            login: login.to_string(),
+            password: "password".to_string(),
+        });
+        match handler.process(request, client(None)).await.unwrap() {
+            ResponseData::Login { token, .. } => Some(token),
+            _ => panic!("Expected login response"),
+        }
+    }
+
+    fn client(token: Option<String>) -> Client {
+        Client {
+            token,
+            user_agent: "test".to_string(),
+            address: None,
+        }
+    }
+
+    fn setup() -> PravdaHandler<DatabaseMemory> {
+        // Tests use dates long past
+        PravdaHandler::new(DatabaseMemory::new()).with_schedule_policy(SchedulePolicy {
+            edit_days_back: u32::MAX,
+            ..Default::default()
+        })
+    }
+
+    fn get_salaries(response: Response) -> HashMap<UserId, Salary> {
+        match response.unwrap() {
+            ResponseData::SalaryCalculation { salaries } => {
+                salaries.into_iter().map(|s| (s.id, s)).collect()
+            }
+            _ => panic!("Expected salary calculation"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let handler = setup();
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+
+        let response = handler
+            .process(
+                Request::User(UserRequest::Login {
+                    login: "worker".to_string(),
+                    password: "password".to_string(),
+                }),
+                client(None),
+            )
+            .await;
+        let token = match response {
+            Ok(ResponseData::Login { token, id: uid }) => {
+                assert_eq!(uid, id);
+                token
+            }
+            _ => panic!("Expected login response"),
+        };
+
+        let response = handler
+            .process(Request::User(UserRequest::GetUserInfo), client(Some(token)))
+            .await;
+        assert!(matches!(response, Ok(ResponseData::UserInfo(User { id: uid, .. })) if uid == id));
+    }
+
+    #[tokio::test]
+    async fn test_login_failed() {
+        let handler = setup();
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+
+        for (login, password) in [("worker", "wrong"), ("nobody", "password")] {
+            let response = handler
+                .process(
+                    Request::User(UserRequest::Login {
+                        login: login.to_string(),
+                        password: password.to_string(),
+                    }),
+                    client(None),
+                )
+                .await;
+            assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        }
+    }
+
+    async fn set_legacy_password(handler: &PravdaHandler<DatabaseMemory>, id: UserId) {
+        let mut user = handler
+            .database
+            .get_user(&UserSearch::Id(id))
+            .await
+            .unwrap()
+            .unwrap();
+        user.pwd_salt = utils::make_uuid();
+        user.pwd_hash = utils::sha3(format!("password#{}", user.pwd_salt));
+        handler.database.update_user(&user).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hash_upgraded_on_login() {
+        let handler = setup();
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        set_legacy_password(&handler, id).await;
+
+        login(&handler, "worker").await;
+        let user = handler.database.get_user(&UserSearch::Id(id)).await;
+        let user = user.unwrap().unwrap();
+        assert!(!user.needs_rehash());
+        assert!(user.check_password("password"));
+        login(&handler, "worker").await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hash_upgraded_on_change_password() {
+        let handler = setup();
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "worker").await;
+        set_legacy_password(&handler, id).await;
+
+        let change_password = |old_password: &str| {
+            Request::User(UserRequest::ChangePassword {
+                old_password: old_password.to_string(),
+                new_password: "new password".to_string(),
+            })
+        };
+        let response = handler
+            .process(change_password("wrong"), client(token.clone()))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        let response = handler
+            .process(change_password("password"), client(token))
+            .await;
+        assert!(matches!(response, Ok(ResponseData::PasswordChanged)));
+
+        let user = handler.database.get_user(&UserSearch::Id(id)).await;
+        let user = user.unwrap().unwrap();
+        assert!(!user.needs_rehash());
+        assert!(user.check_password("new password"));
+        assert!(!user.check_password("password"));
+    }
+
+    #[tokio::test]
+    async fn test_token_required() {
+        let handler = setup();
+        let request = || Request::User(UserRequest::GetUserInfo);
+        assert!(matches!(
+            handler.process(request(), client(None)).await,
+            Err(ProtocolError::Forbidden)
+        ));
+        assert!(matches!(
+            handler
+                .process(request(), client(Some(utils::make_uuid())))
+                .await,
+            Err(ProtocolError::UnknownToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let handler = setup();
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let phone = login(&handler, "worker").await;
+        let tablet = login(&handler, "worker").await;
+        let laptop = login(&handler, "worker").await;
+
+        let user_info = |token: &Option<String>| {
+            handler.process(
+                Request::User(UserRequest::GetUserInfo),
+                client(token.clone()),
+            )
+        };
+        for token in [&phone, &tablet, &laptop] {
+            assert!(user_info(token).await.is_ok());
+        }
+
+        let logout = |all| Request::User(UserRequest::Logout { all });
+        let response = handler.process(logout(false), client(phone.clone())).await;
+        assert!(matches!(response, Ok(ResponseData::LoggedOut)));
+        assert!(matches!(
+            user_info(&phone).await,
+            Err(ProtocolError::UnknownToken)
+        ));
+        assert!(user_info(&tablet).await.is_ok());
+
+        handler
+            .process(logout(true), client(tablet.clone()))
+            .await
+            .unwrap();
+        for token in [&tablet, &laptop] {
+            assert!(matches!(
+                user_info(token).await,
+                Err(ProtocolError::UnknownToken)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_expired() {
+        let handler = setup();
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "worker").await;
+
+        let token_hash = utils::sha3(token.as_ref().unwrap());
+        let expired = Utc::now() - Duration::minutes(1);
+        handler
+            .database
+            .touch_session(&token_hash, expired, expired)
+            .await
+            .unwrap();
+        let response = handler
+            .process(Request::User(UserRequest::GetUserInfo), client(token))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::UnknownToken)));
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_revokes_sessions() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+
+        handler
+            .process(
+                Request::Admin(AdminRequest::ResetPassword { id }),
+                client(admin_token),
+            )
+            .await
+            .unwrap();
+        let response = handler
+            .process(Request::User(UserRequest::GetUserInfo), client(token))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::UnknownToken)));
+    }
+
+    fn setup_password(login: &str, code: &str, password: &str) -> Request {
+        Request::User(UserRequest::SetupPassword {
+            login: login.to_string(),
+            code: code.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    fn login_request(login: &str, password: &str) -> Request {
+        Request::User(UserRequest::Login {
+            login: login.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_add_user_setup_code() {
+        // Wrong codes count as failed logins, but not enough to wait here
+        let handler = throttled_setup(10, 10);
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let token = login(&handler, "admin").await;
+
+        let new_user = User {
+            id: 0,
+            login: "new".to_string(),
+            name: "New".to_string(),
+            is_admin: false,
+            is_worker: true,
+            pay: 100.0,
+            percent: 5.0,
+        };
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::AddUser(new_user)),
+                client(token),
+            )
+            .await;
+        let code = match response {
+            Ok(ResponseData::SetupCode { code, .. }) => code,
+            _ => panic!("Expected setup code"),
+        };
+
+        for password in ["", "Qwer4321"] {
+            let response = handler
+                .process(login_request("new", password), client(None))
+                .await;
+            assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        }
+        let response = handler
+            .process(
+                setup_password("new", "WRONGCODE", "my secret"),
+                client(None),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        let response = handler
+            .process(setup_password("admin", &code, "my secret"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+
+        let response = handler
+            .process(
+                setup_password("new", &code.to_lowercase(), "my secret"),
+                client(None),
+            )
+            .await;
+        assert!(matches!(response, Ok(ResponseData::PasswordChanged)));
+        let response = handler
+            .process(login_request("new", "my secret"), client(None))
+            .await;
+        assert!(matches!(response, Ok(ResponseData::Login { .. })));
+
+        // Codes are single-use
+        let response = handler
+            .process(setup_password("new", &code, "other secret"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_setup_code() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "admin").await;
+
+        let reset = || Request::Admin(AdminRequest::ResetPassword { id });
+        let response = handler.process(reset(), client(token.clone())).await;
+        let old_code = match response {
+            Ok(ResponseData::SetupCode { id: uid, code }) if uid == id => code,
+            _ => panic!("Expected setup code"),
+        };
+        let response = handler
+            .process(login_request("worker", "password"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+
+        // A new reset replaces the previous code
+        let code = match handler.process(reset(), client(token.clone())).await {
+            Ok(ResponseData::SetupCode { code, .. }) => code,
+            _ => panic!("Expected setup code"),
+        };
+        let response = handler
+            .process(
+                setup_password("worker", &old_code, "my secret"),
+                client(None),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        let response = handler
+            .process(setup_password("worker", &code, "my secret"), client(None))
+            .await;
+        assert!(matches!(response, Ok(ResponseData::PasswordChanged)));
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::ResetPassword { id: id + 1 }),
+                client(token),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_setup_code_expired() {
+        let handler = setup();
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        handler
+            .database
+            .set_setup_code(&SetupCodeData {
+                user_id: id,
+                code_hash: utils::sha3("CODE"),
+                expires_at: Utc::now() - Duration::minutes(1),
+            })
+            .await
+            .unwrap();
+
+        let response = handler
+            .process(setup_password("worker", "CODE", "my secret"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_setup_code_throttled() {
+        let handler = throttled_setup(10, 3);
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        handler
+            .database
+            .set_setup_code(&SetupCodeData {
+                user_id: id,
+                code_hash: utils::sha3("CODE"),
+                expires_at: Utc::now() + Duration::days(1),
+            })
+            .await
+            .unwrap();
+
+        let response = handler
+            .process(setup_password("worker", "CODE", "short"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Validation { .. })));
+        for code in ["AAAA", "BBBB", "CCCC"] {
+            let response = handler
+                .process(setup_password("worker", code, "my secret"), client(None))
+                .await;
+            assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        }
+        // Locked out, even the right code doesn't work now
+        let response = handler
+            .process(setup_password("worker", "CODE", "my secret"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        assert!(handler.database.get_setup_code(id).await.unwrap().is_some());
+        let lockouts = handler.database.get_lockouts(None).await.unwrap();
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].login.as_deref(), Some("worker"));
+    }
+
+    fn throttled_setup(backoff_after: u32, lockout_after: u32) -> PravdaHandler<DatabaseMemory> {
+        setup().with_login_throttle(ThrottleConfig {
+            backoff_after,
+            backoff_base: Duration::hours(1),
+            backoff_max: Duration::hours(1),
+            lockout_after,
+            lockout_duration: Duration::hours(1),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_login_backoff() {
+        let handler = throttled_setup(1, 10);
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+
+        let response = handler
+            .process(login_request("worker", "wrong"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        let response = handler
+            .process(login_request("worker", "password"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_login_resets_address() {
+        let handler = throttled_setup(3, 10);
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let attempt = |login: &str, password: &str| {
+            let from = Client {
+                address: Some("10.0.0.1".parse().unwrap()),
+                ..client(None)
+            };
+            handler.process(login_request(login, password), from)
+        };
+
+        for _ in 0..2 {
+            assert!(attempt("worker", "wrong").await.is_err());
+        }
+        assert!(attempt("admin", "password").await.is_ok());
+        // Failures before the successful login don't count for the address
+        assert!(attempt("nobody", "wrong").await.is_err());
+        assert!(attempt("admin", "password").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_login_lockout() {
+        let handler = throttled_setup(10, 3);
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+
+        let from = |address: &str| Client {
+            address: Some(address.parse().unwrap()),
+            ..client(None)
+        };
+        for _ in 0..3 {
+            let response = handler
+                .process(login_request("worker", "wrong"), from("10.0.0.1"))
+                .await;
+            assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        }
+        for address in ["10.0.0.1", "10.0.0.2"] {
+            let response = handler
+                .process(login_request("worker", "password"), from(address))
+                .await;
+            assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+        }
+        // The address is locked out for other logins as well
+        let response = handler
+            .process(login_request("admin", "password"), from("10.0.0.1"))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetLockouts),
+                client(admin_token.clone()),
+            )
+            .await;
+        let lockouts = match response {
+            Ok(ResponseData::Lockouts(lockouts)) => lockouts,
+            _ => panic!("Expected lockouts"),
+        };
+        assert_eq!(lockouts.len(), 2);
+        assert!(lockouts.iter().all(|l| l.cleared_at.is_none()));
+
+        for lockout in lockouts {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::ClearLockout { id: lockout.id }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        let response = handler
+            .process(login_request("worker", "password"), from("10.0.0.1"))
+            .await;
+        assert!(matches!(response, Ok(ResponseData::Login { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_admin_only() {
+        let handler = setup();
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "worker").await;
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetUsers),
+                client(token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+        // Refused before anything about the request is checked
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetRevenue {
+                    year: 2023,
+                    month: 13,
+                }),
+                client(token),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn test_set_workday() {
+        let handler = setup();
+        let id = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "worker").await;
+
+        let set_workday = |day, is_working| {
+            Request::User(UserRequest::SetWorkday {
+                year: 2023,
+                month: 2,
+                day,
+                is_working,
+            })
+        };
+
+        handler
+            .process(set_workday(3, true), client(token.clone()))
+            .await
+            .unwrap();
+        let response = handler
+            .process(set_workday(28, true), client(token.clone()))
+            .await;
+        match response {
+            Ok(ResponseData::Schedule { schedule, .. }) => {
+                let days = &schedule[&id];
+                assert_eq!(days.len(), 29);
+                assert_eq!(days.iter().enumerate().filter(|(_, w)| **w).count(), 2);
+                assert!(days[3] && days[28]);
+            }
+            _ => panic!("Expected schedule"),
+        }
+
+        handler
+            .process(set_workday(3, false), client(token.clone()))
+            .await
+            .unwrap();
+        let response = handler.process(set_workday(28, false), client(token)).await;
+        match response {
+            Ok(ResponseData::Schedule { schedule, .. }) => assert!(schedule.is_empty()),
+            _ => panic!("Expected schedule"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_revenue() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let token = login(&handler, "admin").await;
+
+        for with_percent in [100.0, 250.0] {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 5,
+                        revenue: Revenue {
+                            day: 7,
+                            with_percent,
+                            without_percent: 30.0,
+                        },
+                    }),
+                    client(token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetRevenue {
+                    year: 2023,
+                    month: 5,
+                }),
+                client(token),
+            )
+            .await;
+        match response {
+            Ok(ResponseData::Revenue { revenue, .. }) => {
+                assert_eq!(revenue.len(), 1);
+                assert_eq!(revenue[0].day, 7);
+                assert_eq!(revenue[0].with_percent, 250.0);
+                assert_eq!(revenue[0].without_percent, 30.0);
+            }
+            _ => panic!("Expected revenue"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_salary_calculation() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let first = add_user(&handler, "first", false, 1000.0, 10.0).await;
+        let second = add_user(&handler, "second", false, 500.0, 20.0).await;
+        let admin_token = login(&handler, "admin").await;
+
+        for (user, days) in [("first", vec![1, 2, 3]), ("second", vec![1])] {
+            let token = login(&handler, user).await;
+            for day in days {
+                handler
+                    .process(
+                        Request::User(UserRequest::SetWorkday {
+                            year: 2023,
+                            month: 6,
+                            day,
+                            is_working: true,
+                        }),
+                        client(token.clone()),
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Day 3 has no revenue entry, so it doesn't count
+        for (day, with_percent) in [(1, 1000.0), (2, 200.0)] {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 6,
+                        revenue: Revenue {
+                            day,
+                            with_percent,
+                            without_percent: 5000.0,
+                        },
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetSalaryCalculation {
+                    year: 2023,
+                    month: 6,
+                }),
+                client(admin_token.clone()),
+            )
+            .await;
+        let salaries = get_salaries(response);
+        assert_eq!(salaries.len(), 2);
+        assert_eq!(salaries[&first].total, 1000.0 * 2.0 + (500.0 + 200.0) * 0.1);
+        assert_eq!(salaries[&second].total, 500.0 + 500.0 * 0.2);
+        assert_eq!(salaries[&first].paid, 0.0);
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::AddPayout {
+                    year: 2023,
+                    month: 6,
+                    payout: Payout {
+                        day: 15,
+                        user_id: second,
+                        amount: 300.0,
+                    },
+                }),
+                client(admin_token),
+            )
+            .await;
+        let salaries = get_salaries(response);
+        assert_eq!(salaries[&second].paid, 300.0);
+        assert_eq!(salaries[&second].total, 600.0);
+        assert_eq!(salaries[&second].balance, 300.0);
+        assert_eq!(salaries[&first].paid, 0.0);
+        assert_eq!(salaries[&first].balance, salaries[&first].total);
+    }
+
+    #[tokio::test]
+    async fn test_deactivate_user() {
+        let handler = setup();
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+        let set_workday = |day| {
+            Request::Admin(AdminRequest::SetWorkday {
+                user_id: worker,
+                year: 2023,
+                month: 6,
+                day,
+                is_working: true,
+            })
+        };
+        handler
+            .process(set_workday(1), client(admin_token.clone()))
+            .await
+            .unwrap();
+        handler
+            .process(
+                Request::Admin(AdminRequest::SetRevenue {
+                    year: 2023,
+                    month: 6,
+                    revenue: Revenue {
+                        day: 1,
+                        with_percent: 0.0,
+                        without_percent: 5000.0,
+                    },
+                }),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::DeactivateUser { id: admin }),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Validation { .. })));
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::DeactivateUser { id: worker }),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(
+            matches!(response, Ok(ResponseData::Users(users)) if users.iter().all(|u| u.id != worker))
+        );
+
+        // Sessions are revoked and logging in again fails
+        let response = handler
+            .process(Request::User(UserRequest::GetUserInfo), client(token))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::UnknownToken)));
+        let response = handler
+            .process(login_request("worker", "password"), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::LoginFailed)));
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetDeactivatedUsers),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(
+            matches!(response, Ok(ResponseData::Users(users)) if users.len() == 1 && users[0].id == worker)
+        );
+        let response = handler
+            .process(set_workday(2), client(admin_token.clone()))
+            .await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "user_id")
+        );
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetPayRate(PayRate {
+                    user_id: worker,
+                    year: 2023,
+                    month: 7,
+                    day: 1,
+                    pay: 2000.0,
+                    percent: 0.0,
+                })),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "user_id")
+        );
+
+        // Past months still count the days they worked, months without them leave them out
+        let salaries = |month| {
+            handler.process(
+                Request::Admin(AdminRequest::GetSalaryCalculation { year: 2023, month }),
+                client(admin_token.clone()),
+            )
+        };
+        assert_eq!(get_salaries(salaries(6).await)[&worker].total, 1000.0);
+        assert!(!get_salaries(salaries(7).await).contains_key(&worker));
+
+        handler
+            .process(
+                Request::Admin(AdminRequest::ReactivateUser { id: worker }),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        assert!(login(&handler, "worker").await.is_some());
+        handler
+            .process(set_workday(2), client(admin_token))
+            .await
+            .unwrap();
+        let user = handler
+            .database
+            .get_user(&UserSearch::Id(worker))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.is_active && user.deactivated_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_salary_breakdown() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let first = add_user(&handler, "first", false, 1000.0, 10.0).await;
+        let second = add_user(&handler, "second", false, 500.0, 20.0).await;
+        let admin_token = login(&handler, "admin").await;
+
+        for (user, days) in [("first", vec![1, 2]), ("second", vec![2])] {
+            let token = login(&handler, user).await;
+            for day in days {
+                handler
+                    .process(
+                        Request::User(UserRequest::SetWorkday {
+                            year: 2023,
+                            month: 6,
+                            day,
+                            is_working: true,
+                        }),
+                        client(token.clone()),
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+        for (day, with_percent) in [(1, 1000.0), (2, 600.0)] {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 6,
+                        revenue: Revenue {
+                            day,
+                            with_percent,
+                            without_percent: 0.0,
+                        },
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::AddPayout {
+                    year: 2023,
+                    month: 6,
+                    payout: Payout {
+                        day: 15,
+                        user_id: first,
+                        amount: 1500.0,
+                    },
+                }),
+                client(admin_token),
+            )
+            .await;
+        let salaries = get_salaries(response);
+
+        let salary = &salaries[&first];
+        assert_eq!(salary.days_worked, 2);
+        assert_eq!(salary.base_pay, 2000.0);
+        let commission = salary
+            .commission
+            .iter()
+            .map(|c| (c.day, c.revenue, c.workers, c.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(commission, [(1, 1000.0, 1, 100.0), (2, 600.0, 2, 30.0)]);
+        assert_eq!(salary.total, 2130.0);
+        assert_eq!(salary.paid, 1500.0);
+        assert_eq!(salary.balance, 630.0);
+
+        let salary = &salaries[&second];
+        assert_eq!(salary.days_worked, 1);
+        assert_eq!(salary.commission.len(), 1);
+        assert_eq!(salary.commission[0].amount, 60.0);
+        assert_eq!(salary.total, 560.0);
+        assert_eq!(salary.balance, 560.0);
+    }
+
+    #[tokio::test]
+    async fn test_pay_rates() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 10.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+
+        for day in [1, 10] {
+            handler
+                .process(
+                    Request::User(UserRequest::SetWorkday {
+                        year: 2023,
+                        month: 6,
+                        day,
+                        is_working: true,
+                    }),
+                    client(token.clone()),
+                )
+                .await
+                .unwrap();
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 6,
+                        revenue: Revenue {
+                            day,
+                            with_percent: 1000.0,
+                            without_percent: 0.0,
+                        },
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        let rate = |month, day, pay, percent| PayRate {
+            user_id: worker,
+            year: 2023,
+            month,
+            day,
+            pay,
+            percent,
+        };
+        for rate in [rate(1, 1, 500.0, 5.0), rate(6, 5, 2000.0, 20.0)] {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetPayRate(rate)),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetPayRate(rate(6, 5, -1.0, 20.0))),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "pay"));
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetPayRate(PayRate {
+                    user_id: -1,
+                    ..rate(6, 5, 2000.0, 20.0)
+                })),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetPayRates { user_id: worker }),
+                client(admin_token.clone()),
+            )
+            .await;
+        match response.unwrap() {
+            ResponseData::PayRates { user_id, rates } => {
+                assert_eq!(user_id, worker);
+                let rates = rates
+                    .iter()
+                    .map(|r| (r.year, r.month, r.day, r.pay))
+                    .collect::<Vec<_>>();
+                assert_eq!(rates, [(2023, 1, 1, 500.0), (2023, 6, 5, 2000.0)]);
+            }
+            _ => panic!("Expected pay rates"),
+        }
+
+        let get_total = || async {
+            let response = handler
+                .process(
+                    Request::Admin(AdminRequest::GetSalaryCalculation {
+                        year: 2023,
+                        month: 6,
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await;
+            get_salaries(response)[&worker].total
+        };
+        let total = 500.0 + 50.0 + 2000.0 + 200.0;
+        assert_eq!(get_total().await, total);
+
+        // The user entry follows the rate in effect today
+        let user = handler
+            .database
+            .get_user(&UserSearch::Id(worker))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((float(user.pay), float(user.percent)), (2000.0, 20.0));
+
+        // A raise today doesn't change past months
+        let mut user = handler
+            .database
+            .get_user(&UserSearch::Id(worker))
+            .await
+            .unwrap()
+            .map(|u| user_info(&u))
+            .unwrap();
+        user.pay = 3000.0;
+        handler
+            .process(
+                Request::Admin(AdminRequest::UpdateUser(user)),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(get_total().await, total);
+
+        let delete = |month, day| {
+            Request::Admin(AdminRequest::DeletePayRate {
+                user_id: worker,
+                year: 2023,
+                month,
+                day,
+            })
+        };
+        handler
+            .process(delete(6, 5), client(admin_token.clone()))
+            .await
+            .unwrap();
+        assert_eq!(get_total().await, 2.0 * (500.0 + 50.0));
+        let response = handler
+            .process(delete(6, 5), client(admin_token.clone()))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+
+        let user = handler
+            .database
+            .get_user(&UserSearch::Id(worker))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((float(user.pay), float(user.percent)), (3000.0, 20.0));
+
+        // The rate from today on is the last one left
+        handler
+            .process(delete(1, 1), client(admin_token.clone()))
+            .await
+            .unwrap();
+        let today = utils::today();
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::DeletePayRate {
+                    user_id: worker,
+                    year: today.year() as u16,
+                    month: today.month() as u8,
+                    day: today.day() as u8,
+                }),
+                client(admin_token),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "day"));
+    }
+
+    #[tokio::test]
+    async fn test_schedule_policy() {
+        let handler =
+            PravdaHandler::new(DatabaseMemory::new()).with_schedule_policy(SchedulePolicy {
+                edit_days_back: 0,
+                max_workers_per_day: Some(1),
+                ..Default::default()
+            });
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let first = add_user(&handler, "first", false, 0.0, 0.0).await;
+        add_user(&handler, "second", false, 0.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let first_token = login(&handler, "first").await;
+        let second_token = login(&handler, "second").await;
+
+        let today = utils::today();
+        let yesterday = today.pred_opt().unwrap();
+        let set_workday = |date: NaiveDate, is_working| {
+            Request::User(UserRequest::SetWorkday {
+                year: date.year() as u16,
+                month: date.month() as u8,
+                day: date.day() as u8,
+                is_working,
+            })
+        };
+        let set_workday_for = |user_id, date: NaiveDate| {
+            Request::Admin(AdminRequest::SetWorkday {
+                user_id,
+                year: date.year() as u16,
+                month: date.month() as u8,
+                day: date.day() as u8,
+                is_working: true,
+            })
+        };
+        let error = |response: Response| match response {
+            Err(ProtocolError::Validation { field, message }) if field == "day" => message,
+            _ => panic!("Expected a validation error"),
+        };
+
+        let response = handler
+            .process(set_workday(yesterday, true), client(first_token.clone()))
+            .await;
+        assert_eq!(error(response), ScheduleViolation::DayLocked.message());
+        handler
+            .process(set_workday(today, true), client(first_token.clone()))
+            .await
+            .unwrap();
+        let response = handler
+            .process(set_workday(today, true), client(second_token.clone()))
+            .await;
+        assert_eq!(error(response), ScheduleViolation::DayFull.message());
+        let response = handler
+            .process(set_workday(today, true), client(admin_token.clone()))
+            .await;
+        assert_eq!(error(response), ScheduleViolation::NotWorker.message());
+
+        // Admins may change past days, but not overfill one or schedule non-workers
+        handler
+            .process(
+                set_workday_for(first, yesterday),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        let response = handler
+            .process(
+                set_workday_for(admin, yesterday),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert_eq!(error(response), ScheduleViolation::NotWorker.message());
+        let response = handler
+            .process(set_workday_for(-1, yesterday), client(admin_token.clone()))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+        let response = handler
+            .process(set_workday(yesterday, false), client(first_token.clone()))
+            .await;
+        assert_eq!(error(response), ScheduleViolation::DayLocked.message());
+
+        // Leaving a full day is fine
+        handler
+            .process(set_workday(today, false), client(first_token))
+            .await
+            .unwrap();
+        handler
+            .process(set_workday(today, true), client(second_token))
+            .await
+            .unwrap();
+
+        let audit_log = handler
+            .database
+            .get_audit_log(&AuditFilter {
+                kind: Some("SetWorkday".to_string()),
+                limit: 100,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let authors = audit_log.iter().map(|a| a.user_id).collect::<Vec<_>>();
+        assert_eq!(authors.len(), 4);
+        assert_eq!(authors[2], admin);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests_rejected() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+
+        let field = |response: Response| match response {
+            Err(ProtocolError::Validation { field, .. }) => field,
+            _ => panic!("Expected a validation error"),
+        };
+        for (month, day, invalid) in [(13, 1, "month"), (2, 30, "day"), (6, 0, "day")] {
+            let response = handler
+                .process(
+                    Request::User(UserRequest::SetWorkday {
+                        year: 2023,
+                        month,
+                        day,
+                        is_working: true,
+                    }),
+                    client(token.clone()),
+                )
+                .await;
+            assert_eq!(field(response), invalid);
+        }
+        let response = handler
+            .process(
+                Request::User(UserRequest::GetSchedule {
+                    year: 2023,
+                    month: 13,
+                }),
+                client(token),
+            )
+            .await;
+        assert_eq!(field(response), "month");
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetRevenue {
+                    year: 2023,
+                    month: 6,
+                    revenue: Revenue {
+                        day: 1,
+                        with_percent: 100.0,
+                        without_percent: -100.0,
+                    },
+                }),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert_eq!(field(response), "revenue.without_percent");
+
+        assert!(handler
+            .database
+            .get_schedule(NaiveDate::MIN, NaiveDate::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(handler
+            .database
+            .get_revenue(NaiveDate::MIN, NaiveDate::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+        let audit_log = handler
+            .database
+            .get_audit_log(&AuditFilter {
+                limit: 100,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(audit_log.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_month() {
+        let handler = setup();
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 10.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+
+        let set_workday = |month, day| {
+            Request::User(UserRequest::SetWorkday {
+                year: 2023,
+                month,
+                day,
+                is_working: true,
+            })
+        };
+        let set_revenue = |month| {
+            Request::Admin(AdminRequest::SetRevenue {
+                year: 2023,
+                month,
+                revenue: Revenue {
+                    day: 1,
+                    with_percent: 1000.0,
+                    without_percent: 0.0,
+                },
+            })
+        };
+        let add_payout = |month| {
+            Request::Admin(AdminRequest::AddPayout {
+                year: 2023,
+                month,
+                payout: Payout {
+                    day: 1,
+                    user_id: worker,
+                    amount: 100.0,
+                },
+            })
+        };
+        handler
+            .process(set_workday(6, 1), client(token.clone()))
+            .await
+            .unwrap();
+        handler
+            .process(set_revenue(6), client(admin_token.clone()))
+            .await
+            .unwrap();
+
+        let close = Request::Admin(AdminRequest::CloseMonth {
+            year: 2023,
+            month: 6,
+        });
+        let response = handler
+            .process(close.clone(), client(admin_token.clone()))
+            .await;
+        assert_eq!(get_salaries(response)[&worker].total, 1100.0);
+        // Stored as decimals, not as the floats the protocol sends
+        let closed = handler.database.get_closed_months(2023).await.unwrap();
+        let snapshot: Value = serde_json::from_str(&closed[0].snapshot).unwrap();
+        assert_eq!(snapshot[0]["total"], "1100");
+        let response = handler.process(close, client(admin_token.clone())).await;
+        assert!(matches!(
+            response,
+            Err(ProtocolError::MonthClosed {
+                year: 2023,
+                month: 6
+            })
+        ));
+
+        for (request, token) in [
+            (set_workday(6, 2), &token),
+            (set_revenue(6), &admin_token),
+            (add_payout(6), &admin_token),
+            (
+                Request::Admin(AdminRequest::DeletePayout {
+                    year: 2023,
+                    month: 6,
+                    day: 1,
+                    user_id: worker,
+                }),
+                &admin_token,
+            ),
+        ] {
+            let response = handler.process(request, client(token.clone())).await;
+            assert!(matches!(response, Err(ProtocolError::MonthClosed { .. })));
+        }
+        // Other months stay open
+        handler
+            .process(set_workday(7, 1), client(token.clone()))
+            .await
+            .unwrap();
+        handler
+            .process(add_payout(7), client(admin_token.clone()))
+            .await
+            .unwrap();
+
+        // Later rate changes don't touch the closed month
+        handler
+            .process(
+                Request::Admin(AdminRequest::SetPayRate(PayRate {
+                    user_id: worker,
+                    year: 2023,
+                    month: 1,
+                    day: 1,
+                    pay: 2000.0,
+                    percent: 10.0,
+                })),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        let get_salaries_june = || async {
+            let response = handler
+                .process(
+                    Request::Admin(AdminRequest::GetSalaryCalculation {
+                        year: 2023,
+                        month: 6,
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await;
+            get_salaries(response)
+        };
+        assert_eq!(get_salaries_june().await[&worker].total, 1100.0);
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::GetClosedMonths { year: 2023 }),
+                client(admin_token.clone()),
+            )
+            .await;
+        match response.unwrap() {
+            ResponseData::ClosedMonths { year, months } => {
+                assert_eq!(year, 2023);
+                assert_eq!(months.len(), 1);
+                assert_eq!((months[0].month, months[0].closed_by), (6, admin));
+            }
+            _ => panic!("Expected closed months"),
+        }
+
+        let reopen = Request::Admin(AdminRequest::ReopenMonth {
+            year: 2023,
+            month: 6,
+        });
+        let response = handler
+            .process(reopen.clone(), client(admin_token.clone()))
+            .await;
+        assert!(matches!(
+            response,
+            Ok(ResponseData::ClosedMonths { months, .. }) if months.is_empty()
+        ));
+        let response = handler.process(reopen, client(admin_token.clone())).await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "month")
+        );
+        assert_eq!(get_salaries_june().await[&worker].total, 2100.0);
+        handler
+            .process(set_workday(6, 2), client(token))
+            .await
+            .unwrap();
+
+        let audit_log = handler
+            .database
+            .get_audit_log(&AuditFilter {
+                user_id: Some(admin),
+                limit: 100,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let kinds = audit_log
+            .iter()
+            .map(|a| a.kind.as_str())
+            .filter(|k| k.ends_with("Month"))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["ReopenMonth", "CloseMonth"]);
+        let reopened: Value =
+            serde_json::from_str(audit_log[0].value_before.as_ref().unwrap()).unwrap();
+        assert_eq!(reopened["salaries"][0]["total"], "1100");
+    }
+
+    fn get_report(response: Response) -> Report {
+        match response.unwrap() {
+            ResponseData::Report(report) => report,
+            _ => panic!("Expected report"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let first = add_user(&handler, "first", false, 1000.0, 10.0).await;
+        let second = add_user(&handler, "second", false, 500.0, 20.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let admin = |request| handler.process(Request::Admin(request), client(admin_token.clone()));
+
+        for (month, day, with_percent) in [
+            (5, 31, 400.0),
+            (6, 30, 1000.0),
+            (7, 1, 200.0),
+            (8, 10, 300.0),
+        ] {
+            admin(AdminRequest::SetWorkday {
+                user_id: first,
+                year: 2023,
+                month,
+                day,
+                is_working: true,
+            })
+            .await
+            .unwrap();
+            admin(AdminRequest::SetRevenue {
+                year: 2023,
+                month,
+                revenue: Revenue {
+                    day,
+                    with_percent,
+                    without_percent: 100.0,
+                },
+            })
+            .await
+            .unwrap();
+        }
+        admin(AdminRequest::AddPayout {
+            year: 2023,
+            month: 7,
+            payout: Payout {
+                day: 5,
+                user_id: second,
+                amount: 250.5,
+            },
+        })
+        .await
+        .unwrap();
+
+        // June keeps its snapshot after the rate changes, July doesn't
+        admin(AdminRequest::CloseMonth {
+            year: 2023,
+            month: 6,
+        })
+        .await
+        .unwrap();
+        admin(AdminRequest::SetPayRate(PayRate {
+            user_id: first,
+            year: 2023,
+            month: 6,
+            day: 1,
+            pay: 2000.0,
+            percent: 10.0,
+        }))
+        .await
+        .unwrap();
+
+        let date = |month, day| Date {
+            year: 2023,
+            month,
+            day,
+        };
+        let report = get_report(
+            admin(AdminRequest::GetReport {
+                from: date(6, 15),
+                to: date(7, 31),
+            })
+            .await,
+        );
+        assert_eq!(report.with_percent, 1200.0);
+        assert_eq!(report.without_percent, 200.0);
+        assert_eq!(report.workers.len(), 2);
+        assert_eq!(report.workers[0].id, first);
+        assert_eq!(report.workers[0].days_worked, 2);
+        assert_eq!(report.workers[0].earned, (1000.0 + 100.0) + (2000.0 + 20.0));
+        assert_eq!(report.workers[0].paid, 0.0);
+        assert_eq!(report.workers[1].id, second);
+        assert_eq!(report.workers[1].days_worked, 0);
+        assert_eq!(report.workers[1].paid, 250.5);
+        let months = report
+            .months
+            .iter()
+            .map(|m| (m.month, m.with_percent, m.workers.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(months, [(6, 1000.0, 1), (7, 200.0, 2)]);
+        assert_eq!(report.months[0].workers[0].earned, 1100.0);
+
+        let report = get_report(admin(AdminRequest::GetYearToDateReport { year: 2023 }).await);
+        assert_eq!((report.from.month, report.from.day), (1, 1));
+        assert_eq!((report.to.month, report.to.day), (12, 31));
+        assert_eq!(report.months.len(), 12);
+        assert_eq!(report.with_percent, 1900.0);
+        assert_eq!(report.workers[0].days_worked, 4);
+
+        let response = admin(AdminRequest::GetYearToDateReport { year: 9999 }).await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "year")
+        );
+        let response = admin(AdminRequest::GetReport {
+            from: date(7, 1),
+            to: date(6, 30),
+        })
+        .await;
+        assert!(matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "to"));
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 10.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let worker_token = login(&handler, "worker").await;
+        let admin = |request| handler.process(Request::Admin(request), client(admin_token.clone()));
+
+        admin(AdminRequest::SetWorkday {
+            user_id: worker,
+            year: 2023,
+            month: 6,
+            day: 30,
+            is_working: true,
+        })
+        .await
+        .unwrap();
+        admin(AdminRequest::SetRevenue {
+            year: 2023,
+            month: 6,
+            revenue: Revenue {
+                day: 30,
+                with_percent: 1000.5,
+                without_percent: 100.0,
+            },
+        })
+        .await
+        .unwrap();
+        admin(AdminRequest::AddPayout {
+            year: 2023,
+            month: 7,
+            payout: Payout {
+                day: 1,
+                user_id: worker,
+                amount: 500.0,
+            },
+        })
+        .await
+        .unwrap();
+
+        let request = |format, dataset| ExportRequest {
+            from: "2023-06-01".to_string(),
+            to: "2023-07-31".to_string(),
+            format,
+            dataset,
+        };
+        let csv = |dataset| {
+            let request = request(export::Format::Csv, Some(dataset));
+            let token = admin_token.clone();
+            async {
+                let file = handler.export(request, client(token)).await.unwrap();
+                assert_eq!(file.content_type, "text/csv; charset=utf-8");
+                String::from_utf8(file.data).unwrap()
+            }
+        };
+        let schedule = csv(export::Dataset::Schedule).await;
+        assert!(schedule.ends_with("Дата,ID,Сотрудник\n2023-06-30,2,WORKER\n"));
+        let revenue = csv(export::Dataset::Revenue).await;
+        assert!(revenue.ends_with("\n2023-06-30,1000.5,100\n"));
+        let payouts = csv(export::Dataset::Payouts).await;
+        assert!(payouts.ends_with("\n2023-07-01,2,WORKER,500\n"));
+
+        // The same numbers as the salary calculation, a row per month
+        let salaries = csv(export::Dataset::Salaries).await;
+        let salary = get_salaries(
+            admin(AdminRequest::GetSalaryCalculation {
+                year: 2023,
+                month: 6,
+            })
+            .await,
+        )
+        .remove(&worker)
+        .unwrap();
+        assert_eq!(salary.total, 1100.05);
+        let lines = salaries.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "2023,6,2,WORKER,1,1000,1100.05,0,1100.05",
+                "2023,7,2,WORKER,0,0,0,500,-500",
+            ]
+        );
+
+        let file = handler
+            .export(
+                request(export::Format::Xlsx, None),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        assert!(file.name.ends_with(".xlsx"));
+        assert!(file.data.starts_with(b"PK"));
+
+        let response = handler
+            .export(
+                request(export::Format::Csv, None),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "dataset")
+        );
+        let response = handler
+            .export(
+                request(export::Format::Xlsx, None),
+                client(worker_token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+        let response = handler
+            .export(request(export::Format::Xlsx, None), client(None))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 10.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let worker_token = login(&handler, "worker").await;
+        let import = |dataset, dry_run, file: &'static str, token: Option<String>| {
+            handler.import(
+                ImportRequest { dataset, dry_run },
+                file.as_bytes(),
+                client(token),
+            )
+        };
+        let changes = |response: Response| match response.unwrap() {
+            ResponseData::Import(result) => result
+                .changes
+                .into_iter()
+                .map(|c| (c.line, c.action))
+                .collect::<Vec<_>>(),
+            _ => panic!("Expected import result"),
+        };
+        handler
+            .process(
+                Request::Admin(AdminRequest::SetRevenue {
+                    year: 2021,
+                    month: 3,
+                    revenue: Revenue {
+                        day: 2,
+                        with_percent: 100.0,
+                        without_percent: 0.0,
+                    },
+                }),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+
+        let revenue = "date,with_percent,without_percent\n\
+                       2021-03-01,1000.50,100\n\
+                       2021-03-02,100,0\n\
+                       2021-03-31,200,0\n";
+        let response = import(import::Dataset::Revenue, true, revenue, admin_token.clone()).await;
+        assert_eq!(
+            changes(response),
+            [
+                (2, ImportAction::Add),
+                (3, ImportAction::Keep),
+                (4, ImportAction::Add)
+            ]
+        );
+        let march = date(2021, 3, 1).unwrap();
+        let april = date(2021, 4, 1).unwrap();
+        let stored = handler.database.get_revenue(march, april).await.unwrap();
+        assert_eq!(stored.len(), 1);
+
+        import(
+            import::Dataset::Revenue,
+            false,
+            revenue,
+            admin_token.clone(),
+        )
+        .await
+        .unwrap();
+        let stored = handler.database.get_revenue(march, april).await.unwrap();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[0].with_percent, Decimal::new(100050, 2));
+
+        let schedule = "date,login\n2021-03-01,worker\n";
+        import(
+            import::Dataset::Schedule,
+            false,
+            schedule,
+            admin_token.clone(),
+        )
+        .await
+        .unwrap();
+        let salaries = get_salaries(
+            handler
+                .process(
+                    Request::Admin(AdminRequest::GetSalaryCalculation {
+                        year: 2021,
+                        month: 3,
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await,
+        );
+        assert_eq!(salaries[&worker].total, 1000.0 + 100.05);
+
+        // A bad line anywhere keeps the whole file out
+        let payouts = "date,login,amount\n2021-03-05,worker,300\n2021-03-06,nobody,300\n";
+        let response = import(
+            import::Dataset::Payouts,
+            false,
+            payouts,
+            admin_token.clone(),
+        )
+        .await;
+        assert!(
+            matches!(response, Err(ProtocolError::Validation { field, .. }) if field == "lines[3].login")
+        );
+        assert!(handler
+            .database
+            .get_payouts(march, april)
+            .await
+            .unwrap()
+            .is_empty());
+
+        handler
+            .process(
+                Request::Admin(AdminRequest::CloseMonth {
+                    year: 2021,
+                    month: 3,
+                }),
+                client(admin_token.clone()),
+            )
+            .await
+            .unwrap();
+        let response = import(
+            import::Dataset::Schedule,
+            true,
+            schedule,
+            admin_token.clone(),
+        )
+        .await;
+        assert!(matches!(
+            response,
+            Err(ProtocolError::MonthClosed {
+                year: 2021,
+                month: 3
+            })
+        ));
+        let response = import(import::Dataset::Schedule, true, schedule, worker_token).await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+
+        let audit_log = handler
+            .database
+            .get_audit_log(&AuditFilter {
+                kind: Some("Import".to_string()),
+                limit: 10,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(audit_log.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_commission_rules() {
+        let handler = setup();
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 1000.0, 10.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let token = login(&handler, "worker").await;
+
+        // Thursday the 1st, Saturday the 3rd and Monday the 12th
+        for day in [1, 3, 12] {
+            handler
+                .process(
+                    Request::User(UserRequest::SetWorkday {
+                        year: 2023,
+                        month: 6,
+                        day,
+                        is_working: true,
+                    }),
+                    client(token.clone()),
+                )
+                .await
+                .unwrap();
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 6,
+                        revenue: Revenue {
+                            day,
+                            with_percent: 1000.0,
+                            without_percent: 5000.0,
+                        },
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        let holiday = Holiday {
+            year: 2023,
+            month: 6,
+            day: 12,
+            name: "Russia Day".to_string(),
+        };
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetHoliday(holiday)),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(matches!(
+            response,
+            Ok(ResponseData::Holidays { year: 2023, holidays }) if holidays.len() == 1
+        ));
+
+        let rule = |user_id, tiers: &[(f64, f64)]| CommissionRule {
+            user_id,
+            year: 2023,
+            month: 6,
+            day: 2,
+            daily_pay: 500.0,
+            tiers: tiers
+                .iter()
+                .map(|&(from, percent)| CommissionTier { from, percent })
+                .collect(),
+            without_percent: 1.0,
+            daily_minimum: 0.0,
+            weekend_multiplier: 2.0,
+            holiday_multiplier: 3.0,
+        };
+        for invalid in [
+            rule(worker, &[(1000.0, 20.0), (0.0, 10.0)]),
+            rule(worker, &[(0.0, -10.0)]),
+        ] {
+            let response = handler
+                .process(
+                    Request::Admin(AdminRequest::SetCommissionRule(invalid)),
+                    client(admin_token.clone()),
+                )
+                .await;
+            assert!(matches!(response, Err(ProtocolError::Validation { .. })));
+        }
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetCommissionRule(rule(-1, &[(0.0, 10.0)]))),
+                client(admin_token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::SetCommissionRule(rule(
+                    worker,
+                    &[(0.0, 10.0), (1000.0, 20.0)],
+                ))),
+                client(admin_token.clone()),
+            )
+            .await;
+        match response.unwrap() {
+            ResponseData::CommissionRules(rules) => {
+                assert_eq!(rules.len(), 1);
+                assert_eq!((rules[0].user_id, rules[0].day), (worker, 2));
+                assert_eq!(rules[0].tiers.len(), 2);
+            }
+            _ => panic!("Expected commission rules"),
+        }
+
+        let get_salary = || async {
+            let response = handler
+                .process(
+                    Request::Admin(AdminRequest::GetSalaryCalculation {
+                        year: 2023,
+                        month: 6,
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await;
+            get_salaries(response).remove(&worker).unwrap()
+        };
+        let salary = get_salary().await;
+        let days = salary
+            .commission
+            .iter()
+            .map(|c| (c.day, c.base_pay, c.multiplier, c.amount))
+            .collect::<Vec<_>>();
+        // The default rule on the 1st, then the second tier and 1% of `without_percent`
+        assert_eq!(
+            days,
+            [
+                (1, 1000.0, 1.0, 100.0),
+                (3, 1000.0, 2.0, 500.0),
+                (12, 1500.0, 3.0, 750.0),
+            ]
+        );
+        assert_eq!(salary.base_pay, 3500.0);
+        assert_eq!(salary.total, 4850.0);
+
+        for request in [
+            AdminRequest::DeleteCommissionRule {
+                user_id: worker,
+                year: 2023,
+                month: 6,
+                day: 2,
+            },
+            AdminRequest::DeleteHoliday {
+                year: 2023,
+                month: 6,
+                day: 12,
+            },
+        ] {
+            handler
+                .process(Request::Admin(request.clone()), client(admin_token.clone()))
+                .await
+                .unwrap();
+            let response = handler
+                .process(Request::Admin(request), client(admin_token.clone()))
+                .await;
+            assert!(matches!(response, Err(ProtocolError::NotFound)));
+        }
+        assert_eq!(get_salary().await.total, 3.0 * 1100.0);
+
+        let audit_log = handler
+            .database
+            .get_audit_log(&AuditFilter {
+                user_id: Some(admin),
+                limit: 100,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let kinds = audit_log
+            .iter()
+            .map(|a| a.kind.as_str())
+            .filter(|k| k.contains("Commission") || k.contains("Holiday"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "DeleteHoliday",
+                "DeleteCommissionRule",
+                "SetCommissionRule",
+                "SetHoliday"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payouts() {
+        let handler = setup();
+        add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let token = login(&handler, "admin").await;
+
+        let payout = |amount| Payout {
+            day: 10,
+            user_id: worker,
+            amount,
+        };
+        let get_payouts = || {
+            Request::Admin(AdminRequest::GetPayouts {
+                year: 2023,
+                month: 7,
+            })
+        };
+
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::UpdatePayout {
+                    year: 2023,
+                    month: 7,
+                    payout: payout(100.0),
+                }),
+                client(token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+
+        for request in [
+            AdminRequest::AddPayout {
+                year: 2023,
+                month: 7,
+                payout: payout(100.0),
+            },
+            AdminRequest::UpdatePayout {
+                year: 2023,
+                month: 7,
+                payout: payout(150.0),
+            },
+        ] {
+            handler
+                .process(Request::Admin(request), client(token.clone()))
+                .await
+                .unwrap();
+        }
+        // Adding to the same day again doesn't replace the payout
+        let response = handler
+            .process(
+                Request::Admin(AdminRequest::AddPayout {
+                    year: 2023,
+                    month: 7,
+                    payout: payout(200.0),
+                }),
+                client(token.clone()),
+            )
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Conflict)));
+        match handler.process(get_payouts(), client(token.clone())).await {
+            Ok(ResponseData::Payouts { payouts, .. }) => {
+                assert_eq!(payouts.len(), 1);
+                assert_eq!(payouts[0].amount, 150.0);
+            }
+            _ => panic!("Expected payouts"),
+        }
+
+        let delete = || {
+            Request::Admin(AdminRequest::DeletePayout {
+                year: 2023,
+                month: 7,
+                day: 10,
+                user_id: worker,
+            })
+        };
+        handler
+            .process(delete(), client(token.clone()))
+            .await
+            .unwrap();
+        match handler.process(get_payouts(), client(token.clone())).await {
+            Ok(ResponseData::Payouts { payouts, .. }) => assert!(payouts.is_empty()),
+            _ => panic!("Expected payouts"),
+        }
+        let response = handler.process(delete(), client(token)).await;
+        assert!(matches!(response, Err(ProtocolError::NotFound)));
+    }
+
+    fn get_audit_log(kind: Option<&str>, page: u32, page_size: u32) -> Request {
+        Request::Admin(AdminRequest::GetAuditLog {
+            user_id: None,
+            from: None,
+            to: None,
+            kind: kind.map(|k| k.to_string()),
+            page,
+            page_size,
+        })
+    }
+
+    fn audit_entries(response: Response) -> Vec<AuditEntry> {
+        match response.unwrap() {
+            ResponseData::AuditLog { entries, .. } => entries,
+            _ => panic!("Expected audit log"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let handler = setup();
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let worker = add_user(&handler, "worker", false, 0.0, 0.0).await;
+        let admin_token = login(&handler, "admin").await;
+        let worker_token = login(&handler, "worker").await;
+
+        for with_percent in [100.0, 250.0] {
+            handler
+                .process(
+                    Request::Admin(AdminRequest::SetRevenue {
+                        year: 2023,
+                        month: 5,
+                        revenue: Revenue {
+                            day: 7,
+                            with_percent,
+                            without_percent: 0.0,
+                        },
+                    }),
+                    client(admin_token.clone()),
+                )
+                .await
+                .unwrap();
+        }
+        handler
+            .process(
+                Request::User(UserRequest::SetWorkday {
+                    year: 2023,
+                    month: 5,
+                    day: 7,
+                    is_working: true,
+                }),
+                client(worker_token.clone()),
+            )
+            .await
+            .unwrap();
+
+        let response = handler
+            .process(get_audit_log(None, 0, 10), client(worker_token))
+            .await;
+        assert!(matches!(response, Err(ProtocolError::Forbidden)));
+
+        let response = handler
+            .process(get_audit_log(None, 0, 10), client(admin_token.clone()))
+            .await;
+        let entries = audit_entries(response);
+        let kinds = entries.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, ["SetWorkday", "SetRevenue", "SetRevenue"]);
+        assert_eq!(entries[0].user_id, worker);
+        let before: Value = serde_json::from_str(entries[0].before.as_ref().unwrap()).unwrap();
+        let after: Value = serde_json::from_str(entries[0].after.as_ref().unwrap()).unwrap();
+        assert_eq!(before["is_working"], false);
+        assert_eq!(after["is_working"], true);
+
+        assert_eq!(entries[1].user_id, admin);
+        let before: Value = serde_json::from_str(entries[1].before.as_ref().unwrap()).unwrap();
+        let after: Value = serde_json::from_str(entries[1].after.as_ref().unwrap()).unwrap();
+        assert_eq!(before["revenue"]["with_percent"], 100.0);
+        assert_eq!(after["revenue"]["with_percent"], 250.0);
+        assert!(entries[2].before.is_none());
+
+        let response = handler
+            .process(get_audit_log(Some("SetRevenue"), 1, 1), client(admin_token))
+            .await;
+        let entries = audit_entries(response);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].before.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_users_and_passwords() {
+        let handler = setup();
+        let admin = add_user(&handler, "admin", true, 0.0, 0.0).await;
+        let token = login(&handler, "admin").await;
+
+        let id = match handler
+            .process(
+                Request::Admin(AdminRequest::AddUser(User {
+                    id: 0,
+                    login: "worker".to_string(),
+                    name: "Worker".to_string(),
+                    is_admin: false,
+                    is_worker: true,
+                    pay: 1000.0,
+                    percent: 5.0,
+                })),
+                client(token.clone()),
+            )
+            .await
+        {
+            Ok(ResponseData::SetupCode { id, .. }) => id,
+            _ => panic!("Expected setup code"),
+        };
+        let mut user = match handler
+            .process(
+                Request::Admin(AdminRequest::GetUsers),
+                client(token.clone()),
+            )
+            .await
+        {
+            Ok(ResponseData::Users(users)) => users.into_iter().find(|u| u.id == id).unwrap(),
+            _ => panic!("Expected users"),
+        };
+        user.pay = 1200.0;
+        handler
+            .process(
+                Request::Admin(AdminRequest::UpdateUser(user)),
+                client(token.clone()),
+            )
+            .await
+            .unwrap();
+        handler
+            .process(
+                Request::Admin(AdminRequest::ResetPassword { id }),
+                client(token.clone()),
+            )
+            .await
+            .unwrap();
+        handler
+            .process(
+                Request::User(UserRequest::ChangePassword {
+                    old_password: "password".to_string(),
+                    new_password: "my secret".to_string(),
+                }),
+                client(token.clone()),
+            )
+            .await
+            .unwrap();
+
+        let entries = audit_entries(
+            handler
+                .process(get_audit_log(None, 0, 10), client(token))
+                .await,
+        );
+        let kinds = entries.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            ["ChangePassword", "ResetPassword", "UpdateUser", "AddUser"]
+        );
+        assert!(entries.iter().all(|e| e.user_id == admin));
+        let before: Value = serde_json::from_str(entries[2].before.as_ref().unwrap()).unwrap();
+        let after: Value = serde_json::from_str(entries[2].after.as_ref().unwrap()).unwrap();
+        assert_eq!(before["pay"], 1000.0);
+        assert_eq!(after["pay"], 1200.0);
+        assert!(entries[0].before.is_none() && entries[0].after.is_none());
+        assert!(entries
+            .iter()
+            .all(|e| !e.after.as_deref().unwrap_or("").contains("argon2")));
+    }
 }
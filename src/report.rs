@@ -0,0 +1,214 @@
+use crate::database::{PayoutData, RevenueData};
+use chrono::{Datelike, Months, NaiveDate};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+/// What a worker earned for one day: pay, commission and top-up together
+#[derive(Debug, Clone, PartialEq)]
+pub struct DayEarning {
+    pub user_id: i32,
+    pub day: NaiveDate,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkerTotal {
+    /// Days that earned something, the same days a salary counts
+    pub days_worked: u32,
+    pub earned: Decimal,
+    pub paid: Decimal,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Totals {
+    pub with_percent: Decimal,
+    pub without_percent: Decimal,
+    /// Everyone who worked or was paid, by user id
+    pub workers: BTreeMap<i32, WorkerTotal>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub totals: Totals,
+    /// Every month the report touches by year and month, empty ones included
+    pub months: BTreeMap<(i32, u32), Totals>,
+}
+
+/// Sums revenue, days worked, earnings and payouts from `from` to `to`
+/// inclusive, over the whole period and by month.
+///
+/// Entries outside the period are ignored, so earnings may come from whole
+/// months even when the period starts or ends in the middle of one.
+pub fn summarize(
+    from: NaiveDate,
+    to: NaiveDate,
+    revenue: &[RevenueData],
+    earnings: &[DayEarning],
+    payouts: &[PayoutData],
+) -> Report {
+    let mut months = BTreeMap::new();
+    let mut month = from.with_day(1).unwrap_or(from);
+    while month <= to {
+        months.insert((month.year(), month.month()), Totals::default());
+        month = match month.checked_add_months(Months::new(1)) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    let mut report = Report {
+        totals: Totals::default(),
+        months,
+    };
+    let within = |day: NaiveDate| from <= day && day <= to;
+
+    for r in revenue.iter().filter(|r| within(r.day)) {
+        for totals in report.totals_of(r.day) {
+            totals.with_percent += r.with_percent;
+            totals.without_percent += r.without_percent;
+        }
+    }
+    for e in earnings.iter().filter(|e| within(e.day)) {
+        for totals in report.totals_of(e.day) {
+            let worker = totals.workers.entry(e.user_id).or_default();
+            worker.days_worked += 1;
+            worker.earned += e.amount;
+        }
+    }
+    for p in payouts.iter().filter(|p| within(p.day)) {
+        for totals in report.totals_of(p.day) {
+            totals.workers.entry(p.user_id).or_default().paid += p.amount;
+        }
+    }
+    report
+}
+
+impl Report {
+    /// The period's totals and those of the day's month
+    fn totals_of(&mut self, day: NaiveDate) -> impl Iterator<Item = &mut Totals> {
+        let month = self.months.get_mut(&(day.year(), day.month()));
+        [Some(&mut self.totals), month].into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, month, day).unwrap()
+    }
+
+    fn revenue(day: NaiveDate, with_percent: Decimal) -> RevenueData {
+        RevenueData {
+            day,
+            with_percent,
+            without_percent: dec!(100),
+        }
+    }
+
+    fn earning(day: NaiveDate, user_id: i32, amount: Decimal) -> DayEarning {
+        DayEarning {
+            user_id,
+            day,
+            amount,
+        }
+    }
+
+    fn payout(day: NaiveDate, user_id: i32, amount: Decimal) -> PayoutData {
+        PayoutData {
+            day,
+            user_id,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let revenue = [
+            revenue(date(5, 31), dec!(1000)),
+            revenue(date(6, 15), dec!(1000.10)),
+            revenue(date(7, 1), dec!(500)),
+            revenue(date(8, 20), dec!(300)),
+        ];
+        let earnings = [
+            earning(date(5, 31), 1, dec!(100)),
+            earning(date(6, 15), 1, dec!(150.05)),
+            earning(date(6, 15), 2, dec!(80)),
+            earning(date(7, 1), 1, dec!(120)),
+            earning(date(8, 20), 2, dec!(90)),
+        ];
+        let payouts = [
+            payout(date(6, 30), 1, dec!(200)),
+            payout(date(8, 1), 3, dec!(50)),
+        ];
+
+        let report = summarize(date(6, 1), date(8, 10), &revenue, &earnings, &payouts);
+        assert_eq!(report.totals.with_percent, dec!(1500.10));
+        assert_eq!(report.totals.without_percent, dec!(200));
+        assert_eq!(
+            report.totals.workers,
+            BTreeMap::from([
+                (
+                    1,
+                    WorkerTotal {
+                        days_worked: 2,
+                        earned: dec!(270.05),
+                        paid: dec!(200),
+                    }
+                ),
+                (
+                    2,
+                    WorkerTotal {
+                        days_worked: 1,
+                        earned: dec!(80),
+                        paid: dec!(0),
+                    }
+                ),
+                (
+                    3,
+                    WorkerTotal {
+                        days_worked: 0,
+                        earned: dec!(0),
+                        paid: dec!(50),
+                    }
+                ),
+            ])
+        );
+
+        let months = report.months.keys().copied().collect::<Vec<_>>();
+        assert_eq!(months, [(2023, 6), (2023, 7), (2023, 8)]);
+        let june = &report.months[&(2023, 6)];
+        assert_eq!(june.with_percent, dec!(1000.10));
+        assert_eq!(june.workers[&1].earned, dec!(150.05));
+        assert_eq!(june.workers[&1].paid, dec!(200));
+        let august = &report.months[&(2023, 8)];
+        assert_eq!(august.with_percent, dec!(0));
+        assert_eq!(august.workers.keys().copied().collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn test_months_add_up() {
+        let revenue = (1..=12)
+            .map(|month| revenue(date(month, 28), Decimal::from(month)))
+            .collect::<Vec<_>>();
+        let earnings = (1..=12)
+            .map(|month| earning(date(month, 28), 1, Decimal::new(month as i64, 2)))
+            .collect::<Vec<_>>();
+        let report = summarize(date(1, 1), date(12, 31), &revenue, &earnings, &[]);
+        assert_eq!(report.months.len(), 12);
+        let with_percent = report
+            .months
+            .values()
+            .map(|m| m.with_percent)
+            .sum::<Decimal>();
+        assert_eq!(with_percent, report.totals.with_percent);
+        let earned = report
+            .months
+            .values()
+            .map(|m| m.workers[&1].earned)
+            .sum::<Decimal>();
+        assert_eq!(earned, report.totals.workers[&1].earned);
+        assert_eq!(report.totals.workers[&1].days_worked, 12);
+    }
+}
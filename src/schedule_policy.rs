@@ -0,0 +1,152 @@
+use chrono::{Days, NaiveDate, NaiveDateTime, NaiveTime};
+
+/// What workers may change in their own schedule
+#[derive(Clone)]
+pub struct SchedulePolicy {
+    /// Days after a workday during which it can still be changed, 0 allows today and later
+    pub edit_days_back: u32,
+    /// Time at which today's schedule locks, earlier days follow `edit_days_back`
+    pub cutoff: Option<NaiveTime>,
+    /// Whether users that aren't workers can be scheduled at all
+    pub allow_non_workers: bool,
+    pub max_workers_per_day: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScheduleViolation {
+    DayLocked,
+    NotWorker,
+    DayFull,
+}
+
+impl Default for SchedulePolicy {
+    fn default() -> Self {
+        Self {
+            edit_days_back: 3,
+            cutoff: None,
+            allow_non_workers: false,
+            max_workers_per_day: None,
+        }
+    }
+}
+
+impl ScheduleViolation {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::DayLocked => "Этот день уже нельзя изменить",
+            Self::NotWorker => "Пользователь не работает по графику",
+            Self::DayFull => "На этот день уже записано максимальное число работников",
+        }
+    }
+}
+
+impl SchedulePolicy {
+    /// Only applies to workers editing their own schedule, admins may change any day
+    pub fn check_date(&self, day: NaiveDate, now: NaiveDateTime) -> Result<(), ScheduleViolation> {
+        let today = now.date();
+        // Days too far off to represent the last editable day of are never locked
+        let too_old = day
+            .checked_add_days(Days::new(self.edit_days_back.into()))
+            .is_some_and(|last_day| last_day < today);
+        let past_cutoff = day == today && self.cutoff.is_some_and(|cutoff| now.time() >= cutoff);
+        if too_old || past_cutoff {
+            Err(ScheduleViolation::DayLocked)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks adding a workday for a user, `scheduled` is how many others work that day
+    pub fn check_workday(
+        &self,
+        is_worker: bool,
+        scheduled: usize,
+    ) -> Result<(), ScheduleViolation> {
+        if !is_worker && !self.allow_non_workers {
+            return Err(ScheduleViolation::NotWorker);
+        }
+        match self.max_workers_per_day {
+            Some(max) if scheduled >= max as usize => Err(ScheduleViolation::DayFull),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_date() {
+        let day = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap();
+        let policy = SchedulePolicy {
+            edit_days_back: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.check_date(day, at(1, 0)), Ok(()));
+        assert_eq!(policy.check_date(day, at(10, 23)), Ok(()));
+        assert_eq!(
+            policy.check_date(day, at(11, 0)),
+            Err(ScheduleViolation::DayLocked)
+        );
+
+        let policy = SchedulePolicy {
+            edit_days_back: u32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.check_date(day, NaiveDateTime::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_cutoff() {
+        let policy = SchedulePolicy {
+            edit_days_back: 1,
+            cutoff: NaiveTime::from_hms_opt(10, 0, 0),
+            ..Default::default()
+        };
+        let today = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap();
+        let yesterday = NaiveDate::from_ymd_opt(2023, 6, 9).unwrap();
+        let tomorrow = NaiveDate::from_ymd_opt(2023, 6, 11).unwrap();
+        assert_eq!(policy.check_date(today, at(10, 9)), Ok(()));
+        assert_eq!(
+            policy.check_date(today, at(10, 10)),
+            Err(ScheduleViolation::DayLocked)
+        );
+        // The cutoff is only for today, the last day back stays editable all day
+        assert_eq!(policy.check_date(yesterday, at(10, 10)), Ok(()));
+        assert_eq!(policy.check_date(yesterday, at(10, 23)), Ok(()));
+        assert_eq!(
+            policy.check_date(yesterday, at(11, 0)),
+            Err(ScheduleViolation::DayLocked)
+        );
+        assert_eq!(policy.check_date(tomorrow, at(10, 10)), Ok(()));
+    }
+
+    #[test]
+    fn test_check_workday() {
+        let policy = SchedulePolicy::default();
+        assert_eq!(policy.check_workday(true, 100), Ok(()));
+        assert_eq!(
+            policy.check_workday(false, 0),
+            Err(ScheduleViolation::NotWorker)
+        );
+
+        let policy = SchedulePolicy {
+            allow_non_workers: true,
+            max_workers_per_day: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(policy.check_workday(false, 1), Ok(()));
+        assert_eq!(
+            policy.check_workday(true, 2),
+            Err(ScheduleViolation::DayFull)
+        );
+    }
+}
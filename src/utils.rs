@@ -1,16 +1,19 @@
-pub fn get_days_in_month(year: u16, month: u8) -> u32 {
-    use chrono::{Datelike, NaiveDate};
-    // Create a NaiveDate representing the first day of the next month
-    let next_month = NaiveDate::from_ymd_opt(year as i32, (month as u32) + 1, 1);
-
-    // If the next month exists, subtract 1 day to get the last day of the current month
-    if let Some(next_month) = next_month {
-        next_month.pred_opt().unwrap().day()
-    } else {
-        // The next month doesn't exist, so it means we are in December.
-        // Return 31 for December.
-        31
-    }
+/// `None` for months that don't exist
+pub fn get_days_in_month(year: u16, month: u8) -> Option<u32> {
+    use chrono::{Datelike, Months, NaiveDate};
+    let first = NaiveDate::from_ymd_opt(year as i32, month as u32, 1)?;
+    let last = first.checked_add_months(Months::new(1))?.pred_opt()?;
+    Some(last.day())
+}
+
+/// The date at the shop, workdays and new pay rates go by it rather than by UTC
+pub fn today() -> chrono::NaiveDate {
+    local_now().date()
+}
+
+/// The time at the shop, schedule deadlines are set in it
+pub fn local_now() -> chrono::NaiveDateTime {
+    chrono::Local::now().naive_local()
 }
 
 pub fn make_uuid() -> String {
@@ -18,6 +21,16 @@ pub fn make_uuid() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// Random code that is easy to read out and type, for one-time password setup
+pub fn make_setup_code() -> String {
+    use rand::seq::SliceRandom;
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut rng = rand::thread_rng();
+    (0..12)
+        .map(|_| *ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
 pub fn sha3(text: impl AsRef<str>) -> String {
     use sha3::{Digest, Sha3_256};
     let mut hasher = Sha3_256::new();
@@ -25,17 +38,41 @@ pub fn sha3(text: impl AsRef<str>) -> String {
     format!("{:x?}", hasher.finalize())
 }
 
+pub fn argon2_hash(text: impl AsRef<str>) -> anyhow::Result<String> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+    use argon2::Argon2;
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(text.as_ref().as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+pub fn argon2_verify(text: impl AsRef<str>, hash: impl AsRef<str>) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+    use argon2::Argon2;
+    match PasswordHash::new(hash.as_ref()) {
+        Ok(hash) => Argon2::default()
+            .verify_password(text.as_ref().as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn test_get_days_in_month() {
-        assert_eq!(get_days_in_month(2023, 1), 31);
-        assert_eq!(get_days_in_month(2024, 2), 29);
-        assert_eq!(get_days_in_month(2023, 2), 28);
-        assert_eq!(get_days_in_month(2023, 3), 31);
-        assert_eq!(get_days_in_month(2023, 4), 30);
-        assert_eq!(get_days_in_month(2023, 12), 31);
+        assert_eq!(get_days_in_month(2023, 1), Some(31));
+        assert_eq!(get_days_in_month(2024, 2), Some(29));
+        assert_eq!(get_days_in_month(2023, 2), Some(28));
+        assert_eq!(get_days_in_month(2023, 3), Some(31));
+        assert_eq!(get_days_in_month(2023, 4), Some(30));
+        assert_eq!(get_days_in_month(2023, 11), Some(30));
+        assert_eq!(get_days_in_month(2023, 12), Some(31));
+        assert_eq!(get_days_in_month(2023, 0), None);
+        assert_eq!(get_days_in_month(2023, 13), None);
     }
 
     #[test]
@@ -43,9 +80,29 @@ mod tests {
         assert_ne!(make_uuid(), make_uuid());
     }
 
+    #[test]
+    fn test_make_setup_code() {
+        let code = make_setup_code();
+        assert_eq!(code.len(), 12);
+        assert!(code
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()));
+        assert_ne!(code, make_setup_code());
+    }
+
     #[test]
     fn test_sha3() {
         assert_ne!(sha3("Qwer4321"), sha3("qwer4321"));
         assert_eq!(sha3("Qwer4321"), sha3("Qwer4321"));
     }
+
+    #[test]
+    fn test_argon2() {
+        let hash = argon2_hash("Qwer4321").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, argon2_hash("Qwer4321").unwrap());
+        assert!(argon2_verify("Qwer4321", &hash));
+        assert!(!argon2_verify("qwer4321", &hash));
+        assert!(!argon2_verify("Qwer4321", sha3("Qwer4321")));
+    }
 }
@@ -0,0 +1,429 @@
+use chrono::{DateTime, Months, NaiveDate, TimeZone, Utc};
+use pravda_protocol::*;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+/// Amounts, multipliers and thresholds above this are surely typos, and keeping
+/// them out leaves salary arithmetic far from what a decimal can hold
+const MAX_VALUE: f64 = 1e12;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Checks the dates and amounts a request carries before the handler acts on it
+pub fn validate(request: &Request) -> Result<(), ProtocolError> {
+    match request {
+        Request::User(request) => match request {
+            UserRequest::GetSchedule { year, month } => check_month(*year, *month),
+            UserRequest::SetWorkday {
+                year, month, day, ..
+            } => date(*year, *month, *day).map(drop),
+            UserRequest::ChangePassword { new_password, .. } => {
+                password("new_password", new_password)
+            }
+            _ => Ok(()),
+        },
+        Request::Admin(request) => match request {
+            AdminRequest::AddUser(user) | AdminRequest::UpdateUser(user) => {
+                money("pay", user.pay)?;
+                percent("percent", user.percent)
+            }
+            AdminRequest::GetRevenue { year, month }
+            | AdminRequest::GetSalaryCalculation { year, month }
+            | AdminRequest::GetPayouts { year, month }
+            | AdminRequest::CloseMonth { year, month }
+            | AdminRequest::ReopenMonth { year, month } => check_month(*year, *month),
+            AdminRequest::SetRevenue {
+                year,
+                month,
+                revenue,
+            } => {
+                date(*year, *month, revenue.day)?;
+                money("revenue.with_percent", revenue.with_percent)?;
+                money("revenue.without_percent", revenue.without_percent)
+            }
+            AdminRequest::AddPayout {
+                year,
+                month,
+                payout,
+            }
+            | AdminRequest::UpdatePayout {
+                year,
+                month,
+                payout,
+            } => {
+                date(*year, *month, payout.day)?;
+                money("payout.amount", payout.amount)
+            }
+            AdminRequest::GetAuditLog { from, to, .. } => {
+                if let Some(from) = from {
+                    timestamp("from", *from)?;
+                }
+                if let Some(to) = to {
+                    timestamp("to", *to)?;
+                }
+                Ok(())
+            }
+            AdminRequest::SetPayRate(rate) => {
+                date(rate.year, rate.month, rate.day)?;
+                money("pay", rate.pay)?;
+                percent("percent", rate.percent)
+            }
+            AdminRequest::SetCommissionRule(rule) => {
+                date(rule.year, rule.month, rule.day)?;
+                money("daily_pay", rule.daily_pay)?;
+                percent("without_percent", rule.without_percent)?;
+                money("daily_minimum", rule.daily_minimum)?;
+                non_negative("weekend_multiplier", rule.weekend_multiplier)?;
+                non_negative("holiday_multiplier", rule.holiday_multiplier)?;
+                for (i, tier) in rule.tiers.iter().enumerate() {
+                    money(&format!("tiers[{}].from", i), tier.from)?;
+                    percent(&format!("tiers[{}].percent", i), tier.percent)?;
+                }
+                if !rule.tiers.windows(2).all(|t| t[0].from < t[1].from) {
+                    return Err(invalid("tiers", "Ступени должны идти по возрастанию"));
+                }
+                Ok(())
+            }
+            AdminRequest::SetHoliday(holiday) => {
+                date(holiday.year, holiday.month, holiday.day)?;
+                if holiday.name.trim().is_empty() {
+                    return Err(invalid("name", "Название не может быть пустым"));
+                }
+                Ok(())
+            }
+            AdminRequest::GetReport { from, to } => period(from, to).map(drop),
+            AdminRequest::GetYearToDateReport { year } => check_month(*year, 1),
+            AdminRequest::SetWorkday {
+                year, month, day, ..
+            }
+            | AdminRequest::DeletePayout {
+                year, month, day, ..
+            }
+            | AdminRequest::DeletePayRate {
+                year, month, day, ..
+            }
+            | AdminRequest::DeleteCommissionRule {
+                year, month, day, ..
+            }
+            | AdminRequest::DeleteHoliday { year, month, day } => {
+                date(*year, *month, *day).map(drop)
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+pub fn invalid(field: &str, message: &str) -> ProtocolError {
+    ProtocolError::Validation {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+pub fn check_month(year: u16, month: u8) -> Result<(), ProtocolError> {
+    if year == 0 {
+        return Err(invalid("year", "Неверный год"));
+    }
+    if !(1..=12).contains(&month) {
+        return Err(invalid("month", "Месяц должен быть от 1 до 12"));
+    }
+    Ok(())
+}
+
+pub fn date(year: u16, month: u8, day: u8) -> Result<NaiveDate, ProtocolError> {
+    check_month(year, month)?;
+    NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+        .ok_or_else(|| invalid("day", "В этом месяце нет такого дня"))
+}
+
+/// The first and the last day of a report, both included
+pub fn period(from: &Date, to: &Date) -> Result<(NaiveDate, NaiveDate), ProtocolError> {
+    ordered(field_date("from", from)?, field_date("to", to)?)
+}
+
+/// Like `period`, for dates written as `YYYY-MM-DD`
+pub fn iso_period(from: &str, to: &str) -> Result<(NaiveDate, NaiveDate), ProtocolError> {
+    ordered(iso_date("from", from)?, iso_date("to", to)?)
+}
+
+/// A date written as `YYYY-MM-DD`
+pub fn iso_date(field: &str, value: &str) -> Result<NaiveDate, ProtocolError> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").map_err(|_| invalid(field, "Неверная дата"))
+}
+
+/// An amount written as text, like `1234.50`
+pub fn amount(field: &str, value: &str) -> Result<Decimal, ProtocolError> {
+    let amount = value
+        .trim()
+        .parse::<Decimal>()
+        .map_err(|_| invalid(field, "Значение должно быть неотрицательным числом"))?;
+    non_negative(field, amount.to_f64().unwrap_or(f64::NAN))?;
+    kopecks(field, amount)?;
+    Ok(amount)
+}
+
+fn ordered(from: NaiveDate, to: NaiveDate) -> Result<(NaiveDate, NaiveDate), ProtocolError> {
+    if to < from {
+        return Err(invalid("to", "Конец периода раньше начала"));
+    }
+    Ok((from, to))
+}
+
+fn field_date(field: &str, value: &Date) -> Result<NaiveDate, ProtocolError> {
+    date(value.year, value.month, value.day).map_err(|e| match e {
+        ProtocolError::Validation {
+            field: part,
+            message,
+        } => invalid(&format!("{}.{}", field, part), &message),
+        e => e,
+    })
+}
+
+/// The first day of the month and the first day of the next one
+pub fn month_range(year: u16, month: u8) -> Result<(NaiveDate, NaiveDate), ProtocolError> {
+    let first = date(year, month, 1)?;
+    let next = first
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| invalid("year", "Неверный год"))?;
+    Ok((first, next))
+}
+
+pub fn timestamp(field: &str, timestamp: i64) -> Result<DateTime<Utc>, ProtocolError> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .ok_or_else(|| invalid(field, "Неверная дата"))
+}
+
+/// Passwords set by changing one or redeeming a setup code
+pub fn password(field: &str, value: &str) -> Result<(), ProtocolError> {
+    if value.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(invalid(field, "Пароль должен быть не короче 8 символов"));
+    }
+    if value.trim().is_empty() {
+        return Err(invalid(field, "Пароль не может состоять из пробелов"));
+    }
+    Ok(())
+}
+
+/// The decimal a client meant by a float, 0.1 is taken as exactly 0.1
+pub fn decimal(field: &str, value: f64) -> Result<Decimal, ProtocolError> {
+    Decimal::try_from(value).map_err(|_| invalid(field, "Неверное число"))
+}
+
+/// Amounts, multipliers and thresholds are never negative
+fn non_negative(field: &str, value: f64) -> Result<(), ProtocolError> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(invalid(
+            field,
+            "Значение должно быть неотрицательным числом",
+        ));
+    }
+    if value > MAX_VALUE {
+        return Err(invalid(field, "Слишком большое значение"));
+    }
+    Ok(())
+}
+
+/// Money is never negative and is counted in whole kopecks
+fn money(field: &str, value: f64) -> Result<(), ProtocolError> {
+    non_negative(field, value)?;
+    kopecks(field, decimal(field, value)?)
+}
+
+fn kopecks(field: &str, value: Decimal) -> Result<(), ProtocolError> {
+    if value.normalize().scale() > 2 {
+        return Err(invalid(field, "Не больше двух знаков после запятой"));
+    }
+    Ok(())
+}
+
+fn percent(field: &str, value: f64) -> Result<(), ProtocolError> {
+    if !(0.0..=100.0).contains(&value) {
+        return Err(invalid(field, "Процент должен быть от 0 до 100"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(result: Result<(), ProtocolError>) -> String {
+        match result {
+            Err(ProtocolError::Validation { field, .. }) => field,
+            _ => panic!("Expected a validation error"),
+        }
+    }
+
+    fn set_revenue(month: u8, day: u8, with_percent: f64) -> Request {
+        Request::Admin(AdminRequest::SetRevenue {
+            year: 2023,
+            month,
+            revenue: Revenue {
+                day,
+                with_percent,
+                without_percent: 0.0,
+            },
+        })
+    }
+
+    #[test]
+    fn test_dates() {
+        assert!(validate(&set_revenue(2, 28, 0.0)).is_ok());
+        assert_eq!(field(validate(&set_revenue(13, 1, 0.0))), "month");
+        assert_eq!(field(validate(&set_revenue(0, 1, 0.0))), "month");
+        assert_eq!(field(validate(&set_revenue(2, 29, 0.0))), "day");
+        assert_eq!(field(validate(&set_revenue(1, 0, 0.0))), "day");
+        let schedule = |year, month| Request::User(UserRequest::GetSchedule { year, month });
+        assert!(validate(&schedule(2024, 12)).is_ok());
+        assert_eq!(field(validate(&schedule(0, 1))), "year");
+        assert_eq!(field(validate(&schedule(2024, 40))), "month");
+        let audit_log = Request::Admin(AdminRequest::GetAuditLog {
+            user_id: None,
+            from: Some(0),
+            to: Some(i64::MAX),
+            kind: None,
+            page: 0,
+            page_size: 10,
+        });
+        assert_eq!(field(validate(&audit_log)), "to");
+    }
+
+    #[test]
+    fn test_report_period() {
+        let report = |from: (u16, u8, u8), to: (u16, u8, u8)| {
+            let date = |(year, month, day)| Date { year, month, day };
+            Request::Admin(AdminRequest::GetReport {
+                from: date(from),
+                to: date(to),
+            })
+        };
+        assert!(validate(&report((2023, 1, 1), (2023, 12, 31))).is_ok());
+        assert!(validate(&report((2023, 6, 15), (2023, 6, 15))).is_ok());
+        assert_eq!(
+            field(validate(&report((2023, 2, 30), (2023, 3, 1)))),
+            "from.day"
+        );
+        assert_eq!(
+            field(validate(&report((2023, 1, 1), (2023, 13, 1)))),
+            "to.month"
+        );
+        assert_eq!(field(validate(&report((2023, 6, 2), (2023, 6, 1)))), "to");
+        let year_to_date = Request::Admin(AdminRequest::GetYearToDateReport { year: 0 });
+        assert_eq!(field(validate(&year_to_date)), "year");
+    }
+
+    #[test]
+    fn test_iso_period() {
+        let (from, to) = iso_period("2023-06-15", "2023-07-31").unwrap();
+        assert_eq!(from, NaiveDate::from_ymd_opt(2023, 6, 15).unwrap());
+        assert_eq!(to, NaiveDate::from_ymd_opt(2023, 7, 31).unwrap());
+        assert_eq!(
+            field(iso_period("2023-02-30", "2023-03-01").map(drop)),
+            "from"
+        );
+        assert_eq!(field(iso_period("2023-06-01", "июль").map(drop)), "to");
+        assert_eq!(
+            field(iso_period("2023-06-02", "2023-06-01").map(drop)),
+            "to"
+        );
+    }
+
+    #[test]
+    fn test_amount() {
+        assert_eq!(
+            amount("amount", " 1234.50 ").unwrap(),
+            Decimal::new(123450, 2)
+        );
+        assert_eq!(amount("amount", "0").unwrap(), Decimal::ZERO);
+        assert_eq!(field(amount("amount", "-1").map(drop)), "amount");
+        assert_eq!(field(amount("amount", "1,5").map(drop)), "amount");
+        assert_eq!(field(amount("amount", "100.005").map(drop)), "amount");
+        assert!(amount("amount", "100.500").is_ok());
+        assert_eq!(field(amount("amount", "1e13").map(drop)), "amount");
+        assert_eq!(
+            field(amount("amount", "10000000000000").map(drop)),
+            "amount"
+        );
+    }
+
+    #[test]
+    fn test_month_range() {
+        let (first, next) = month_range(2023, 12).unwrap();
+        assert_eq!(first, NaiveDate::from_ymd_opt(2023, 12, 1).unwrap());
+        assert_eq!(next, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        assert!(month_range(2023, 13).is_err());
+    }
+
+    #[test]
+    fn test_amounts() {
+        assert_eq!(
+            field(validate(&set_revenue(1, 1, -1.0))),
+            "revenue.with_percent"
+        );
+        assert_eq!(
+            field(validate(&set_revenue(1, 1, f64::NAN))),
+            "revenue.with_percent"
+        );
+        assert_eq!(
+            field(validate(&set_revenue(1, 1, 1e20))),
+            "revenue.with_percent"
+        );
+        assert_eq!(
+            field(validate(&set_revenue(1, 1, 100.005))),
+            "revenue.with_percent"
+        );
+        assert!(validate(&set_revenue(1, 1, 12345.67)).is_ok());
+        assert_eq!(decimal("pay", 0.1).unwrap(), Decimal::new(1, 1));
+        assert_eq!(decimal("pay", 12345.67).unwrap().to_string(), "12345.67");
+        let user = |pay, percent| User {
+            id: 1,
+            login: "worker".to_string(),
+            name: "Worker".to_string(),
+            is_admin: false,
+            is_worker: true,
+            pay,
+            percent,
+        };
+        let add_user = |user| Request::Admin(AdminRequest::AddUser(user));
+        assert!(validate(&add_user(user(1000.0, 100.0))).is_ok());
+        assert_eq!(field(validate(&add_user(user(-1.0, 10.0)))), "pay");
+        assert_eq!(field(validate(&add_user(user(0.0, 101.0)))), "percent");
+
+        let rule = |tiers: &[(f64, f64)]| {
+            Request::Admin(AdminRequest::SetCommissionRule(CommissionRule {
+                user_id: 1,
+                year: 2023,
+                month: 6,
+                day: 1,
+                daily_pay: 0.0,
+                tiers: tiers
+                    .iter()
+                    .map(|&(from, percent)| CommissionTier { from, percent })
+                    .collect(),
+                without_percent: 0.0,
+                daily_minimum: 0.0,
+                weekend_multiplier: 1.0,
+                holiday_multiplier: 1.0,
+            }))
+        };
+        assert!(validate(&rule(&[(0.0, 10.0), (1000.0, 20.0)])).is_ok());
+        assert_eq!(field(validate(&rule(&[(0.0, 10.0), (0.0, 20.0)]))), "tiers");
+        assert_eq!(
+            field(validate(&rule(&[(0.0, 10.0), (10.0, -20.0)]))),
+            "tiers[1].percent"
+        );
+    }
+
+    #[test]
+    fn test_password() {
+        assert!(password("password", "correct horse").is_ok());
+        assert_eq!(field(password("password", "secret")), "password");
+        assert_eq!(field(password("password", "        ")), "password");
+        let change = Request::User(UserRequest::ChangePassword {
+            old_password: "password".to_string(),
+            new_password: "short".to_string(),
+        });
+        assert_eq!(field(validate(&change)), "new_password");
+    }
+}